@@ -0,0 +1,55 @@
+/*
+ * C interface to tsvfirst's incremental deduplicator.
+ *
+ * Build the shared library with:
+ *
+ *     cargo rustc --lib --release --features ffi --crate-type cdylib
+ *
+ * Memory ownership:
+ *   - A handle from tsvfirst_new() belongs to the caller and must be released
+ *     with tsvfirst_free().
+ *   - Output buffers from tsvfirst_feed()/tsvfirst_finish() belong to the
+ *     caller and must be released with tsvfirst_buffer_free(), passing the
+ *     returned length. A call with no kept output returns NULL and length 0.
+ *   - Input passed to tsvfirst_feed() is only read during the call.
+ *
+ * A handle is not thread-safe; use one per thread.
+ */
+
+#ifndef TSVFIRST_H
+#define TSVFIRST_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Flags for tsvfirst_new() */
+#define TSVFIRST_SORTED     1  /* input is sorted by key; use constant memory */
+#define TSVFIRST_WHITESPACE 2  /* split fields on whitespace rather than tabs */
+
+typedef struct TsvfirstDedup TsvfirstDedup;
+
+/* Creates a deduplicator keyed on `fields`, a -f style spec such as "1,3i"
+ * (NULL means "1"). Returns NULL if the spec is invalid. */
+TsvfirstDedup *tsvfirst_new(const char *fields, int flags);
+
+/* Feeds `len` bytes of input; records may span calls. Kept records completed
+ * by this chunk are returned in *out. Returns 0 on success, -1 on error. */
+int tsvfirst_feed(TsvfirstDedup *dedup, const uint8_t *data, size_t len,
+                  uint8_t **out, size_t *out_len);
+
+/* Flushes a final record lacking a trailing newline. Returns 0 or -1. */
+int tsvfirst_finish(TsvfirstDedup *dedup, uint8_t **out, size_t *out_len);
+
+void tsvfirst_buffer_free(uint8_t *buf, size_t len);
+
+void tsvfirst_free(TsvfirstDedup *dedup);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* TSVFIRST_H */
@@ -0,0 +1,176 @@
+use std::collections::{BTreeMap, HashSet};
+use std::error;
+use std::io;
+use std::io::Write;
+
+use config::{BadInput, Config};
+use key::KeyBuilder;
+use output::chomp;
+
+/// Number of rows read from the start of the input
+const SAMPLE_ROWS: usize = 10000;
+
+/// Rows longer than this suggest --max-row-buffer
+const LONG_ROW_BYTES: usize = 16 << 20;
+
+/// Samples the start of the input and writes a report of its shape along with
+/// suggested flags, without deduplicating anything
+pub fn advise(config: &Config, out: &mut dyn Write) -> Result<(), Box<dyn error::Error>> {
+    let keys = config.key_builder()?;
+    let splitter = config.splitter();
+
+    let mut rows = 0;
+    let mut with_tabs = 0;
+    let mut with_spaces = 0;
+    let mut longest = 0;
+    let mut short_rows = 0;
+    let mut columns : BTreeMap<usize, usize> = BTreeMap::new();
+
+    // Keys (and first key fields) seen, and whether any recurred after a gap
+    let mut grouping = Grouping::new();
+    let mut first_field_grouping = Grouping::new();
+    let first_field = if config.line_regex.is_none() && config.fields.len() > 1 {
+        Some(KeyBuilder::new(&config.fields[..1], config.splitter()))
+    }
+    else {
+        None
+    };
+
+    let mut line = vec![];
+    'inputs: for input in config.get_inputs()? {
+        let mut input = match input {
+            Ok(input) => input,
+            Err(e) => match BadInput::skipped(config, &e) {
+                Some(bad) => {
+                    writeln!(io::stderr(), "tsvfirst: skipping {}", bad)?;
+                    continue;
+                }
+                None => return Err(e.into()),
+            },
+        };
+        loop {
+            if rows == SAMPLE_ROWS {
+                break 'inputs;
+            }
+            line.clear();
+            if input.reader.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+            rows += 1;
+            longest = longest.max(line.len());
+            if line.contains(&b'\t') {
+                with_tabs += 1;
+            }
+            else if line.contains(&b' ') {
+                with_spaces += 1;
+            }
+            *columns.entry(splitter.split(chomp(&line)).count()).or_insert(0) += 1;
+
+            let key = keys.build(&line);
+            if key.missing_field.is_some() || key.unmatched {
+                short_rows += 1;
+            }
+            grouping.observe(key.bytes);
+            if let Some(ref first_field) = first_field {
+                first_field_grouping.observe(first_field.build(&line).bytes);
+            }
+        }
+    }
+
+    writeln!(out, "sampled rows: {}{}", rows, if rows == SAMPLE_ROWS { " (limit reached)" } else { "" })?;
+    if rows == 0 {
+        return Ok(());
+    }
+
+    let mut advice = vec![];
+    let delimiter = if with_tabs * 2 >= rows {
+        "tab"
+    }
+    else if with_spaces * 2 >= rows {
+        "whitespace"
+    }
+    else {
+        "none found (rows have a single field)"
+    };
+    writeln!(out, "detected delimiter: {}", delimiter)?;
+    if delimiter == "whitespace" && !config.whitespace {
+        advice.push("use -w: rows appear to be whitespace- rather than tab-separated".to_owned());
+    }
+    else if delimiter == "tab" && config.whitespace {
+        advice.push("drop -w: rows appear to be tab-separated".to_owned());
+    }
+
+    writeln!(out, "columns per row:")?;
+    let mut by_frequency : Vec<_> = columns.iter().collect();
+    by_frequency.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+    for (count, n) in by_frequency.iter().take(5) {
+        writeln!(out, "  {:>6} columns: {} rows ({:.1}%)", count, n, percent(**n, rows))?;
+    }
+    if by_frequency.len() > 5 {
+        writeln!(out, "  ... and {} other column counts", by_frequency.len() - 5)?;
+    }
+    if short_rows > 0 {
+        writeln!(out, "rows without a complete key: {} ({:.1}%)", short_rows, percent(short_rows, rows))?;
+        advice.push("check -f: some rows lack the key fields (see --errors text)".to_owned());
+    }
+
+    let distinct = grouping.seen.len();
+    writeln!(out, "distinct keys: {} ({:.1}% of rows)", distinct, percent(distinct, rows))?;
+    writeln!(out, "grouped by key: {}", if grouping.grouped { "yes" } else { "no" })?;
+    if grouping.grouped && distinct < rows {
+        if !config.sorted {
+            advice.push("use -s: rows with the same key appear consecutively".to_owned());
+        }
+    }
+    else if !grouping.grouped && config.sorted {
+        advice.push("drop -s: rows with the same key are not consecutive, so duplicates will be missed".to_owned());
+    }
+    else if first_field.is_some() && first_field_grouping.grouped && config.sorted_by.is_empty() {
+        advice.push(format!("use --sorted-by {}: rows appear grouped by the first key field", config.fields[0].index + 1));
+    }
+
+    if longest > LONG_ROW_BYTES {
+        writeln!(out, "longest row: {} bytes", longest)?;
+        if config.max_row_buffer.is_none() {
+            advice.push("use --max-row-buffer 16M: some rows are very long".to_owned());
+        }
+    }
+
+    if advice.is_empty() {
+        writeln!(out, "advice: current flags look appropriate")?;
+    }
+    else {
+        writeln!(out, "advice:")?;
+        for line in advice {
+            writeln!(out, "  - {}", line)?;
+        }
+    }
+    Ok(())
+}
+
+/// Tracks whether equal values only ever appear consecutively
+struct Grouping {
+    seen: HashSet<Vec<u8>>,
+    last: Option<Vec<u8>>,
+    grouped: bool,
+}
+
+impl Grouping {
+    fn new() -> Grouping {
+        Grouping { seen: HashSet::new(), last: None, grouped: true }
+    }
+
+    fn observe(&mut self, value: Vec<u8>) {
+        if self.last.as_ref() == Some(&value) {
+            return;
+        }
+        if !self.seen.insert(value.clone()) {
+            self.grouped = false;
+        }
+        self.last = Some(value);
+    }
+}
+
+fn percent(n: usize, of: usize) -> f64 {
+    100.0 * n as f64 / of as f64
+}
@@ -0,0 +1,258 @@
+use std::str;
+use std::str::FromStr;
+
+/// Combines the values of one field over all of a key's rows
+pub trait Aggregate {
+    /// Adds the field's value from a row
+    fn add(&mut self, value: &[u8]);
+
+    /// The combined value, written as an extra column
+    fn result(&self) -> Vec<u8>;
+}
+
+/// Creates an aggregate for a new key
+type Start = fn() -> Box<dyn Aggregate>;
+
+/// Aggregate functions by name, in the order listed in errors
+const FUNCTIONS: &[(&str, Start)] = &[
+    ("sum", Sum::boxed),
+    ("mean", Mean::boxed),
+    ("min", Min::boxed),
+    ("max", Max::boxed),
+    ("first", First::boxed),
+    ("last", Last::boxed),
+];
+
+/// An aggregate function applied to a field, e.g. `sum(3)`
+#[derive(Debug, Clone)]
+pub struct Aggregation {
+    pub function: String,
+    /// 0-indexed
+    pub field: usize,
+    start: Start,
+}
+
+impl FromStr for Aggregation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Aggregation, String> {
+        let s = s.trim();
+        let (function, field) = match s.find('(') {
+            Some(open) if s.ends_with(')') => (&s[..open], &s[open + 1..s.len() - 1]),
+            _ => return Err(format!("expected FUNCTION(FIELD), got '{}'", s)),
+        };
+        let field = match field.trim().parse::<usize>() {
+            Ok(n) if n > 0 => n - 1,
+            _ => return Err(format!("invalid field number '{}' in '{}'", field, s)),
+        };
+        let function = function.trim();
+        match FUNCTIONS.iter().find(|&&(name, _)| name == function) {
+            Some(&(_, start)) => Ok(Aggregation { function: function.into(), field, start }),
+            None => {
+                let names : Vec<&str> = FUNCTIONS.iter().map(|&(name, _)| name).collect();
+                Err(format!("unknown aggregate function '{}' (expected one of {})", function, names.join(", ")))
+            }
+        }
+    }
+}
+
+impl Aggregation {
+    /// Starts aggregating the field for a new key
+    pub fn start(&self) -> Box<dyn Aggregate> {
+        (self.start)()
+    }
+}
+
+fn number(value: &[u8]) -> Option<f64> {
+    str::from_utf8(value).ok()
+        .and_then(|value| value.trim().parse::<f64>().ok())
+        .filter(|value| !value.is_nan())
+}
+
+fn format_number(value: Option<f64>) -> Vec<u8> {
+    value.map_or(vec![], |value| value.to_string().into_bytes())
+}
+
+/// Total of the values that are numbers
+#[derive(Default)]
+struct Sum {
+    total: f64,
+}
+
+impl Sum {
+    fn boxed() -> Box<dyn Aggregate> {
+        Box::new(Sum::default())
+    }
+}
+
+impl Aggregate for Sum {
+    fn add(&mut self, value: &[u8]) {
+        self.total += number(value).unwrap_or(0.0);
+    }
+
+    fn result(&self) -> Vec<u8> {
+        format_number(Some(self.total))
+    }
+}
+
+/// Average of the values that are numbers; empty if there are none
+#[derive(Default)]
+struct Mean {
+    total: f64,
+    values: u64,
+}
+
+impl Mean {
+    fn boxed() -> Box<dyn Aggregate> {
+        Box::new(Mean::default())
+    }
+}
+
+impl Aggregate for Mean {
+    fn add(&mut self, value: &[u8]) {
+        if let Some(value) = number(value) {
+            self.total += value;
+            self.values += 1;
+        }
+    }
+
+    fn result(&self) -> Vec<u8> {
+        format_number(Some(self.total / self.values as f64).filter(|_| self.values > 0))
+    }
+}
+
+/// Smallest of the values that are numbers; empty if there are none
+#[derive(Default)]
+struct Min {
+    min: Option<f64>,
+}
+
+impl Min {
+    fn boxed() -> Box<dyn Aggregate> {
+        Box::new(Min::default())
+    }
+}
+
+impl Aggregate for Min {
+    fn add(&mut self, value: &[u8]) {
+        if let Some(value) = number(value) {
+            self.min = Some(self.min.map_or(value, |min| min.min(value)));
+        }
+    }
+
+    fn result(&self) -> Vec<u8> {
+        format_number(self.min)
+    }
+}
+
+/// Largest of the values that are numbers; empty if there are none
+#[derive(Default)]
+struct Max {
+    max: Option<f64>,
+}
+
+impl Max {
+    fn boxed() -> Box<dyn Aggregate> {
+        Box::new(Max::default())
+    }
+}
+
+impl Aggregate for Max {
+    fn add(&mut self, value: &[u8]) {
+        if let Some(value) = number(value) {
+            self.max = Some(self.max.map_or(value, |max| max.max(value)));
+        }
+    }
+
+    fn result(&self) -> Vec<u8> {
+        format_number(self.max)
+    }
+}
+
+/// The first row's value, as is
+#[derive(Default)]
+struct First {
+    value: Option<Vec<u8>>,
+}
+
+impl First {
+    fn boxed() -> Box<dyn Aggregate> {
+        Box::new(First::default())
+    }
+}
+
+impl Aggregate for First {
+    fn add(&mut self, value: &[u8]) {
+        if self.value.is_none() {
+            self.value = Some(value.to_owned());
+        }
+    }
+
+    fn result(&self) -> Vec<u8> {
+        self.value.clone().unwrap_or_default()
+    }
+}
+
+/// The last row's value, as is
+#[derive(Default)]
+struct Last {
+    value: Vec<u8>,
+}
+
+impl Last {
+    fn boxed() -> Box<dyn Aggregate> {
+        Box::new(Last::default())
+    }
+}
+
+impl Aggregate for Last {
+    fn add(&mut self, value: &[u8]) {
+        self.value = value.to_owned();
+    }
+
+    fn result(&self) -> Vec<u8> {
+        self.value.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aggregate(spec: &str, values: &[&[u8]]) -> Vec<u8> {
+        let mut aggregate = spec.parse::<Aggregation>().unwrap().start();
+        for value in values {
+            aggregate.add(value);
+        }
+        aggregate.result()
+    }
+
+    #[test]
+    fn sum_skips_non_numbers() {
+        assert_eq!(aggregate("sum(1)", &[b"1.5", b"abc", b" 2 ", b"", b"NaN", b"-0.5"]), b"3");
+        assert_eq!(aggregate("sum(1)", &[b"x"]), b"0");
+    }
+
+    #[test]
+    fn mean_counts_only_numbers() {
+        assert_eq!(aggregate("mean(1)", &[b"1", b"abc", b"4", b""]), b"2.5");
+        assert_eq!(aggregate("mean(1)", &[b"abc", b""]), b"");
+    }
+
+    #[test]
+    fn min_max_first_last() {
+        let values : &[&[u8]] = &[b"x", b"3", b"-2", b"10", b"y"];
+        assert_eq!(aggregate("min(1)", values), b"-2");
+        assert_eq!(aggregate("max(1)", values), b"10");
+        assert_eq!(aggregate("first(1)", values), b"x");
+        assert_eq!(aggregate("last(1)", values), b"y");
+        assert_eq!(aggregate("max(1)", &[b"x"]), b"");
+    }
+
+    #[test]
+    fn rejects_bad_specs() {
+        for spec in ["sum", "sum()", "sum(0)", "sum(x)", "median(2)"] {
+            assert!(spec.parse::<Aggregation>().is_err(), "{}", spec);
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::process;
+
+/// Writes to a temporary file beside the destination, which `commit` renames
+/// over it, so the destination never holds partial output even if the run
+/// fails or is killed. The temporary file is removed if dropped uncommitted.
+pub struct AtomicFile {
+    // Taken to close it once committed or dropped
+    out: Option<BufWriter<fs::File>>,
+    temp: PathBuf,
+    path: PathBuf,
+}
+
+impl AtomicFile {
+    pub fn create(path: &str) -> io::Result<AtomicFile> {
+        let path = PathBuf::from(path);
+        let name = match path.file_name() {
+            Some(name) => name,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a file name")),
+        };
+        // Hidden, and in the same directory so the rename can't cross filesystems
+        let mut temp = OsString::from(".");
+        temp.push(name);
+        temp.push(format!(".{}.tmp", process::id()));
+        let temp = path.with_file_name(temp);
+        let file = fs::OpenOptions::new().write(true).create_new(true).open(&temp)?;
+        let atomic = AtomicFile { out: Some(BufWriter::new(file)), temp, path };
+        // A replaced file keeps its permissions, rather than those of the umask
+        match fs::metadata(&atomic.path) {
+            Ok(metadata) => fs::set_permissions(&atomic.temp, metadata.permissions())?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(atomic)
+    }
+
+    /// Replaces the destination with everything written
+    pub fn commit(mut self) -> io::Result<()> {
+        let file = self.out.take().unwrap().into_inner().map_err(|e| e.into_error())?;
+        // Otherwise a crash soon after the rename could leave an empty file
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&self.temp, &self.path)
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.out.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.as_mut().unwrap().flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        // Closed first, as open files can't be removed on Windows. Once
+        // committed there's nothing left to remove.
+        self.out.take();
+        let _ = fs::remove_file(&self.temp);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn keeps_permissions() {
+        let path = env::temp_dir().join(format!("tsvfirst-atomic-{}", process::id()));
+        fs::write(&path, "old\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        let mut file = AtomicFile::create(path.to_str().unwrap()).unwrap();
+        file.write_all(b"new\n").unwrap();
+        file.commit().unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
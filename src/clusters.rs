@@ -0,0 +1,32 @@
+use std::fs;
+use std::io;
+use std::io::{BufWriter, Write};
+
+/// Writes one line per input row naming the duplicate cluster (distinct key)
+/// it belongs to: `cluster<TAB>role<TAB>source<TAB>line`, where role is
+/// `original` for the first row with the key and `duplicate` otherwise.
+/// Rows without a key (unmatched by --line-regex) have an empty cluster and
+/// the role `unkeyed`.
+pub struct ClusterFile {
+    out: BufWriter<fs::File>,
+}
+
+impl ClusterFile {
+    pub fn create(path: &str) -> io::Result<ClusterFile> {
+        Ok(ClusterFile { out: BufWriter::new(fs::File::create(path)?) })
+    }
+
+    /// `cluster` is the key's id and occurrence number, if it has a key
+    pub fn record(&mut self, cluster: Option<(u64, u64)>, source: &str, line: u64) -> io::Result<()> {
+        match cluster {
+            Some((id, 1)) => write!(self.out, "{}\toriginal", id)?,
+            Some((id, _)) => write!(self.out, "{}\tduplicate", id)?,
+            None => write!(self.out, "\tunkeyed")?,
+        }
+        writeln!(self.out, "\t{}\t{}", source, line)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
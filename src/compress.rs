@@ -0,0 +1,282 @@
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::str::FromStr;
+
+use bzip2::read::MultiBzDecoder;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use xz2::read::XzDecoder;
+use zstd;
+
+/// Leading bytes of each supported compressed format
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+const ZSTD_MAGIC: &[u8] = b"\x28\xb5\x2f\xfd";
+const XZ_MAGIC: &[u8] = b"\xfd7zXZ\x00";
+/// Bytes read from an input at a time, large enough that a block holds many
+/// rows to scan for line endings
+const BUFFER_SIZE: usize = 256 * 1024;
+/// "BZh", the block size digit, then the first block's magic number
+const BZIP2_MAGIC_LENGTH: usize = 10;
+
+/// A compressed format, as told from a stream's first bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Gzip,
+    Zstd,
+    Bzip2,
+    Xz,
+}
+
+impl Format {
+    /// The format a stream starting with `head` is in, if compressed. `head`
+    /// needs to be 10 bytes to recognise bzip2.
+    pub fn sniff(head: &[u8]) -> Option<Format> {
+        if head.starts_with(GZIP_MAGIC) {
+            Some(Format::Gzip)
+        }
+        else if head.starts_with(ZSTD_MAGIC) {
+            Some(Format::Zstd)
+        }
+        else if head.starts_with(XZ_MAGIC) {
+            Some(Format::Xz)
+        }
+        else if is_bzip2(head) {
+            Some(Format::Bzip2)
+        }
+        else {
+            None
+        }
+    }
+
+    /// The format of the file at `path`, if compressed
+    pub fn of_file(path: &str) -> io::Result<Option<Format>> {
+        let (head, _) = read_head(fs::File::open(path)?)?;
+        Ok(Format::sniff(&head))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Gzip => "gzip",
+            Format::Zstd => "zstd",
+            Format::Bzip2 => "bzip2",
+            Format::Xz => "xz",
+        }
+    }
+
+    /// How to write this format, if it can be written
+    pub fn compression(self) -> Option<Compression> {
+        match self {
+            Format::Gzip => Some(Compression::Gzip(6)),
+            Format::Zstd => Some(Compression::Zstd(zstd::DEFAULT_COMPRESSION_LEVEL)),
+            Format::Bzip2 | Format::Xz => None,
+        }
+    }
+}
+
+/// Reads `input`, decompressing it if it starts like a gzip, zstd, bzip2 or
+/// xz stream, whatever its name. Concatenated compressed streams are read one
+/// after another, as by zcat.
+pub fn reader<R: Read + 'static>(input: R) -> io::Result<Box<dyn BufRead>> {
+    let (head, input) = read_head(input)?;
+    let format = Format::sniff(&head);
+    // The sniffed bytes are read again by whatever reads the input
+    let input = io::Cursor::new(head).chain(input);
+    Ok(match format {
+        Some(Format::Gzip) => Box::new(BufReader::with_capacity(BUFFER_SIZE, MultiGzDecoder::new(input))),
+        Some(Format::Zstd) => Box::new(BufReader::with_capacity(BUFFER_SIZE, zstd::Decoder::new(input)?)),
+        Some(Format::Xz) => Box::new(BufReader::with_capacity(BUFFER_SIZE, XzDecoder::new_multi_decoder(input))),
+        Some(Format::Bzip2) => Box::new(BufReader::with_capacity(BUFFER_SIZE, MultiBzDecoder::new(input))),
+        None => Box::new(BufReader::with_capacity(BUFFER_SIZE, input)),
+    })
+}
+
+/// Reads up to the first 10 bytes of `input`, fewer only if it is shorter
+fn read_head<R: Read>(mut input: R) -> io::Result<(Vec<u8>, R)> {
+    let mut head = [0; BZIP2_MAGIC_LENGTH];
+    let mut length = 0;
+    while length < head.len() {
+        match input.read(&mut head[length..]) {
+            Ok(0) => break,
+            Ok(n) => length += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((head[..length].to_vec(), input))
+}
+
+fn is_bzip2(head: &[u8]) -> bool {
+    head.len() == BZIP2_MAGIC_LENGTH && head.starts_with(b"BZh") && (b'1'..=b'9').contains(&head[3])
+        && &head[4..] == b"\x31\x41\x59\x26\x53\x59"
+}
+
+/// How to compress the output
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    /// gzip at a level from 0 (none) to 9 (smallest)
+    Gzip(u32),
+    /// zstd at a level from 1 (fastest) to 22 (smallest)
+    Zstd(i32),
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    /// `gzip` or `zstd`, optionally with a level, e.g. `zstd:19`
+    fn from_str(s: &str) -> Result<Compression, String> {
+        let (method, level) = match s.find(':') {
+            Some(colon) => (&s[..colon], Some(&s[colon + 1..])),
+            None => (s, None),
+        };
+        let bad_level = |level: &str| format!("invalid {} level '{}'", method, level);
+        match method {
+            "gzip" => match level {
+                None => Ok(Compression::Gzip(6)),
+                Some(level) => match level.parse() {
+                    Ok(n) if n <= 9 => Ok(Compression::Gzip(n)),
+                    _ => Err(bad_level(level)),
+                },
+            },
+            "zstd" => match level {
+                None => Ok(Compression::Zstd(zstd::DEFAULT_COMPRESSION_LEVEL)),
+                Some(level) => match level.parse() {
+                    Ok(n) if (1..=22).contains(&n) => Ok(Compression::Zstd(n)),
+                    _ => Err(bad_level(level)),
+                },
+            },
+            _ => Err(format!("unknown compression '{}'", method)),
+        }
+    }
+}
+
+impl Compression {
+    /// The default compression for a filename's extension, if any
+    pub fn for_path(path: &str) -> Option<Compression> {
+        if path.ends_with(".gz") {
+            Some(Compression::Gzip(6))
+        }
+        else if path.ends_with(".zst") {
+            Some(Compression::Zstd(zstd::DEFAULT_COMPRESSION_LEVEL))
+        }
+        else {
+            None
+        }
+    }
+
+    pub fn writer<W: Write>(self, inner: W) -> io::Result<Compressed<W>> {
+        Ok(match self {
+            Compression::Gzip(level) => Compressed::Gzip(GzEncoder::new(inner, flate2::Compression::new(level))),
+            Compression::Zstd(level) => Compressed::Zstd(zstd::Encoder::new(inner, level)?),
+        })
+    }
+}
+
+/// A writer that may compress what is written to it
+pub enum Compressed<W: Write> {
+    Plain(W),
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Compressed<W> {
+    /// Writes the end of the compressed stream, returning the inner writer
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Compressed::Plain(inner) => Ok(inner),
+            Compressed::Gzip(encoder) => encoder.finish(),
+            Compressed::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Compressed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Compressed::Plain(ref mut inner) => inner.write(buf),
+            Compressed::Gzip(ref mut encoder) => encoder.write(buf),
+            Compressed::Zstd(ref mut encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Compressed::Plain(ref mut inner) => inner.flush(),
+            Compressed::Gzip(ref mut encoder) => encoder.flush(),
+            Compressed::Zstd(ref mut encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bzip2::write::BzEncoder;
+    use xz2::write::XzEncoder;
+
+    fn read_all(input: Vec<u8>) -> Vec<u8> {
+        let mut output = vec![];
+        reader(io::Cursor::new(input)).unwrap().read_to_end(&mut output).unwrap();
+        output
+    }
+
+    /// Compresses each part as a separate member, one after another
+    fn members<W: Write, F: Fn(Vec<u8>) -> W, G: Fn(W) -> io::Result<Vec<u8>>>(parts: &[&[u8]], new: F, finish: G) -> Vec<u8> {
+        let mut stream = vec![];
+        for part in parts {
+            let mut encoder = new(vec![]);
+            encoder.write_all(part).unwrap();
+            stream.extend(finish(encoder).unwrap());
+        }
+        stream
+    }
+
+    const PARTS: &[&[u8]] = &[b"a\t1\n", b"b\t2\n", b"a\t3\n"];
+
+    #[test]
+    fn reads_concatenated_gzip() {
+        let input = members(PARTS, |w| GzEncoder::new(w, flate2::Compression::default()), GzEncoder::finish);
+        assert_eq!(read_all(input), PARTS.concat());
+    }
+
+    #[test]
+    fn reads_concatenated_zstd() {
+        let input = members(PARTS, |w| zstd::Encoder::new(w, 3).unwrap(), zstd::Encoder::finish);
+        assert_eq!(read_all(input), PARTS.concat());
+    }
+
+    #[test]
+    fn reads_concatenated_bzip2() {
+        let input = members(PARTS, |w| BzEncoder::new(w, bzip2::Compression::default()), BzEncoder::finish);
+        assert_eq!(read_all(input), PARTS.concat());
+    }
+
+    #[test]
+    fn reads_concatenated_xz() {
+        let input = members(PARTS, |w| XzEncoder::new(w, 6), XzEncoder::finish);
+        assert_eq!(read_all(input), PARTS.concat());
+    }
+
+    #[test]
+    fn sniffs_formats() {
+        let gzip = members(PARTS, |w| GzEncoder::new(w, flate2::Compression::default()), GzEncoder::finish);
+        let bzip2 = members(PARTS, |w| BzEncoder::new(w, bzip2::Compression::default()), BzEncoder::finish);
+        let xz = members(PARTS, |w| XzEncoder::new(w, 6), XzEncoder::finish);
+        assert_eq!(Format::sniff(&gzip), Some(Format::Gzip));
+        assert_eq!(Format::sniff(&bzip2[..BZIP2_MAGIC_LENGTH]), Some(Format::Bzip2));
+        assert_eq!(Format::sniff(&xz), Some(Format::Xz));
+        assert_eq!(Format::sniff(b"\x28\xb5\x2f\xfd"), Some(Format::Zstd));
+        // A bzip2 header needs its block magic too
+        assert_eq!(Format::sniff(&bzip2[..4]), None);
+        assert_eq!(Format::sniff(b"BZh9\tx\n"), None);
+        assert_eq!(Format::sniff(b"a\t1\n"), None);
+        assert_eq!(Format::sniff(b""), None);
+    }
+
+    #[test]
+    fn reads_short_inputs_as_is() {
+        for input in [&b""[..], b"a", b"a\tb\n", b"BZh9\n", b"\x1f"] {
+            assert_eq!(read_all(input.to_vec()), input);
+        }
+    }
+}
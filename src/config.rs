@@ -1,23 +1,216 @@
-use std::io::prelude::*;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::io;
-use std::io::BufReader;
+use std::io::{BufRead, BufWriter, Read, Write};
+use std::env;
+use std::error;
+use std::fmt;
 use std::fs;
+use std::mem;
+use std::path::{self, Component, PathBuf};
+use std::vec;
+
+use regex::bytes::Regex;
+
+use agg::Aggregation;
+use atomic::AtomicFile;
+use compress::{self, Compressed, Compression};
+use diagnostics::ErrorFormat;
+use filter::Predicate;
+use glob;
+use http;
+use keep::{Keep, TieBreak};
+use key::{self, KeyBuilder, KeyField, LongKey, NoMatch};
+use mask::FieldMask;
+use output::{GroupSeparator, OutputFormat, SqlDialect};
+use partition::Partitions;
+use state;
+use template::Template;
+use utf8::Utf8Mode;
+
+/// What --count-distinct reports
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CountDistinct {
+    /// The number of distinct keys in all the inputs
+    Total,
+    /// Each input's name and number of distinct keys, counted separately
+    PerFile,
+}
+
+/// When --output is split into numbered chunks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rotate {
+    /// Once a chunk has this many lines
+    Lines(u64),
+    /// Before a line would take a chunk over this many bytes, uncompressed
+    Bytes(u64),
+}
 
 #[derive(Debug)]
 pub struct Config {
     pub inputs: Vec<String>,  // empty implies stdin
-    pub fields: Vec<usize>,
+    pub output: Option<String>,  // file to replace with the results; None implies stdout
+    pub compress: Option<Compression>,  // None infers it from the output's extension
+    pub rotate: Option<Rotate>,  // split the output file into numbered chunks
+    pub state: Option<String>,  // file of progress to carry on from and update
+    pub append: bool,  // append to the output file rather than replace it, with state
+    pub skip_bad_inputs: bool,  // warn about and skip inputs that can't be opened
+    pub glob: Option<String>,  // only read files matching this from directory inputs
+    pub files_from: Option<String>,  // file listing more inputs; "-" for stdin
+    pub files_from_separator: u8,  // ends each name in files_from
+    pub fields: Vec<KeyField>,
+    pub filters: Vec<Predicate>,  // rows failing any of these are ignored
+    pub having: Vec<Predicate>,  // kept rows failing any of these aren't written
+    pub line_regex: Option<String>,
+    pub no_match: NoMatch,
+    pub partitions: Option<Partitions>,  // ranges whose labels are added to keys
+    pub outside_partitions: NoMatch,  // for rows in none of the partitions
+    pub alias_map: Option<String>,  // file of key value aliases
+    pub numeric_precision: Option<u32>,
+    pub key_xxhash: bool,  // key on a hash of the key fields
+    pub hash_seed: u64,  // salts key hashes; random per Config by default
+    pub hash_secret: Option<Vec<u8>>,  // key hashes are HMACs under this instead
+    pub max_key_bytes: Option<usize>,  // longer keys are handled per long_key
+    pub long_key: LongKey,
+    pub emit_key_hash: bool,  // prefix output rows with the key hash
+    pub count_prefix: bool,  // prefix output rows with their key's row count
+    pub count_distinct: Option<CountDistinct>,  // write this instead of rows
+    pub pipe_per_key: Option<String>,  // command to write each key's rows to instead
+    pub pipe_jobs: usize,  // most --pipe-per-key commands open at once
+    pub keep: Keep,  // which row to keep per key
+    pub keep_rules: Option<String>,  // file of policies overriding keep for some keys
+    pub tie_breaks: Vec<TieBreak>,  // decide MaxBy/MinBy ties in turn; else the earlier row is kept
+    pub aggregations: Vec<Aggregation>,  // columns appended to kept rows combining their key's values
+    pub max_per_key: u64,  // rows to keep per key with Keep::First
+    pub seed: Option<u64>,  // for --keep random; None varies per run
+    pub all_duplicates: bool,  // write every row of keys seen more than once
+    pub invert: bool,  // write only the rows that would be dropped
+    pub always_keep_from: Option<String>,  // file of keys whose rows are all kept
+    pub across_files_only: bool,  // only drop keys first seen in an earlier input
     pub sorted: bool,
+    pub sorted_by: Vec<KeyField>,  // input is grouped by these key fields
     pub whitespace: bool,
+    pub quoted: bool,  // allow for double-quoted fields when finding key fields
+    pub ascii_whitespace: bool,  // only split on ASCII whitespace with `whitespace`
+    pub max_dupe_ratio: Option<f64>,  // fail if a larger fraction of rows are dropped
+    pub pad_fields: Option<usize>,  // pad shorter rows with empty fields to this many
+    pub truncate_fields: bool,  // and cut longer rows down to it
+    pub skip_footer: usize,  // rows at the end of each input to ignore
+    pub footer_regex: Option<String>,  // ignore rows from the first match to the end of the input
+    pub head: Option<usize>,  // stop after this many kept rows
+    pub max_row_buffer: Option<usize>,  // stream rows longer than this
+    pub memory_limit: Option<usize>,  // soft limit on bytes used to remember keys
+    pub adaptive_memory: bool,  // switch to key hashes at the limit rather than fail
+    pub output_format: OutputFormat,
+    pub table: String,
+    pub sql_dialect: SqlDialect,
+    pub template: Option<Template>,  // write kept rows through this instead
+    pub group_separator: Option<GroupSeparator>,  // write every row, with blank lines around key groups
+    pub ensure_eol: bool,  // end a final row lacking a newline with one
+    pub mark_dupes: Option<Vec<u8>>,  // write duplicates with this prefix rather than dropping them
+    pub mask_key: Vec<FieldMask>,
+    pub index: Option<String>,  // file to write the output's key index to
+    pub clusters: Option<String>,  // file to write row cluster assignments to
+    pub summary: Option<String>,  // file to write each key's row count to
+    pub dupes_to: Option<String>,  // file to write dropped rows to
+    pub utf8: Utf8Mode,
+    pub profile: bool,
+    pub trace_keys: Option<String>,  // log decisions about keys matching this regex to stderr
+    pub stats_per_file: bool,  // report row counts per input to stderr
+    pub progress: bool,
+    pub expected_lines: Option<u64>,  // progress hints for an ETA
+    pub expected_bytes: Option<u64>,
+    pub byte_exact: bool,  // refuse options that change kept rows
+    pub warnings: bool,  // warn on stderr about options that look wrong for the input
+    pub errors: Option<ErrorFormat>,  // None discards diagnostics
+    pub errors_file: Option<String>,  // None implies stderr
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
+    }
 }
 
 impl Config {
     pub fn new() -> Config {
         Config {
             inputs: vec![],
-            fields: vec![1],
+            output: None,
+            compress: None,
+            rotate: None,
+            state: None,
+            append: false,
+            skip_bad_inputs: false,
+            glob: None,
+            files_from: None,
+            files_from_separator: b'\n',
+            fields: vec![KeyField::new(0)],
+            filters: vec![],
+            having: vec![],
+            line_regex: None,
+            no_match: NoMatch::Pass,
+            partitions: None,
+            outside_partitions: NoMatch::Pass,
+            alias_map: None,
+            numeric_precision: None,
+            key_xxhash: false,
+            hash_seed: random_seed(),
+            hash_secret: None,
+            max_key_bytes: None,
+            long_key: LongKey::Hash,
+            emit_key_hash: false,
+            count_prefix: false,
+            count_distinct: None,
+            pipe_per_key: None,
+            pipe_jobs: 8,
+            keep: Keep::First,
+            keep_rules: None,
+            tie_breaks: vec![],
+            aggregations: vec![],
+            max_per_key: 1,
+            seed: None,
+            all_duplicates: false,
+            invert: false,
+            always_keep_from: None,
+            across_files_only: false,
             sorted: false,
+            sorted_by: vec![],
             whitespace: false,
+            quoted: false,
+            ascii_whitespace: false,
+            max_dupe_ratio: None,
+            pad_fields: None,
+            truncate_fields: false,
+            skip_footer: 0,
+            footer_regex: None,
+            head: None,
+            max_row_buffer: None,
+            memory_limit: None,
+            adaptive_memory: true,
+            output_format: OutputFormat::Tsv,
+            table: "rows".into(),
+            sql_dialect: SqlDialect::Postgres,
+            template: None,
+            group_separator: None,
+            ensure_eol: false,
+            mark_dupes: None,
+            mask_key: vec![],
+            index: None,
+            clusters: None,
+            summary: None,
+            dupes_to: None,
+            utf8: Utf8Mode::Ignore,
+            profile: false,
+            trace_keys: None,
+            stats_per_file: false,
+            progress: false,
+            expected_lines: None,
+            expected_bytes: None,
+            byte_exact: false,
+            warnings: true,
+            errors: None,
+            errors_file: None,
         }
     }
 
@@ -26,51 +219,947 @@ impl Config {
         self
     }
 
-    pub fn fields(mut self, fields: &[usize]) -> Config {
+    pub fn skip_bad_inputs(mut self, yes: bool) -> Config {
+        self.skip_bad_inputs = yes;
+        self
+    }
+
+    /// Also read the inputs named in the file at `path` ("-" for stdin), each
+    /// ended by `separator`, typically '\n' or '\0'. The list is read when the
+    /// inputs are.
+    pub fn files_from(mut self, path: &str, separator: u8) -> Config {
+        self.files_from = Some(path.into());
+        self.files_from_separator = separator;
+        self
+    }
+
+    /// Only read the files in directory inputs whose names match `pattern`,
+    /// where `*` matches any run of characters and `?` any one
+    pub fn glob(mut self, pattern: &str) -> Config {
+        self.glob = Some(pattern.into());
+        self
+    }
+
+    pub fn output(mut self, path: &str) -> Config {
+        self.output = Some(path.into());
+        self
+    }
+
+    pub fn compress(mut self, compression: Compression) -> Config {
+        self.compress = Some(compression);
+        self
+    }
+
+    /// Writes the output file in numbered chunks, e.g. out.0001.tsv, out.0002.tsv
+    /// for out.tsv, each no bigger than `rotate` allows (unless a single line
+    /// is), and each replaced as soon as it is full
+    pub fn rotate(mut self, rotate: Rotate) -> Config {
+        self.rotate = Some(rotate);
+        self
+    }
+
+    /// Carries on from the progress saved in `path` by an earlier run on the
+    /// same single input file, reading only rows added since and treating
+    /// keys seen then as seen, then saves the progress made. Only complete
+    /// lines are read, so a row still being written is left for the next run.
+    pub fn state(mut self, path: &str) -> Config {
+        self.state = Some(path.to_owned());
+        self
+    }
+
+    /// With `state`, adds the rows kept to the end of the output file rather
+    /// than replacing it. The file is first cut back to its length when the
+    /// state was saved, dropping anything a failed run left.
+    pub fn append(mut self, yes: bool) -> Config {
+        self.append = yes;
+        self
+    }
+
+    /// How the output is compressed, if at all
+    pub fn output_compression(&self) -> Option<Compression> {
+        self.compress.or_else(|| self.output.as_ref().and_then(|path| Compression::for_path(path)))
+    }
+
+    pub fn fields(mut self, fields: &[KeyField]) -> Config {
         self.fields = fields.to_owned();
         self
     }
 
+    pub fn filter(mut self, predicate: Predicate) -> Config {
+        self.filters.push(predicate);
+        self
+    }
+
+    pub fn having(mut self, predicate: Predicate) -> Config {
+        self.having.push(predicate);
+        self
+    }
+
+    pub fn tie_break(mut self, tie_break: TieBreak) -> Config {
+        self.tie_breaks.push(tie_break);
+        self
+    }
+
+    pub fn aggregate(mut self, aggregation: Aggregation) -> Config {
+        self.aggregations.push(aggregation);
+        self
+    }
+
+    pub fn line_regex(mut self, pattern: &str) -> Config {
+        self.line_regex = Some(pattern.into());
+        self
+    }
+
+    pub fn no_match(mut self, policy: NoMatch) -> Config {
+        self.no_match = policy;
+        self
+    }
+
+    /// Handles keys longer than `limit` bytes per `policy`, to bound the
+    /// memory a pathological row's key takes
+    pub fn max_key_bytes(mut self, limit: usize, policy: LongKey) -> Config {
+        self.max_key_bytes = Some(limit);
+        self.long_key = policy;
+        self
+    }
+
+    /// Adds the label of the range a field's value falls in to each key, so
+    /// that a row is kept per key per range. Rows in no range are handled
+    /// per `outside`.
+    pub fn partition_by(mut self, partitions: Partitions, outside: NoMatch) -> Config {
+        self.partitions = Some(partitions);
+        self.outside_partitions = outside;
+        self
+    }
+
+    pub fn alias_map(mut self, path: &str) -> Config {
+        self.alias_map = Some(path.into());
+        self
+    }
+
+    pub fn numeric_precision(mut self, places: u32) -> Config {
+        self.numeric_precision = Some(places);
+        self
+    }
+
+    pub fn key_xxhash(mut self, yes: bool) -> Config {
+        self.key_xxhash = yes;
+        self
+    }
+
+    pub fn hash_seed(mut self, seed: u64) -> Config {
+        self.hash_seed = seed;
+        self
+    }
+
+    /// Hash keys with HMAC-SHA256 under `secret` rather than salted XXH3, so
+    /// that hashes written out can't be matched against other data without it
+    pub fn hash_secret(mut self, secret: &[u8]) -> Config {
+        self.hash_secret = Some(secret.to_owned());
+        self
+    }
+
+    pub fn emit_key_hash(mut self, yes: bool) -> Config {
+        self.emit_key_hash = yes;
+        self
+    }
+
+    pub fn count_prefix(mut self, yes: bool) -> Config {
+        self.count_prefix = yes;
+        self
+    }
+
+    pub fn pipe_per_key(mut self, command: &str, jobs: usize) -> Config {
+        self.pipe_per_key = Some(command.to_owned());
+        self.pipe_jobs = jobs;
+        self
+    }
+
+    pub fn count_distinct(mut self, mode: CountDistinct) -> Config {
+        self.count_distinct = Some(mode);
+        self
+    }
+
+    pub fn keep(mut self, policy: Keep) -> Config {
+        self.keep = policy;
+        self
+    }
+
+    /// Reads policies for keys matching patterns from a rules file (see
+    /// `keep::KeepRules`), overriding `keep` for them
+    pub fn keep_rules(mut self, path: &str) -> Config {
+        self.keep_rules = Some(path.to_owned());
+        self
+    }
+
+    pub fn max_per_key(mut self, rows: u64) -> Config {
+        self.max_per_key = rows;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Config {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn all_duplicates(mut self, yes: bool) -> Config {
+        self.all_duplicates = yes;
+        self
+    }
+
+    pub fn invert(mut self, yes: bool) -> Config {
+        self.invert = yes;
+        self
+    }
+
+    pub fn always_keep_from(mut self, path: &str) -> Config {
+        self.always_keep_from = Some(path.to_owned());
+        self
+    }
+
+    pub fn across_files_only(mut self, yes: bool) -> Config {
+        self.across_files_only = yes;
+        self
+    }
+
     pub fn sorted(mut self, yes: bool) -> Config {
         self.sorted = yes;
         self
     }
 
+    pub fn sorted_by(mut self, fields: &[KeyField]) -> Config {
+        self.sorted_by = fields.to_owned();
+        self
+    }
+
+    pub fn quoted(mut self, yes: bool) -> Config {
+        self.quoted = yes;
+        self
+    }
+
     pub fn whitespace(mut self, yes: bool) -> Config {
         self.whitespace = yes;
         self
     }
 
-    pub fn get_reader(&self) -> io::Result<Box<io::BufRead>> {
-        let default_input = vec!["-".into()];
-        let inputs = if self.inputs.is_empty() {
-            &default_input
+    pub fn max_dupe_ratio(mut self, ratio: f64) -> Config {
+        self.max_dupe_ratio = Some(ratio);
+        self
+    }
+
+    pub fn ascii_whitespace(mut self, yes: bool) -> Config {
+        self.ascii_whitespace = yes;
+        self
+    }
+
+    pub fn pad_fields(mut self, count: usize, truncate: bool) -> Config {
+        self.pad_fields = Some(count);
+        self.truncate_fields = truncate;
+        self
+    }
+
+    pub fn skip_footer(mut self, rows: usize) -> Config {
+        self.skip_footer = rows;
+        self
+    }
+
+    pub fn footer_regex(mut self, pattern: &str) -> Config {
+        self.footer_regex = Some(pattern.into());
+        self
+    }
+
+    pub fn head(mut self, rows: usize) -> Config {
+        self.head = Some(rows);
+        self
+    }
+
+    pub fn max_row_buffer(mut self, bytes: usize) -> Config {
+        self.max_row_buffer = Some(bytes);
+        self
+    }
+
+    pub fn memory_limit(mut self, bytes: usize) -> Config {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    pub fn adaptive_memory(mut self, yes: bool) -> Config {
+        self.adaptive_memory = yes;
+        self
+    }
+
+    pub fn output_format(mut self, format: OutputFormat) -> Config {
+        self.output_format = format;
+        self
+    }
+
+    pub fn table(mut self, table: &str) -> Config {
+        self.table = table.into();
+        self
+    }
+
+    pub fn sql_dialect(mut self, dialect: SqlDialect) -> Config {
+        self.sql_dialect = dialect;
+        self
+    }
+
+    pub fn template(mut self, template: Template) -> Config {
+        self.template = Some(template);
+        self
+    }
+
+    pub fn group_separator(mut self, separator: GroupSeparator) -> Config {
+        self.group_separator = Some(separator);
+        self
+    }
+
+    pub fn ensure_eol(mut self, yes: bool) -> Config {
+        self.ensure_eol = yes;
+        self
+    }
+
+    pub fn mark_dupes(mut self, prefix: &[u8]) -> Config {
+        self.mark_dupes = Some(prefix.to_vec());
+        self
+    }
+
+    pub fn mask_key(mut self, mask: FieldMask) -> Config {
+        self.mask_key.push(mask);
+        self
+    }
+
+    pub fn index(mut self, path: &str) -> Config {
+        self.index = Some(path.into());
+        self
+    }
+
+    pub fn summary(mut self, path: &str) -> Config {
+        self.summary = Some(path.to_owned());
+        self
+    }
+
+    pub fn dupes_to(mut self, path: &str) -> Config {
+        self.dupes_to = Some(path.to_owned());
+        self
+    }
+
+    pub fn clusters(mut self, path: &str) -> Config {
+        self.clusters = Some(path.into());
+        self
+    }
+
+    pub fn utf8(mut self, mode: Utf8Mode) -> Config {
+        self.utf8 = mode;
+        self
+    }
+
+    pub fn profile(mut self, yes: bool) -> Config {
+        self.profile = yes;
+        self
+    }
+
+    pub fn trace_keys(mut self, pattern: &str) -> Config {
+        self.trace_keys = Some(pattern.into());
+        self
+    }
+
+    pub fn stats_per_file(mut self, yes: bool) -> Config {
+        self.stats_per_file = yes;
+        self
+    }
+
+    pub fn progress(mut self, yes: bool) -> Config {
+        self.progress = yes;
+        self
+    }
+
+    pub fn expected_lines(mut self, lines: u64) -> Config {
+        self.expected_lines = Some(lines);
+        self
+    }
+
+    pub fn expected_bytes(mut self, bytes: u64) -> Config {
+        self.expected_bytes = Some(bytes);
+        self
+    }
+
+    pub fn byte_exact(mut self, yes: bool) -> Config {
+        self.byte_exact = yes;
+        self
+    }
+
+    pub fn warnings(mut self, yes: bool) -> Config {
+        self.warnings = yes;
+        self
+    }
+
+    pub fn errors(mut self, format: Option<ErrorFormat>) -> Config {
+        self.errors = format;
+        self
+    }
+
+    pub fn errors_file(mut self, path: &str) -> Config {
+        self.errors_file = Some(path.into());
+        self
+    }
+
+    /// Whether kept rows are held back until their key's rows have all been
+    /// seen: for --keep policies other than first and nth, --rules, --agg, and to
+    /// know the key's row count for --count, {count} in --format or count in
+    /// --having
+    pub fn buffers_rows(&self) -> bool {
+        !self.keep.is_streamed()
+            || self.keep_rules.is_some()
+            || !self.aggregations.is_empty()
+            || self.count_prefix
+            || self.template.as_ref().is_some_and(|t| t.uses_count())
+            || self.having.iter().any(|p| p.uses_count())
+    }
+
+    /// Names the first option that writes rows other than exactly as they
+    /// were read, if any
+    pub fn rewriting_option(&self) -> Option<&'static str> {
+        if self.output_format != OutputFormat::Tsv {
+            Some("--output-format")
+        }
+        else if self.template.is_some() {
+            Some("--format")
+        }
+        else if !self.mask_key.is_empty() {
+            Some("--mask-key")
+        }
+        else if self.mark_dupes.is_some() {
+            Some("--mark-dupes")
+        }
+        else if self.emit_key_hash {
+            Some("--emit-key-hash")
+        }
+        else if self.count_prefix {
+            Some("--count")
+        }
+        else if !self.aggregations.is_empty() {
+            Some("--agg")
+        }
+        else if self.pad_fields.is_some() {
+            Some("--pad-fields")
+        }
+        else if self.utf8 == Utf8Mode::Lossy {
+            Some("--utf8 lossy")
+        }
+        else if self.ensure_eol {
+            Some("--ensure-eol")
+        }
+        // Buffered winners are given a newline, as they may be written out of order
+        else if !self.keep.is_streamed() {
+            Some("--keep")
+        }
+        else if self.keep_rules.is_some() {
+            Some("--rules")
+        }
+        else if self.buffers_rows() {
+            Some("--having")
         }
         else {
-            &self.inputs
+            None
+        }
+    }
+
+    /// Regex matching the delimiter between fields. Whitespace includes
+    /// Unicode spaces such as U+00A0 unless limited to ASCII.
+    pub fn splitter(&self) -> Regex {
+        let delim = match (self.whitespace, self.ascii_whitespace) {
+            (false, _) => r"\t",
+            (true, false) => r"\s+",
+            (true, true) => r"(?-u:\s)+",
         };
+        Regex::new(delim).unwrap()
+    }
+
+    pub fn key_builder(&self) -> Result<KeyBuilder, Box<dyn error::Error>> {
+        let mut keys = KeyBuilder::new(&self.fields, self.splitter());
+        if let Some(ref pattern) = self.line_regex {
+            keys = keys.line_regex(Regex::new(pattern)?);
+        }
+        if let Some(places) = self.numeric_precision {
+            keys = keys.numeric_precision(places);
+        }
+        keys = keys.hashed(self.key_xxhash).hash_seed(self.hash_seed).quoted(self.quoted);
+        if let Some(ref secret) = self.hash_secret {
+            keys = keys.hash_secret(secret);
+        }
+        if let Some(ref partitions) = self.partitions {
+            keys = keys.partitions(partitions.clone());
+        }
+        if let Some(ref path) = self.alias_map {
+            keys = keys.aliases(key::load_aliases(path)?);
+        }
+        Ok(keys)
+    }
 
-        let mut reader : Option<Box<BufRead>> = None;
-        let mut stdin_used = false;
-        for input in inputs {
-            let sub_reader : Box<BufRead> = match input.as_ref() {
-                "-" => if stdin_used {
-                        return Err(io::Error::new(io::ErrorKind::Other, "stdin used twice"));
-                    }
-                    else {
-                        stdin_used = true;
-                        // Experimental: lock io::stdin() for duration of program
-                        // Should be fine as it's a few bytes
-                        let stdin = Box::leak(Box::new(io::stdin()));
-                        Box::new(stdin.lock())
-                    },
-                filename => Box::new(BufReader::new(fs::File::open(filename)?)),
-            };
-            reader = match reader {
-                None => Some(sub_reader),
-                Some(reader) => Some(Box::new(reader.chain(sub_reader))),
+    /// Builds keys from key values rather than rows: the key fields alone,
+    /// joined by tabs (or the --line-regex captures, so joined), normalized as
+    /// `key_builder` would
+    pub fn value_key_builder(&self) -> Result<KeyBuilder, Box<dyn error::Error>> {
+        let fields : Vec<KeyField> = self.fields.iter().enumerate()
+            .map(|(i, field)| KeyField { index: i, ..field.clone() })
+            .collect();
+        let mut keys = KeyBuilder::new(&fields, Regex::new(r"\t")?)
+            .hashed(self.key_xxhash)
+            .hash_seed(self.hash_seed);
+        if let Some(ref secret) = self.hash_secret {
+            keys = keys.hash_secret(secret);
+        }
+        if self.line_regex.is_some() {
+            keys = keys.line_regex(Regex::new(r"^(.*)$")?);
+        }
+        if let Some(places) = self.numeric_precision {
+            keys = keys.numeric_precision(places);
+        }
+        if let Some(ref path) = self.alias_map {
+            keys = keys.aliases(key::load_aliases(path)?);
+        }
+        Ok(keys)
+    }
+
+    /// Builds the group key for --sorted-by, if set
+    pub fn group_builder(&self) -> Option<KeyBuilder> {
+        if self.sorted_by.is_empty() {
+            None
+        }
+        else {
+            Some(KeyBuilder::new(&self.sorted_by, self.splitter()))
+        }
+    }
+
+    /// Opens the output. A file is only replaced once `Output::commit` is
+    /// called, so it may also be one of the inputs.
+    pub fn get_output(&self) -> io::Result<Output> {
+        let compression = self.output_compression();
+        match (self.output.as_ref(), self.rotate) {
+            (Some(path), Some(rotate)) => Ok(Output {
+                writer: open_output(Some(&chunk_path(path, 1)), compression)?,
+                rotation: Some(Rotation {
+                    rotate, path: path.clone(), compression, chunk: 1, lines: 0, bytes: 0, partial: vec![],
+                }),
+            }),
+            (Some(path), None) if self.append => {
+                // The saved output length is of what was written, before compression
+                let state = match (self.state.as_ref(), compression) {
+                    (Some(state), None) => state,
+                    _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "append requires state and no compression")),
+                };
+                let file = state::open_output(path, state::output_length(state)?)?;
+                Ok(Output { writer: Compressed::Plain(Destination::Append(BufWriter::new(file))), rotation: None })
             }
+            (path, _) => Ok(Output { writer: open_output(path.map(|p| p.as_str()), compression)?, rotation: None }),
         }
-        Ok(reader.unwrap())
+    }
+
+    /// Returns the inputs in order. Each is opened only when the iterator
+    /// reaches it, so files aren't held open (or checked) before they are needed.
+    pub fn get_inputs(&self) -> io::Result<Inputs> {
+        let mut inputs = self.inputs.clone();
+        if let Some(ref path) = self.files_from {
+            inputs.extend(read_list(path, self.files_from_separator)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?);
+        }
+        else if inputs.is_empty() {
+            inputs.push("-".into());
+        }
+
+        // cmd.exe leaves wildcards for the program to expand. Directories are
+        // replaced by the files within them.
+        let mut expanded = vec![];
+        for input in &inputs {
+            if cfg!(windows) && !http::is_url(input) && glob::is_pattern(input) {
+                expanded.extend(glob::expand(input, true)?);
+            }
+            else if input != "-" && !http::is_url(input) && fs::metadata(input).is_ok_and(|m| m.is_dir()) {
+                expanded.extend(glob::walk(input, self.glob.as_deref(), cfg!(windows))?);
+            }
+            else {
+                expanded.push(input.clone());
+            }
+        }
+        let list_on_stdin = self.files_from.as_ref().is_some_and(|path| path == "-");
+        if expanded.iter().filter(|input| *input == "-").count() + list_on_stdin as usize > 1 {
+            return Err(io::Error::other("stdin used twice"));
+        }
+
+        Ok(Inputs { names: expanded.into_iter() })
+    }
+}
+
+/// Iterator opening each input in turn
+pub struct Inputs {
+    names: vec::IntoIter<String>,
+}
+
+impl Iterator for Inputs {
+    type Item = io::Result<Input>;
+
+    fn next(&mut self) -> Option<io::Result<Input>> {
+        let name = self.names.next()?;
+        let reader : Box<dyn BufRead> = match name.as_ref() {
+            "-" => match compress::reader(stdin()) {
+                Ok(reader) => reader,
+                Err(e) => return Some(Err(io::Error::new(e.kind(), format!("-: {}", e)))),
+            },
+            url if http::is_url(url) => match http::open(url).and_then(compress::reader) {
+                Ok(reader) => reader,
+                Err(e) => return Some(Err(BadInput::error(url, e))),
+            },
+            filename => match open_file(filename).and_then(compress::reader) {
+                Ok(reader) => reader,
+                Err(e) => return Some(Err(BadInput::error(filename, e))),
+            },
+        };
+        Some(Ok(Input { name, reader }))
+    }
+}
+
+/// A file or URL input that couldn't be opened, carried inside the
+/// `io::Error` that `Inputs` returns for it
+pub struct BadInput {
+    pub name: String,
+    pub error: io::Error,
+}
+
+impl BadInput {
+    fn error(name: &str, error: io::Error) -> io::Error {
+        io::Error::new(error.kind(), BadInput { name: name.into(), error })
+    }
+
+    /// The input `error` is for, if it is one that --skip-bad-inputs skips
+    pub fn skipped<'a>(config: &Config, error: &'a io::Error) -> Option<&'a BadInput> {
+        if !config.skip_bad_inputs {
+            return None;
+        }
+        error.get_ref().and_then(|e| e.downcast_ref::<BadInput>())
+    }
+}
+
+impl fmt::Display for BadInput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.name, self.error)
+    }
+}
+
+// As the message it replaces, so errors read the same in main's output
+impl fmt::Debug for BadInput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.to_string(), f)
+    }
+}
+
+impl error::Error for BadInput {}
+
+/// Standard input. On Unix it is read straight from its file descriptor, in
+/// the large blocks `compress::reader` asks for, rather than through the small
+/// buffer `io::stdin()` copies through.
+#[cfg(unix)]
+fn stdin() -> Box<dyn Read> {
+    use std::os::unix::io::FromRawFd;
+
+    /// Left open when dropped, as the process owns it
+    struct RawStdin(mem::ManuallyDrop<fs::File>);
+
+    impl Read for RawStdin {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    // Safe as fd 0 stays open for the life of the process, and nothing else
+    // reads it: get_inputs rejects a second '-'
+    Box::new(RawStdin(mem::ManuallyDrop::new(unsafe { fs::File::from_raw_fd(0) })))
+}
+
+#[cfg(not(unix))]
+fn stdin() -> Box<dyn Read> {
+    // Lock io::stdin() for the duration of the program
+    let stdin = Box::leak(Box::new(io::stdin()));
+    Box::new(stdin.lock())
+}
+
+/// The names in a --files-from list, each ended by `separator`, skipping
+/// empty ones
+fn read_list(path: &str, separator: u8) -> io::Result<Vec<String>> {
+    let list = if path == "-" {
+        let mut list = vec![];
+        io::stdin().lock().read_to_end(&mut list)?;
+        list
+    }
+    else {
+        fs::read(path)?
+    };
+    list.split(|&b| b == separator)
+        .filter(|name| !name.is_empty())
+        .map(|name| String::from_utf8(name.to_owned()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData,
+                format!("name '{}' isn't valid UTF-8", String::from_utf8_lossy(name)))
+        }))
+        .collect()
+}
+
+/// Opens a file. On Windows, paths too long for the legacy MAX_PATH limit are
+/// opened via their extended-length (`\\?\`) form.
+fn open_file(filename: &str) -> io::Result<fs::File> {
+    const MAX_PATH: usize = 260;
+    if !cfg!(windows) || filename.starts_with(r"\\?\") {
+        return fs::File::open(filename);
+    }
+
+    // Extended-length paths must be absolute with no '.' or '..' components
+    let mut absolute = PathBuf::new();
+    for component in env::current_dir()?.join(filename).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => { absolute.pop(); }
+            component => absolute.push(component),
+        }
+    }
+    let absolute = absolute.to_string_lossy().replace('/', "\\");
+    if absolute.len() < MAX_PATH {
+        return fs::File::open(filename);
+    }
+    match absolute.strip_prefix(r"\\") {
+        Some(unc) => fs::File::open(format!(r"\\?\UNC\{}", unc)),
+        None => fs::File::open(format!(r"\\?\{}", absolute)),
+    }
+}
+
+/// Opens `path`, or stdout if `None`, to be replaced once committed
+fn open_output(path: Option<&str>, compression: Option<Compression>) -> io::Result<Compressed<Destination>> {
+    let destination = match path {
+        Some(path) => Destination::File(AtomicFile::create(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?),
+        None => Destination::Stdout(io::stdout()),
+    };
+    Ok(match compression {
+        Some(compression) => compression.writer(destination)?,
+        None => Compressed::Plain(destination),
+    })
+}
+
+/// Finishes writing `writer`, replacing its file
+fn commit_output(writer: Compressed<Destination>) -> io::Result<()> {
+    match writer.finish()? {
+        Destination::Stdout(mut stdout) => stdout.flush(),
+        Destination::File(file) => file.commit(),
+        Destination::Append(file) => file.into_inner().map_err(|e| e.into_error())?.sync_all(),
+    }
+}
+
+/// The name of chunk `n` of the output file `path`: the number goes before
+/// the extensions of the file name, or at its end if it has none
+fn chunk_path(path: &str, n: u32) -> String {
+    let name = path.rfind(['/', path::MAIN_SEPARATOR]).map_or(0, |i| i + 1);
+    // A leading dot hides a file rather than starting an extension
+    let end = path.get(name + 1..).and_then(|rest| rest.find('.')).map_or(path.len(), |i| name + 1 + i);
+    format!("{}.{:04}{}", &path[..end], n, &path[end..])
+}
+
+/// Where results are written
+pub struct Output {
+    writer: Compressed<Destination>,
+    rotation: Option<Rotation>,
+}
+
+/// The progress of an output file written in chunks
+struct Rotation {
+    rotate: Rotate,
+    path: String,
+    compression: Option<Compression>,
+    /// The chunk being written, from 1
+    chunk: u32,
+    /// Written to the chunk so far
+    lines: u64,
+    bytes: u64,
+    /// The end of the output, not yet a whole line
+    partial: Vec<u8>,
+}
+
+impl Output {
+    /// Finishes writing. A file output replaces its destination only now.
+    pub fn commit(mut self) -> io::Result<()> {
+        if self.rotation.as_ref().is_some_and(|rotation| !rotation.partial.is_empty()) {
+            self.write_line()?;
+        }
+        commit_output(self.writer)
+    }
+
+    /// Writes the buffered line, first moving on to the next chunk if it
+    /// belongs there
+    fn write_line(&mut self) -> io::Result<()> {
+        let rotation = self.rotation.as_mut().unwrap();
+        let full = rotation.lines > 0 && match rotation.rotate {
+            Rotate::Lines(lines) => rotation.lines >= lines,
+            Rotate::Bytes(bytes) => rotation.bytes + rotation.partial.len() as u64 > bytes,
+        };
+        if full {
+            rotation.chunk += 1;
+            let next = open_output(Some(&chunk_path(&rotation.path, rotation.chunk)), rotation.compression)?;
+            commit_output(mem::replace(&mut self.writer, next))?;
+            rotation.lines = 0;
+            rotation.bytes = 0;
+        }
+        self.writer.write_all(&rotation.partial)?;
+        rotation.lines += 1;
+        rotation.bytes += rotation.partial.len() as u64;
+        rotation.partial.clear();
+        Ok(())
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.rotation.is_none() {
+            return self.writer.write(buf);
+        }
+        // Chunks only end between lines
+        for line in buf.split_inclusive(|&b| b == b'\n') {
+            self.rotation.as_mut().unwrap().partial.extend_from_slice(line);
+            if line.ends_with(b"\n") {
+                self.write_line()?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+enum Destination {
+    Stdout(io::Stdout),
+    File(AtomicFile),
+    Append(BufWriter<fs::File>),
+}
+
+impl Write for Destination {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Destination::Stdout(ref mut stdout) => stdout.write(buf),
+            Destination::File(ref mut file) => file.write(buf),
+            Destination::Append(ref mut file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Destination::Stdout(ref mut stdout) => stdout.flush(),
+            Destination::File(ref mut file) => file.flush(),
+            Destination::Append(ref mut file) => file.flush(),
+        }
+    }
+}
+
+/// An opened input source, processed in order after the previous one
+pub struct Input {
+    /// Where the rows came from, for messages, e.g. a filename or "-"
+    pub name: String,
+    pub reader: Box<dyn BufRead>,
+}
+
+/// A seed that differs from run to run (and Config to Config)
+fn random_seed() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    /// A fresh directory for one test's files
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("tsvfirst-config-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    fn input_names(config: &Config) -> Vec<String> {
+        config.get_inputs().unwrap().map(|input| input.unwrap().name).collect()
+    }
+
+    #[test]
+    fn files_from_reads_nul_separated_names() {
+        let dir = scratch("files0-from");
+        let odd = dir.join("with\nnewline and space.tsv");
+        let plain = dir.join("plain.tsv");
+        fs::write(&odd, "a\n").unwrap();
+        fs::write(&plain, "b\n").unwrap();
+        let list = dir.join("list");
+        // Empty names, as from a trailing separator, are ignored
+        fs::write(&list, [odd.to_str().unwrap(), "", plain.to_str().unwrap(), ""].join("\0")).unwrap();
+        let config = Config::new().add_input(plain.to_str().unwrap()).files_from(list.to_str().unwrap(), 0);
+        assert_eq!(input_names(&config), vec![plain.to_str().unwrap(), odd.to_str().unwrap(), plain.to_str().unwrap()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn files_from_reads_newline_separated_names() {
+        let dir = scratch("files-from");
+        let a = dir.join("a.tsv");
+        fs::write(&a, "a\n").unwrap();
+        let list = dir.join("list");
+        fs::write(&list, format!("{}\n\n{}\n", a.display(), a.display())).unwrap();
+        let config = Config::new().files_from(list.to_str().unwrap(), b'\n');
+        assert_eq!(input_names(&config), vec![a.to_str().unwrap(), a.to_str().unwrap()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn files_from_rejects_bad_names_and_lists() {
+        let dir = scratch("files-from-bad");
+        let list = dir.join("list");
+        fs::write(&list, b"ok\0bad\xff\0").unwrap();
+        let error = Config::new().files_from(list.to_str().unwrap(), 0).get_inputs().err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let missing = dir.join("missing");
+        assert!(Config::new().files_from(missing.to_str().unwrap(), 0).get_inputs().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn chunk_paths_number_before_extensions() {
+        assert_eq!(chunk_path("out.tsv.gz", 2), "out.0002.tsv.gz");
+        assert_eq!(chunk_path("out", 12345), "out.12345");
+        assert_eq!(chunk_path("a.d/.out.tsv", 1), "a.d/.out.0001.tsv");
+    }
+
+    fn rotated(rotate: Rotate, name: &str, writes: &[&[u8]]) -> Vec<Vec<u8>> {
+        let dir = scratch(name);
+        let path = dir.join("out.tsv");
+        let mut output = Config::new().output(path.to_str().unwrap()).rotate(rotate).get_output().unwrap();
+        for write in writes {
+            output.write_all(write).unwrap();
+        }
+        output.commit().unwrap();
+        let mut names : Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        names.sort();
+        let chunks = names.iter().enumerate().map(|(i, name)| {
+            assert_eq!(name.to_str().unwrap(), format!("out.{:04}.tsv", i + 1));
+            fs::read(dir.join(name)).unwrap()
+        }).collect();
+        fs::remove_dir_all(&dir).unwrap();
+        chunks
+    }
+
+    #[test]
+    fn rotates_by_lines() {
+        let chunks = rotated(Rotate::Lines(2), "rotate-lines", &[b"a\nb", b"\nc\nd\ne\n", b"f"]);
+        assert_eq!(chunks, vec![b"a\nb\n".to_vec(), b"c\nd\n".to_vec(), b"e\nf".to_vec()]);
+    }
+
+    #[test]
+    fn rotates_by_size_between_lines() {
+        let chunks = rotated(Rotate::Bytes(4), "rotate-size", &[b"a\nb\nc\nlong line\nd\n"]);
+        assert_eq!(chunks, vec![b"a\nb\n".to_vec(), b"c\n".to_vec(), b"long line\n".to_vec(), b"d\n".to_vec()]);
+        // An empty output still has a first chunk
+        assert_eq!(rotated(Rotate::Bytes(4), "rotate-empty", &[]), vec![b"".to_vec()]);
     }
 }
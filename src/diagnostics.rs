@@ -0,0 +1,92 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+
+use output::write_json_string;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ErrorFormat, String> {
+        match s {
+            "text" => Ok(ErrorFormat::Text),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(format!("unknown error format '{}'", s)),
+        }
+    }
+}
+
+/// Kinds of non-fatal problem found in the input
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Category {
+    /// Row has fewer fields than the key requires
+    ShortRow,
+    /// Row is not valid UTF-8
+    InvalidUtf8,
+}
+
+impl Category {
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::ShortRow => "short_row",
+            Category::InvalidUtf8 => "invalid_utf8",
+        }
+    }
+}
+
+/// Where in the input a diagnostic applies
+pub struct Location<'a> {
+    pub source: &'a str,
+    /// 1-indexed line number within the source
+    pub line: u64,
+    /// Byte offset of the start of the line within the source
+    pub offset: u64,
+}
+
+/// Sink for non-fatal diagnostics. Reports are discarded unless a format is set.
+pub struct Diagnostics {
+    format: Option<ErrorFormat>,
+    out: Box<dyn Write>,
+}
+
+impl Diagnostics {
+    /// Writes to `path`, or standard error if not given
+    pub fn new(format: Option<ErrorFormat>, path: Option<&str>) -> io::Result<Diagnostics> {
+        let out : Box<dyn Write> = match path {
+            Some(path) if format.is_some() => Box::new(io::BufWriter::new(fs::File::create(path)?)),
+            _ => Box::new(io::stderr()),
+        };
+        Ok(Diagnostics { format, out })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.format.is_some()
+    }
+
+    pub fn report(&mut self, category: Category, at: &Location, message: &str) -> io::Result<()> {
+        match self.format {
+            None => Ok(()),
+            Some(ErrorFormat::Text) => {
+                writeln!(self.out, "tsvfirst: {}:{}: {}", at.source, at.line, message)
+            }
+            Some(ErrorFormat::Json) => {
+                write!(self.out, "{{\"category\":\"{}\",\"source\":", category.name())?;
+                write_json_string(&mut self.out, at.source.as_bytes())?;
+                write!(self.out, ",\"line\":{},\"offset\":{},\"message\":", at.line, at.offset)?;
+                write_json_string(&mut self.out, message.as_bytes())?;
+                self.out.write_all(b"}\n")
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
@@ -0,0 +1,421 @@
+use std::collections::HashSet;
+use std::error;
+use std::mem;
+use std::ops::Range;
+use std::sync::Mutex;
+
+use regex::bytes::Regex;
+
+use config::Config;
+use filter::{self, Predicate};
+use keep::Keep;
+use key::{self, Key, KeyBuilder, LongKey, NoMatch};
+use seen::Seen;
+
+use xxhash_rust::xxh3::xxh3_64;
+
+/// Key sets in a `SharedDeduplicator`, so threads seldom wait on each other
+const SHARDS: usize = 64;
+
+/// Incremental deduplicator for byte streams: feed it arbitrary chunks of
+/// input and it appends each kept record to an output buffer. Records split
+/// across chunk boundaries are buffered until their newline arrives.
+///
+/// Uses the filters and key settings of the `Config` (fields, whitespace, line
+/// regex and no-match policy), `always_keep_from` and `sorted`/`sorted_by`;
+/// input and output options are ignored. Only `Keep::First` and `Keep::Nth`
+/// are supported, and `across_files_only` (there is only one input) and
+/// `pad_fields` (records are written as fed) are refused.
+pub struct Deduplicator {
+    rules: Rules,
+    groups: Option<KeyBuilder>,
+    seen: Seen,
+    /// Start of a record whose newline hasn't been fed yet
+    pending: Vec<u8>,
+}
+
+impl Deduplicator {
+    pub fn new(config: &Config) -> Result<Deduplicator, Box<dyn error::Error>> {
+        Ok(Deduplicator {
+            rules: Rules::new(config)?,
+            groups: config.group_builder(),
+            seen: Seen::new(config.sorted)
+                .memory_limit(config.memory_limit, config.adaptive_memory)
+                .hash_seed(config.hash_seed),
+            pending: vec![],
+        })
+    }
+
+    /// Processes `chunk`, appending every kept record it completes to `out`
+    pub fn feed(&mut self, chunk: &[u8], out: &mut Vec<u8>) -> Result<(), Box<dyn error::Error>> {
+        let mut pending = mem::take(&mut self.pending);
+        let result = split_records(&mut pending, chunk, |record| self.process(record, out));
+        self.pending = pending;
+        result
+    }
+
+    /// Processes any final record that lacked a trailing newline
+    pub fn finish(&mut self, out: &mut Vec<u8>) -> Result<(), Box<dyn error::Error>> {
+        if !self.pending.is_empty() {
+            let record = mem::take(&mut self.pending);
+            self.process(&record, out)?;
+        }
+        Ok(())
+    }
+
+    /// Processes every record in `data`, which holds whole records (the last
+    /// may lack its newline), returning the byte ranges of the kept records
+    /// within `data`, newlines included. Nothing is copied, so this suits
+    /// input already in memory. Can't be used while `feed` has a partial
+    /// record pending.
+    pub fn feed_slice(&mut self, data: &[u8]) -> Result<Vec<Range<usize>>, Box<dyn error::Error>> {
+        if !self.pending.is_empty() {
+            return Err("feed_slice called with a partial record pending from feed".into());
+        }
+        let mut kept = vec![];
+        let mut start = 0;
+        while start < data.len() {
+            let end = data[start..].iter().position(|&b| b == b'\n').map_or(data.len(), |i| start + i + 1);
+            if self.keeps(&data[start..end])? {
+                kept.push(start..end);
+            }
+            start = end;
+        }
+        Ok(kept)
+    }
+
+    fn process(&mut self, record: &[u8], out: &mut Vec<u8>) -> Result<(), Box<dyn error::Error>> {
+        if self.keeps(record)? {
+            out.extend_from_slice(record);
+        }
+        Ok(())
+    }
+
+    fn keeps(&mut self, record: &[u8]) -> Result<bool, Box<dyn error::Error>> {
+        Ok(match self.rules.action(record)? {
+            Action::Skip => false,
+            Action::Pass => true,
+            Action::Dedupe(key) => {
+                if let Some(ref groups) = self.groups {
+                    self.seen.start_group(&groups.build(record).bytes);
+                }
+                let wanted = self.rules.keeps(self.seen.observe(&key.bytes));
+                self.seen.check_memory()?;
+                wanted
+            }
+        })
+    }
+}
+
+/// A deduplicator that many threads can use at once through a shared
+/// reference: keys are spread over independently locked sets, so threads
+/// only wait for each other when their keys land in the same set. Each thread
+/// feeds its own `Stream`; which of two rows with the same key fed by
+/// different threads at the same time counts as the first is up to chance.
+///
+/// Takes the same settings as `Deduplicator`, except that the input can't be
+/// declared sorted or grouped, as there is no single input order.
+pub struct SharedDeduplicator {
+    rules: Rules,
+    shards: Vec<Mutex<Seen>>,
+}
+
+impl SharedDeduplicator {
+    pub fn new(config: &Config) -> Result<SharedDeduplicator, Box<dyn error::Error>> {
+        if config.sorted || !config.sorted_by.is_empty() {
+            return Err("SharedDeduplicator doesn't support sorted or grouped input".into());
+        }
+        let limit = config.memory_limit.map(|limit| limit / SHARDS);
+        Ok(SharedDeduplicator {
+            rules: Rules::new(config)?,
+            shards: (0..SHARDS)
+                .map(|_| {
+                    let seen = Seen::new(false)
+                        .memory_limit(limit, config.adaptive_memory)
+                        .hash_seed(config.hash_seed);
+                    Mutex::new(seen)
+                })
+                .collect(),
+        })
+    }
+
+    /// Starts a stream of chunks for one thread to feed
+    pub fn stream(&self) -> Stream<'_> {
+        Stream { shared: self, pending: vec![] }
+    }
+
+    /// Whether to keep one complete `record`
+    pub fn keep(&self, record: &[u8]) -> Result<bool, Box<dyn error::Error>> {
+        Ok(match self.rules.action(record)? {
+            Action::Skip => false,
+            Action::Pass => true,
+            Action::Dedupe(key) => {
+                let shard = &self.shards[xxh3_64(&key.bytes) as usize % SHARDS];
+                // A panic elsewhere can't leave a key set half-updated
+                let mut seen = shard.lock().unwrap_or_else(|e| e.into_inner());
+                let wanted = self.rules.keeps(seen.observe(&key.bytes));
+                seen.check_memory()?;
+                wanted
+            }
+        })
+    }
+}
+
+/// One thread's input to a `SharedDeduplicator`, fed in chunks as for
+/// `Deduplicator`
+pub struct Stream<'a> {
+    shared: &'a SharedDeduplicator,
+    pending: Vec<u8>,
+}
+
+impl<'a> Stream<'a> {
+    /// Processes `chunk`, appending every kept record it completes to `out`
+    pub fn feed(&mut self, chunk: &[u8], out: &mut Vec<u8>) -> Result<(), Box<dyn error::Error>> {
+        let shared = self.shared;
+        split_records(&mut self.pending, chunk, |record| {
+            if shared.keep(record)? {
+                out.extend_from_slice(record);
+            }
+            Ok(())
+        })
+    }
+
+    /// Processes any final record that lacked a trailing newline
+    pub fn finish(&mut self, out: &mut Vec<u8>) -> Result<(), Box<dyn error::Error>> {
+        if !self.pending.is_empty() {
+            let record = mem::take(&mut self.pending);
+            if self.shared.keep(&record)? {
+                out.extend_from_slice(&record);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The record selection and key settings both deduplicators share
+struct Rules {
+    filters: Vec<Predicate>,
+    splitter: Regex,
+    keys: KeyBuilder,
+    no_match: NoMatch,
+    outside_partitions: NoMatch,
+    max_key_bytes: Option<usize>,
+    long_key: LongKey,
+    hash_seed: u64,
+    hash_secret: Option<Vec<u8>>,
+    always_keep: Option<HashSet<Vec<u8>>>,
+    keep: Keep,
+    max_per_key: u64,
+}
+
+/// What to do with a record before its key is looked up
+enum Action {
+    Skip,
+    /// Keep without deduplicating
+    Pass,
+    Dedupe(Key),
+}
+
+impl Rules {
+    fn new(config: &Config) -> Result<Rules, Box<dyn error::Error>> {
+        // Other policies can't decide a row until later input has been seen
+        if !config.keep.is_streamed() {
+            return Err("Deduplicator only supports keeping the first or nth row".into());
+        }
+        if config.keep_rules.is_some() {
+            return Err("Deduplicator doesn't support keep_rules".into());
+        }
+        if config.across_files_only {
+            return Err("Deduplicator doesn't support across_files_only".into());
+        }
+        if config.pad_fields.is_some() {
+            return Err("Deduplicator doesn't support pad_fields".into());
+        }
+        let always_keep = match config.always_keep_from {
+            Some(ref path) => Some(key::load_keys(path, &config.value_key_builder()?)
+                .map_err(|e| format!("{}: {}", path, e))?),
+            None => None,
+        };
+        Ok(Rules {
+            filters: config.filters.clone(),
+            splitter: config.splitter(),
+            keys: config.key_builder()?,
+            no_match: config.no_match,
+            outside_partitions: config.outside_partitions,
+            max_key_bytes: config.max_key_bytes,
+            long_key: config.long_key,
+            hash_seed: config.hash_seed,
+            hash_secret: config.hash_secret.clone(),
+            always_keep,
+            keep: config.keep.clone(),
+            max_per_key: config.max_per_key,
+        })
+    }
+
+    /// Whether to keep a key's `occurrence`th record
+    fn keeps(&self, occurrence: u64) -> bool {
+        self.keep.keeps(occurrence, self.max_per_key)
+    }
+
+    fn action(&self, record: &[u8]) -> Result<Action, Box<dyn error::Error>> {
+        if !filter::accepts(&self.filters, &self.splitter, record, None) {
+            return Ok(Action::Skip);
+        }
+        let mut key = self.keys.build(record);
+        if key.unpartitioned {
+            match self.outside_partitions {
+                NoMatch::Pass => return Ok(Action::Pass),
+                NoMatch::Skip => return Ok(Action::Skip),
+                NoMatch::Error => return Err("record is in no partition range".into()),
+            }
+        }
+        if !key.unmatched {
+            if let Some(limit) = self.max_key_bytes.filter(|&limit| key.bytes.len() > limit) {
+                match self.long_key {
+                    LongKey::Truncate => key.bytes.truncate(limit),
+                    LongKey::Hash => key.bytes = key::hash(&key.bytes, self.hash_seed, self.hash_secret.as_deref()),
+                    LongKey::Skip => return Ok(Action::Skip),
+                    LongKey::Error => return Err(format!("key is {} bytes, over --max-key-bytes {}",
+                        key.bytes.len(), limit).into()),
+                }
+            }
+            if self.always_keep.as_ref().is_some_and(|keys| keys.contains(&key.bytes)) {
+                return Ok(Action::Pass);
+            }
+            return Ok(Action::Dedupe(key));
+        }
+        match self.no_match {
+            NoMatch::Pass => Ok(Action::Pass),
+            NoMatch::Skip => Ok(Action::Skip),
+            NoMatch::Error => Err("record does not match line regex".into()),
+        }
+    }
+}
+
+/// Calls `process` on each record completed by `chunk`, joining the first to
+/// any `pending` start from earlier chunks and leaving the incomplete end of
+/// `chunk` there
+fn split_records<F>(pending: &mut Vec<u8>, chunk: &[u8], mut process: F) -> Result<(), Box<dyn error::Error>>
+    where F: FnMut(&[u8]) -> Result<(), Box<dyn error::Error>>
+{
+    let mut start = 0;
+    for end in chunk.iter().enumerate().filter(|&(_, &b)| b == b'\n').map(|(i, _)| i + 1) {
+        if pending.is_empty() {
+            process(&chunk[start..end])?;
+        }
+        else {
+            pending.extend_from_slice(&chunk[start..end]);
+            process(pending)?;
+            pending.clear();
+        }
+        start = end;
+    }
+    pending.extend_from_slice(&chunk[start..]);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::thread;
+
+    #[test]
+    fn always_keeps_listed_keys() {
+        let path = env::temp_dir().join(format!("tsvfirst-engine-keep-{}", process::id()));
+        fs::write(&path, "b\n").unwrap();
+        let config = Config::new().always_keep_from(path.to_str().unwrap());
+        let mut dedup = Deduplicator::new(&config).unwrap();
+        fs::remove_file(&path).unwrap();
+        let mut out = vec![];
+        dedup.feed(b"a\t1\nb\t2\na\t3\nb\t4\n", &mut out).unwrap();
+        assert_eq!(out, b"a\t1\nb\t2\nb\t4\n");
+    }
+
+    #[test]
+    fn refuses_unsupported_settings() {
+        assert!(Deduplicator::new(&Config::new().across_files_only(true)).is_err());
+        assert!(Deduplicator::new(&Config::new().pad_fields(3, false)).is_err());
+        assert!(Deduplicator::new(&Config::new().keep_rules("rules")).is_err());
+        assert!(SharedDeduplicator::new(&Config::new().pad_fields(3, false)).is_err());
+    }
+
+    #[test]
+    fn feed_joins_records_split_across_chunks() {
+        let mut dedup = Deduplicator::new(&Config::new()).unwrap();
+        let mut out = vec![];
+        for chunk in [&b"a\t1\nb"[..], b"\t2", b"\na\t", b"3\nc\t4\nb"] {
+            dedup.feed(chunk, &mut out).unwrap();
+        }
+        assert_eq!(out, b"a\t1\nb\t2\nc\t4\n");
+        // The final record, without its newline, is a duplicate
+        dedup.finish(&mut out).unwrap();
+        assert_eq!(out, b"a\t1\nb\t2\nc\t4\n");
+        dedup.feed(b"d\t5", &mut out).unwrap();
+        dedup.finish(&mut out).unwrap();
+        assert_eq!(out, b"a\t1\nb\t2\nc\t4\nd\t5");
+    }
+
+    #[test]
+    fn feed_slice_returns_kept_ranges() {
+        let mut dedup = Deduplicator::new(&Config::new()).unwrap();
+        let data = b"a\t1\nb\t2\na\t3\nc";
+        assert_eq!(dedup.feed_slice(data).unwrap(), vec![0..4, 4..8, 12..13]);
+        // Keys are remembered from one slice to the next
+        assert_eq!(dedup.feed_slice(b"c\nd\n").unwrap(), vec![2..4]);
+        let mut out = vec![];
+        dedup.feed(b"e", &mut out).unwrap();
+        assert!(dedup.feed_slice(b"f\n").is_err());
+    }
+
+    #[test]
+    fn shared_keeps_each_key_once_across_threads() {
+        let shared = SharedDeduplicator::new(&Config::new()).unwrap();
+        let outputs : Vec<Vec<u8>> = thread::scope(|scope| {
+            let handles : Vec<_> = (0..8).map(|t| {
+                let shared = &shared;
+                scope.spawn(move || {
+                    let mut input = vec![];
+                    for key in 0..1000 {
+                        input.extend(format!("k{}\t{}\n", (key * 7 + t * 13) % 500, t).into_bytes());
+                    }
+                    let mut stream = shared.stream();
+                    let mut out = vec![];
+                    // Uneven chunks split records mid-way
+                    for chunk in input.chunks(37) {
+                        stream.feed(chunk, &mut out).unwrap();
+                    }
+                    stream.finish(&mut out).unwrap();
+                    out
+                })
+            }).collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+        let mut keys : Vec<&[u8]> = outputs.iter()
+            .flat_map(|out| out.split(|&b| b == b'\n').filter(|row| !row.is_empty()))
+            .map(|row| row.split(|&b| b == b'\t').next().unwrap())
+            .collect();
+        keys.sort();
+        let rows = keys.len();
+        keys.dedup();
+        assert_eq!((rows, keys.len()), (500, 500));
+    }
+
+    #[test]
+    fn stream_finish_processes_split_final_record() {
+        let shared = SharedDeduplicator::new(&Config::new()).unwrap();
+        let mut first = shared.stream();
+        let mut second = shared.stream();
+        let mut out = vec![];
+        first.feed(b"a\t1\nb", &mut out).unwrap();
+        second.feed(b"b\t2\n", &mut out).unwrap();
+        first.feed(b"\t3", &mut out).unwrap();
+        first.finish(&mut out).unwrap();
+        second.feed(b"c\t", &mut out).unwrap();
+        second.feed(b"4", &mut out).unwrap();
+        second.finish(&mut out).unwrap();
+        assert_eq!(out, b"a\t1\nb\t2\nc\t4");
+    }
+}
@@ -0,0 +1,136 @@
+//! C ABI over `Deduplicator`, enabled by the `ffi` feature. See
+//! `include/tsvfirst.h` for the interface and memory-ownership rules.
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+use std::panic;
+use std::ptr;
+use std::slice;
+
+use config::Config;
+use engine::Deduplicator;
+use key::parse_field_spec;
+
+pub const TSVFIRST_SORTED: c_int = 1;
+pub const TSVFIRST_WHITESPACE: c_int = 2;
+
+/// Opaque handle owned by the caller between `tsvfirst_new` and `tsvfirst_free`
+pub struct TsvfirstDedup {
+    inner: Deduplicator,
+}
+
+/// Creates a deduplicator keyed on `fields` (a -f style spec such as "1,3i";
+/// NULL means "1"), with `flags` a combination of `TSVFIRST_*` values.
+/// Returns NULL if the spec is invalid.
+///
+/// # Safety
+///
+/// `fields` must be NULL or a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn tsvfirst_new(fields: *const c_char, flags: c_int) -> *mut TsvfirstDedup {
+    let spec = if fields.is_null() {
+        "1".to_owned()
+    }
+    else {
+        match CStr::from_ptr(fields).to_str() {
+            Ok(spec) => spec.to_owned(),
+            Err(_) => return ptr::null_mut(),
+        }
+    };
+
+    let created = panic::catch_unwind(|| {
+        let fields = parse_field_spec(&spec).ok()?;
+        let config = Config::new()
+            .fields(&fields)
+            .sorted(flags & TSVFIRST_SORTED != 0)
+            .whitespace(flags & TSVFIRST_WHITESPACE != 0);
+        Deduplicator::new(&config).ok()
+    });
+    match created {
+        Ok(Some(inner)) => Box::into_raw(Box::new(TsvfirstDedup { inner })),
+        _ => ptr::null_mut(),
+    }
+}
+
+/// Feeds `len` bytes of input. Kept records completed by this chunk are
+/// returned in a new buffer via `out`/`out_len` (NULL and 0 if none), which the
+/// caller must release with `tsvfirst_buffer_free`. Returns 0 on success and
+/// -1 on error, in which case no buffer is returned.
+///
+/// # Safety
+///
+/// `dedup` must come from `tsvfirst_new`, `data` must point to `len` readable
+/// bytes (or be NULL with `len` 0), and `out`/`out_len` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn tsvfirst_feed(dedup: *mut TsvfirstDedup, data: *const u8, len: usize,
+                                       out: *mut *mut u8, out_len: *mut usize) -> c_int {
+    if dedup.is_null() || out.is_null() || out_len.is_null() || (data.is_null() && len > 0) {
+        return -1;
+    }
+    let chunk = if len == 0 { &[][..] } else { slice::from_raw_parts(data, len) };
+    let dedup = &mut *dedup;
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        let mut kept = vec![];
+        dedup.inner.feed(chunk, &mut kept).ok().map(|_| kept)
+    }));
+    give_buffer(result.ok().and_then(|kept| kept), out, out_len)
+}
+
+/// Flushes a final record that lacked a trailing newline, returning it (if
+/// kept) exactly as `tsvfirst_feed` does. The handle may then only be freed.
+///
+/// # Safety
+///
+/// As for `tsvfirst_feed`.
+#[no_mangle]
+pub unsafe extern "C" fn tsvfirst_finish(dedup: *mut TsvfirstDedup, out: *mut *mut u8, out_len: *mut usize) -> c_int {
+    if dedup.is_null() || out.is_null() || out_len.is_null() {
+        return -1;
+    }
+    let dedup = &mut *dedup;
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        let mut kept = vec![];
+        dedup.inner.finish(&mut kept).ok().map(|_| kept)
+    }));
+    give_buffer(result.ok().and_then(|kept| kept), out, out_len)
+}
+
+/// Releases a buffer returned by `tsvfirst_feed` or `tsvfirst_finish`.
+///
+/// # Safety
+///
+/// `buf` and `len` must be exactly as returned, and each buffer freed once.
+#[no_mangle]
+pub unsafe extern "C" fn tsvfirst_buffer_free(buf: *mut u8, len: usize) {
+    if !buf.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(buf, len)));
+    }
+}
+
+/// Releases a handle from `tsvfirst_new`.
+///
+/// # Safety
+///
+/// `dedup` must be NULL or come from `tsvfirst_new`, and be freed only once.
+#[no_mangle]
+pub unsafe extern "C" fn tsvfirst_free(dedup: *mut TsvfirstDedup) {
+    if !dedup.is_null() {
+        drop(Box::from_raw(dedup));
+    }
+}
+
+/// Hands ownership of `kept` to the caller
+unsafe fn give_buffer(kept: Option<Vec<u8>>, out: *mut *mut u8, out_len: *mut usize) -> c_int {
+    *out = ptr::null_mut();
+    *out_len = 0;
+    match kept {
+        None => -1,
+        Some(ref kept) if kept.is_empty() => 0,
+        Some(kept) => {
+            let kept = kept.into_boxed_slice();
+            *out_len = kept.len();
+            *out = Box::into_raw(kept) as *mut u8;
+            0
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use std::str;
+use std::str::FromStr;
+
+use regex::bytes::Regex;
+
+use output::chomp;
+
+/// Comparison operators for `--where`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+/// What a comparison is made against
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    /// 0-indexed column number
+    Field(usize),
+    /// Number of rows with the row's key (`--having` only)
+    Count,
+}
+
+/// A condition on a row: for `--where`, to be considered at all, and for
+/// `--having`, to be written once kept
+#[derive(Debug, Clone)]
+pub enum Predicate {
+    /// `'FIELD OP VALUE'` or `'count OP VALUE'`: compares numerically if both
+    /// sides are numbers; otherwise only `==` and `!=` can hold, comparing text
+    Compare { operand: Operand, op: Op, value: String },
+    /// `--match-field FIELD:/REGEX/`
+    Match { field: usize, regex: Regex },
+}
+
+impl FromStr for Predicate {
+    type Err = String;
+
+    /// Parses a condition such as `3 > 100` or `count >= 2`
+    fn from_str(s: &str) -> Result<Predicate, String> {
+        let syntax = Regex::new(r"^\s*(\d+|count)\s*(<=|>=|==|!=|<|>)\s*(.*?)\s*$").unwrap();
+        let captures = syntax.captures(s.as_bytes())
+            .ok_or_else(|| format!("expected 'FIELD OP VALUE' with OP one of < <= > >= == !=, got '{}'", s))?;
+        let text = |i| str::from_utf8(&captures[i]).unwrap();
+        let op = match text(2) {
+            "<" => Op::Lt,
+            "<=" => Op::Le,
+            ">" => Op::Gt,
+            ">=" => Op::Ge,
+            "==" => Op::Eq,
+            _ => Op::Ne,
+        };
+        let operand = match text(1) {
+            "count" => Operand::Count,
+            field => Operand::Field(parse_field(field)?),
+        };
+        Ok(Predicate::Compare { operand, op, value: text(3).to_owned() })
+    }
+}
+
+impl Predicate {
+    /// Parses a `--match-field` condition such as `2:/^ERR/`
+    pub fn parse_match(s: &str) -> Result<Predicate, String> {
+        let (field, pattern) = s.split_once(':').ok_or("expected FIELD:/REGEX/")?;
+        let pattern = pattern.strip_prefix('/').and_then(|p| p.strip_suffix('/')).ok_or("expected FIELD:/REGEX/")?;
+        let regex = Regex::new(pattern).map_err(|e| e.to_string())?;
+        Ok(Predicate::Match { field: parse_field(field)?, regex })
+    }
+
+    /// Whether the condition refers to the key's row count
+    pub fn uses_count(&self) -> bool {
+        match *self {
+            Predicate::Compare { operand, .. } => operand == Operand::Count,
+            Predicate::Match { .. } => false,
+        }
+    }
+
+    /// Whether the row with these fields, and `count` rows with its key if
+    /// known, meets the condition. Rows lacking the field never do.
+    pub fn matches(&self, fields: &[&[u8]], count: Option<u64>) -> bool {
+        match *self {
+            Predicate::Compare { operand: Operand::Field(field), op, ref value } => match fields.get(field) {
+                Some(actual) => compare(actual, op, value),
+                None => false,
+            },
+            Predicate::Compare { operand: Operand::Count, op, ref value } => match count {
+                Some(count) => compare(count.to_string().as_bytes(), op, value),
+                None => false,
+            },
+            Predicate::Match { field, ref regex } => fields.get(field).is_some_and(|actual| regex.is_match(actual)),
+        }
+    }
+}
+
+fn compare(actual: &[u8], op: Op, value: &str) -> bool {
+    let number = |s: &[u8]| str::from_utf8(s).ok().and_then(|s| s.trim().parse::<f64>().ok());
+    match (number(actual), number(value.as_bytes())) {
+        (Some(actual), Some(value)) => match op {
+            Op::Lt => actual < value,
+            Op::Le => actual <= value,
+            Op::Gt => actual > value,
+            Op::Ge => actual >= value,
+            Op::Eq => actual == value,
+            Op::Ne => actual != value,
+        },
+        _ => match op {
+            Op::Eq => actual == value.as_bytes(),
+            Op::Ne => actual != value.as_bytes(),
+            _ => false,
+        },
+    }
+}
+
+fn parse_field(s: &str) -> Result<usize, String> {
+    match s.trim().parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n - 1),
+        _ => Err(format!("invalid field number '{}'", s)),
+    }
+}
+
+/// Whether `line`, with `count` rows with its key if known, meets all of
+/// `predicates`
+pub fn accepts(predicates: &[Predicate], splitter: &Regex, line: &[u8], count: Option<u64>) -> bool {
+    if predicates.is_empty() {
+        return true;
+    }
+    let fields : Vec<&[u8]> = splitter.split(chomp(line)).collect();
+    predicates.iter().all(|predicate| predicate.matches(&fields, count))
+}
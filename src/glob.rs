@@ -0,0 +1,109 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Whether `s` contains wildcard characters
+pub fn is_pattern(s: &str) -> bool {
+    s.contains(['*', '?'])
+}
+
+/// Matches `name` against a pattern where `*` matches any run of characters
+/// and `?` matches exactly one
+pub fn matches(pattern: &str, name: &str, ignore_case: bool) -> bool {
+    let fold = |s: &str| if ignore_case { s.to_lowercase() } else { s.to_owned() };
+    let pattern : Vec<char> = fold(pattern).chars().collect();
+    let name : Vec<char> = fold(name).chars().collect();
+
+    // Iterative matching with backtracking to the most recent '*'
+    let (mut p, mut n) = (0, 0);
+    let mut star : Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        }
+        else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        }
+        else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        }
+        else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Expands wildcards in the final component of `pattern` into the sorted list
+/// of matching paths. A pattern matching nothing is returned unchanged, so that
+/// opening it reports a sensible error.
+pub fn expand(pattern: &str, ignore_case: bool) -> io::Result<Vec<String>> {
+    let path = Path::new(pattern);
+    let file_pattern = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) if is_pattern(name) => name,
+        _ => return Ok(vec![pattern.into()]),
+    };
+    // Matches in the current directory aren't prefixed with "./"
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => Some(dir),
+        _ => None,
+    };
+
+    let mut found = vec![];
+    for entry in fs::read_dir(dir.unwrap_or_else(|| Path::new(".")))? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        if let Some(name) = entry.file_name().to_str() {
+            if matches(file_pattern, name, ignore_case) {
+                found.push(match dir {
+                    Some(dir) => dir.join(name),
+                    None => PathBuf::from(name),
+                });
+            }
+        }
+    }
+    found.sort();
+
+    if found.is_empty() {
+        return Ok(vec![pattern.into()]);
+    }
+    Ok(found.into_iter().map(|p| p.to_string_lossy().into_owned()).collect())
+}
+
+/// Lists the files under `dir` and its subdirectories whose names match
+/// `pattern` (all of them if None), in order of their paths. Hidden entries,
+/// whose names start with '.', and symbolic links to directories are skipped.
+pub fn walk(dir: &str, pattern: Option<&str>, ignore_case: bool) -> io::Result<Vec<String>> {
+    let mut found = vec![];
+    walk_into(Path::new(dir), pattern, ignore_case, &mut found)?;
+    Ok(found.into_iter().map(|p| p.to_string_lossy().into_owned()).collect())
+}
+
+fn walk_into(dir: &Path, pattern: Option<&str>, ignore_case: bool, found: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", dir.display(), e)))?
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with('.') {
+            continue;
+        }
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            walk_into(&entry.path(), pattern, ignore_case, found)?;
+        }
+        else if (file_type.is_file() || file_type.is_symlink() && entry.path().is_file())
+                && pattern.is_none_or(|pattern| matches(pattern, &name, ignore_case)) {
+            found.push(entry.path());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,29 @@
+use std::io;
+use std::io::Read;
+
+#[cfg(feature = "http")]
+use objects;
+
+/// Whether an input name is a URL (http, https, s3 or gs) rather than a filename
+pub fn is_url(name: &str) -> bool {
+    ["http://", "https://", "s3://", "gs://"].iter().any(|scheme| name.starts_with(scheme))
+}
+
+/// Fetches a URL, returning a reader streaming its response body. Fails
+/// unless the response is a success (2xx), after following redirects.
+#[cfg(feature = "http")]
+pub fn open(url: &str) -> io::Result<Box<dyn Read>> {
+    let request = if objects::is_object_url(url) { objects::request(url)? } else { ureq::get(url) };
+    match request.call() {
+        Ok(response) => Ok(Box::new(response.into_reader())),
+        Err(ureq::Error::Status(code, response)) => {
+            Err(io::Error::other(format!("HTTP {} {}", code, response.status_text())))
+        }
+        Err(ureq::Error::Transport(e)) => Err(io::Error::other(e.to_string())),
+    }
+}
+
+#[cfg(not(feature = "http"))]
+pub fn open(_url: &str) -> io::Result<Box<dyn Read>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "built without the http feature, so URLs can't be read"))
+}
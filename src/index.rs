@@ -0,0 +1,65 @@
+use std::fs;
+use std::io;
+use std::io::{BufWriter, Read, Write};
+
+use xxhash_rust::xxh3::xxh3_64;
+
+/// Identifies an index file and its format version
+const MAGIC: &[u8; 8] = b"TSVFIDX1";
+
+/// A companion index for the output: the XXH3 hash of each kept row's key and
+/// the byte offset of the row in the output. The file is the magic bytes, the
+/// entry count, then the entries sorted by hash (then offset), each as two
+/// little-endian u64s, so a key's rows can be found by binary search.
+pub struct Index {
+    out: fs::File,
+    entries: Vec<(u64, u64)>,
+}
+
+impl Index {
+    /// Creates the index file up front, so a bad path fails before any work
+    pub fn create(path: &str) -> io::Result<Index> {
+        Ok(Index { out: fs::File::create(path)?, entries: vec![] })
+    }
+
+    pub fn record(&mut self, key: &[u8], offset: u64) {
+        self.entries.push((hash(key), offset));
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.entries.sort_unstable();
+        let mut out = BufWriter::new(&mut self.out);
+        out.write_all(MAGIC)?;
+        out.write_all(&(self.entries.len() as u64).to_le_bytes())?;
+        for &(hash, offset) in &self.entries {
+            out.write_all(&hash.to_le_bytes())?;
+            out.write_all(&offset.to_le_bytes())?;
+        }
+        out.flush()
+    }
+}
+
+/// The hash of a key as stored in the index
+pub fn hash(key: &[u8]) -> u64 {
+    xxh3_64(key)
+}
+
+/// Reads the entries of an index file written by `Index`
+pub fn read(path: &str) -> io::Result<Vec<(u64, u64)>> {
+    let mut data = vec![];
+    fs::File::open(path)?.read_to_end(&mut data)?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("{}: not a tsvfirst index", path));
+    if data.len() < 16 || &data[..8] != MAGIC || (data.len() - 16) % 16 != 0 {
+        return Err(invalid());
+    }
+    let word = |at: usize| {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&data[at..at + 8]);
+        u64::from_le_bytes(bytes)
+    };
+    let count = (data.len() - 16) / 16;
+    if word(8) != count as u64 {
+        return Err(invalid());
+    }
+    Ok((0..count).map(|i| (word(16 + i * 16), word(24 + i * 16))).collect())
+}
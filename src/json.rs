@@ -0,0 +1,53 @@
+use serde_json::Value;
+
+use numeric;
+
+/// Rewrites a JSON value in a canonical form: no insignificant whitespace,
+/// object members sorted by name and numbers normalized as for numeric key
+/// fields, so that `{"b": 1.0, "a": [2e0]}` and `{"a":[2],"b":1}` are equal.
+/// Returns None if `value` is not valid JSON.
+pub fn canonicalize(value: &[u8]) -> Option<Vec<u8>> {
+    let value : Value = serde_json::from_slice(value).ok()?;
+    let mut out = vec![];
+    write(&value, &mut out);
+    Some(out)
+}
+
+fn write(value: &Value, out: &mut Vec<u8>) {
+    match *value {
+        Value::Number(ref n) => {
+            // Numbers keep their text (arbitrary_precision), so none is lost
+            let text = n.to_string();
+            match numeric::normalize(text.as_bytes(), None) {
+                Some(normalized) => out.extend_from_slice(&normalized),
+                None => out.extend_from_slice(text.as_bytes()),
+            }
+        }
+        Value::Array(ref items) => {
+            out.push(b'[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                write(item, out);
+            }
+            out.push(b']');
+        }
+        Value::Object(ref members) => {
+            let mut members : Vec<_> = members.iter().collect();
+            members.sort_by(|a, b| a.0.cmp(b.0));
+            out.push(b'{');
+            for (i, (name, member)) in members.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                out.extend_from_slice(Value::String(name.clone()).to_string().as_bytes());
+                out.push(b':');
+                write(member, out);
+            }
+            out.push(b'}');
+        }
+        // Null, booleans and strings have one compact form already
+        ref other => out.extend_from_slice(other.to_string().as_bytes()),
+    }
+}
@@ -0,0 +1,411 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs;
+use std::mem;
+use std::str;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use regex::bytes::Regex;
+
+use agg::{Aggregate, Aggregation};
+use key::KeyBuilder;
+use output::chomp;
+
+/// Which row to keep for each key
+#[derive(Debug, Clone, PartialEq)]
+pub enum Keep {
+    /// The first row seen, written as soon as it is read (unless the key's
+    /// final count is needed)
+    First,
+    /// The Nth row seen (from 1), written as soon as it is read; keys with
+    /// fewer rows are dropped entirely
+    Nth(u64),
+    /// The last row seen
+    Last,
+    /// The row that sorts first, comparing whole rows bytewise
+    Lexmin,
+    /// The row that sorts last
+    Lexmax,
+    /// A row chosen uniformly at random
+    Random,
+    /// The row whose field (0-indexed) is numerically largest; rows where it
+    /// isn't a number lose to those where it is
+    MaxBy(usize),
+    /// The row whose field is numerically smallest
+    MinBy(usize),
+}
+
+impl FromStr for Keep {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Keep, String> {
+        match s {
+            "first" => Ok(Keep::First),
+            // N comes from elsewhere (--nth)
+            "nth" => Ok(Keep::Nth(1)),
+            "last" => Ok(Keep::Last),
+            "lexmin" => Ok(Keep::Lexmin),
+            "lexmax" => Ok(Keep::Lexmax),
+            "random" => Ok(Keep::Random),
+            _ => Err(format!("unknown keep policy '{}'", s)),
+        }
+    }
+}
+
+/// How `MaxBy` and `MinBy` choose between rows whose fields are equal, tried
+/// in turn until one decides
+#[derive(Debug, Clone, PartialEq)]
+pub enum TieBreak {
+    /// Keep the earlier row
+    First,
+    /// Keep the later row
+    Last,
+    /// Keep the longer row, if they differ in length
+    Longest,
+    /// Keep the row whose field (0-indexed) is numerically larger, if they differ
+    MaxBy(usize),
+    /// Keep the row whose field is numerically smaller, if they differ
+    MinBy(usize),
+}
+
+impl FromStr for TieBreak {
+    type Err = String;
+
+    /// Parses "first", "last", "longest", "max:FIELD" or "min:FIELD", with
+    /// FIELD numbered from 1
+    fn from_str(s: &str) -> Result<TieBreak, String> {
+        let field = |n: &str| match n.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(n - 1),
+            _ => Err(format!("invalid field number '{}'", n)),
+        };
+        match s {
+            "first" => Ok(TieBreak::First),
+            "last" => Ok(TieBreak::Last),
+            "longest" => Ok(TieBreak::Longest),
+            _ if s.starts_with("max:") => field(&s[4..]).map(TieBreak::MaxBy),
+            _ if s.starts_with("min:") => field(&s[4..]).map(TieBreak::MinBy),
+            _ => Err(format!("unknown tie-break '{}'", s)),
+        }
+    }
+}
+
+impl Keep {
+    /// Whether the policy decides each row as it is read, by `keeps`
+    pub fn is_streamed(&self) -> bool {
+        matches!(*self, Keep::First | Keep::Nth(_))
+    }
+
+    /// Whether a streamed policy keeps a key's `occurrence`th row, keeping up
+    /// to `max_per_key` rows under `First`
+    pub fn keeps(&self, occurrence: u64, max_per_key: u64) -> bool {
+        match *self {
+            Keep::First => occurrence <= max_per_key,
+            Keep::Nth(n) => occurrence == n,
+            _ => false,
+        }
+    }
+
+    /// Whether `candidate` should replace `current` as the row kept for a key
+    fn prefers(&self, candidate: &Row, current: &Row, rng: &mut Rng, splitter: &Regex, tie_breaks: &[TieBreak]) -> bool {
+        let number = |row: &Row, field: usize| {
+            splitter.split(chomp(&row.line)).nth(field)
+                .and_then(|value| str::from_utf8(value).ok())
+                .and_then(|value| value.trim().parse::<f64>().ok())
+                .filter(|value| !value.is_nan())
+        };
+        // Greater if the candidate's field is the better number, which any
+        // number is over a value that isn't one
+        let by = |field: usize, smallest: bool| match (number(candidate, field), number(current, field)) {
+            (Some(candidate), Some(current)) if smallest => current.partial_cmp(&candidate).unwrap(),
+            (Some(candidate), Some(current)) => candidate.partial_cmp(&current).unwrap(),
+            (candidate, current) => candidate.is_some().cmp(&current.is_some()),
+        };
+        let decide = |order: Ordering| match order {
+            Ordering::Greater => Some(true),
+            Ordering::Less => Some(false),
+            Ordering::Equal => None,
+        };
+        // Ties keep the earlier row unless a tie-break decides otherwise
+        let extreme = |field: usize, smallest: bool| decide(by(field, smallest))
+            .or_else(|| tie_breaks.iter().filter_map(|tie_break| match *tie_break {
+                TieBreak::First => Some(false),
+                TieBreak::Last => Some(true),
+                TieBreak::Longest => decide(chomp(&candidate.line).len().cmp(&chomp(&current.line).len())),
+                TieBreak::MaxBy(field) => decide(by(field, false)),
+                TieBreak::MinBy(field) => decide(by(field, true)),
+            }).next())
+            .unwrap_or(false);
+        match *self {
+            Keep::First | Keep::Nth(_) => false,
+            Keep::Last => true,
+            Keep::Lexmin => chomp(&candidate.line) < chomp(&current.line),
+            Keep::Lexmax => chomp(&candidate.line) > chomp(&current.line),
+            // Reservoir sampling: the Nth row replaces the choice with chance 1/N
+            Keep::Random => rng.below(candidate.count) == 0,
+            Keep::MaxBy(field) => extreme(field, false),
+            Keep::MinBy(field) => extreme(field, true),
+        }
+    }
+}
+
+/// Which keys a rule applies to
+enum Pattern {
+    /// The key built from these values
+    Exact(Vec<u8>),
+    /// Keys this matches anywhere within
+    Regex(Regex),
+}
+
+/// Keep policies for keys matching patterns, read from a rules file. The first
+/// rule a key matches decides its policy; keys matching none use the default.
+pub struct KeepRules {
+    /// `None` drops every row of the key
+    rules: Vec<(Pattern, Option<Keep>)>,
+}
+
+impl KeepRules {
+    /// Reads a rules file of lines `POLICY<TAB>exact<TAB>VALUES` or
+    /// `POLICY<TAB>regex<TAB>PATTERN`, skipping blank lines and those starting
+    /// with '#'. VALUES are the key field values, joined by tabs, and are
+    /// built into a key by `keys`. POLICY is first, last, lexmin, lexmax,
+    /// random, max-by:FIELD, min-by:FIELD or skip.
+    pub fn load(path: &str, keys: &KeyBuilder) -> Result<KeepRules, String> {
+        let text = fs::read(path).map_err(|e| e.to_string())?;
+        let mut rules = vec![];
+        for (i, line) in text.split(|&b| b == b'\n').enumerate() {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if line.is_empty() || line.starts_with(b"#") {
+                continue;
+            }
+            let rule = str::from_utf8(line).map_err(|_| "invalid UTF-8".to_owned())
+                .and_then(|line| {
+                    let mut parts = line.splitn(3, '\t');
+                    let policy = parse_rule_policy(parts.next().unwrap())?;
+                    let pattern = match (parts.next(), parts.next()) {
+                        (Some("exact"), Some(values)) => Pattern::Exact(keys.build(values.as_bytes()).bytes),
+                        (Some("regex"), Some(pattern)) => Pattern::Regex(Regex::new(pattern).map_err(|e| e.to_string())?),
+                        _ => return Err("expected POLICY, 'exact' or 'regex' and a pattern, separated by tabs".to_owned()),
+                    };
+                    Ok((pattern, policy))
+                })
+                .map_err(|e| format!("line {}: {}", i + 1, e))?;
+            rules.push(rule);
+        }
+        Ok(KeepRules { rules })
+    }
+
+    /// Whether any rule matches keys by regex, which can't match hashed keys
+    pub fn has_regex(&self) -> bool {
+        self.rules.iter().any(|(pattern, _)| matches!(*pattern, Pattern::Regex(_)))
+    }
+
+    /// The policy for `key`, or `None` if its rows are all dropped
+    fn policy(&self, key: &[u8], default: &Keep) -> Option<Keep> {
+        let rule = self.rules.iter().find(|&(pattern, _)| match *pattern {
+            Pattern::Exact(ref exact) => exact.as_slice() == key,
+            Pattern::Regex(ref regex) => regex.is_match(key),
+        });
+        match rule {
+            Some((_, policy)) => policy.clone(),
+            None => Some(default.clone()),
+        }
+    }
+}
+
+/// Parses a rules file POLICY: any policy decided once the key's rows are all
+/// seen, or skip
+fn parse_rule_policy(s: &str) -> Result<Option<Keep>, String> {
+    let field = |n: &str| match n.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n - 1),
+        _ => Err(format!("invalid field number '{}'", n)),
+    };
+    match s {
+        "skip" => Ok(None),
+        "nth" => Err("nth can't be used in a rules file".to_owned()),
+        _ if s.starts_with("max-by:") => field(&s[7..]).map(|n| Some(Keep::MaxBy(n))),
+        _ if s.starts_with("min-by:") => field(&s[7..]).map(|n| Some(Keep::MinBy(n))),
+        _ => s.parse().map(Some),
+    }
+}
+
+/// A row held back until its key's winner is known
+pub struct Row {
+    pub line: Vec<u8>,
+    pub key: Vec<u8>,
+    pub source: String,
+    pub line_number: u64,
+    /// Which of its key's rows this is, set by `Best`
+    pub occurrence: u64,
+    /// Rows offered for the key so far, set by `Best`
+    pub count: u64,
+}
+
+/// Holds the best row so far for each key under a policy other than `First`.
+/// Winners are released in order of their key's first appearance: at the end
+/// of the input or, for sorted input, when the key (or group) changes.
+pub struct Best {
+    policy: Keep,
+    sorted: bool,
+    /// Position of each key's row in `rows`
+    index: HashMap<Vec<u8>, usize>,
+    rows: Vec<Row>,
+    group: Option<Vec<u8>>,
+    rng: Rng,
+    /// Overrides `policy` for some keys
+    rules: Option<KeepRules>,
+    /// Each key's policy, alongside `rows`; `None` if its rows are dropped
+    policies: Vec<Option<Keep>>,
+    /// Finds the fields compared by `MaxBy` and `MinBy`, and aggregated
+    splitter: Regex,
+    tie_breaks: Vec<TieBreak>,
+    aggregations: Vec<Aggregation>,
+    /// Each key's aggregates, alongside `rows`
+    aggregates: Vec<Vec<Box<dyn Aggregate>>>,
+    /// Rows offered whose key already had a row
+    pub duplicates: u64,
+}
+
+impl Best {
+    /// `seed` fixes the choices of `Keep::Random`; otherwise they vary per run
+    pub fn new(policy: Keep, sorted: bool, seed: Option<u64>, splitter: Regex) -> Best {
+        let rng = Rng::new(seed.unwrap_or_else(|| {
+            SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |t| t.as_nanos() as u64)
+        }));
+        Best {
+            policy, sorted, index: HashMap::new(), rows: vec![], group: None, rng, rules: None,
+            policies: vec![], splitter, tie_breaks: vec![], aggregations: vec![], aggregates: vec![], duplicates: 0,
+        }
+    }
+
+    /// Chooses the policy of each key by `rules`
+    pub fn rules(mut self, rules: KeepRules) -> Best {
+        self.rules = Some(rules);
+        self
+    }
+
+    /// Decides ties under `MaxBy` and `MinBy` by each of `tie_breaks` in turn
+    pub fn tie_breaks(mut self, tie_breaks: Vec<TieBreak>) -> Best {
+        self.tie_breaks = tie_breaks;
+        self
+    }
+
+    /// Appends a column to each winner combining its key's values of a field
+    pub fn aggregating(mut self, aggregations: Vec<Aggregation>) -> Best {
+        self.aggregations = aggregations;
+        self
+    }
+
+    /// Declares the group of the next row. Returns the previous group's
+    /// winners if it has changed.
+    pub fn start_group(&mut self, group: &[u8]) -> Vec<Row> {
+        if self.group.as_ref().is_some_and(|g| g.as_slice() == group) {
+            return vec![];
+        }
+        self.group = Some(group.to_owned());
+        self.finish()
+    }
+
+    /// Considers `row` for its key, returning any winners now final
+    pub fn offer(&mut self, mut row: Row) -> Vec<Row> {
+        // Winners may be written in a different order to the input
+        if !row.line.ends_with(b"\n") {
+            row.line.push(b'\n');
+        }
+
+        let released = if self.sorted && self.rows.last().is_some_and(|last| last.key != row.key) {
+            self.finish()
+        }
+        else {
+            vec![]
+        };
+
+        if !self.aggregations.is_empty() {
+            let fields : Vec<&[u8]> = self.splitter.split(chomp(&row.line)).collect();
+            let aggregates = match self.index.get(&row.key) {
+                Some(&i) => &mut self.aggregates[i],
+                None => {
+                    self.aggregates.push(self.aggregations.iter().map(|a| a.start()).collect());
+                    self.aggregates.last_mut().unwrap()
+                }
+            };
+            for (aggregation, aggregate) in self.aggregations.iter().zip(aggregates) {
+                // Rows missing the field don't count towards it
+                if let Some(value) = fields.get(aggregation.field) {
+                    aggregate.add(value);
+                }
+            }
+        }
+
+        match self.index.get(&row.key) {
+            Some(&i) => {
+                row.count = self.rows[i].count + 1;
+                row.occurrence = row.count;
+                self.duplicates += 1;
+                let prefers = match self.policies[i] {
+                    Some(ref policy) => policy.prefers(&row, &self.rows[i], &mut self.rng, &self.splitter, &self.tie_breaks),
+                    None => false,
+                };
+                if prefers {
+                    self.rows[i] = row;
+                }
+                else {
+                    self.rows[i].count = row.count;
+                }
+            }
+            None => {
+                row.count = 1;
+                row.occurrence = 1;
+                self.policies.push(match self.rules {
+                    Some(ref rules) => rules.policy(&row.key, &self.policy),
+                    None => Some(self.policy.clone()),
+                });
+                self.index.insert(row.key.clone(), self.rows.len());
+                self.rows.push(row);
+            }
+        }
+        released
+    }
+
+    /// Returns all remaining winners
+    pub fn finish(&mut self) -> Vec<Row> {
+        self.index.clear();
+        let mut rows = mem::take(&mut self.rows);
+        for (row, aggregates) in rows.iter_mut().zip(self.aggregates.drain(..)) {
+            // The aggregates go before the line ending
+            let ending = row.line.split_off(chomp(&row.line).len());
+            for aggregate in aggregates {
+                row.line.push(b'\t');
+                row.line.extend(aggregate.result());
+            }
+            row.line.extend(ending);
+        }
+        let policies = mem::take(&mut self.policies);
+        rows.into_iter().zip(policies).filter(|(_, policy)| policy.is_some()).map(|(row, _)| row).collect()
+    }
+}
+
+/// A small, fast generator (splitmix64), plenty for sampling rows
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`, for `n` > 0
+    fn below(&mut self, n: u64) -> u64 {
+        // The bias for n far below 2^64 is negligible
+        self.next() % n
+    }
+}
@@ -0,0 +1,469 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::error;
+use std::fs;
+use std::io;
+use std::str;
+use std::str::FromStr;
+
+use hmac::{Hmac, Mac};
+use regex::bytes::Regex;
+use sha2::Sha256;
+use xxhash_rust::xxh3::xxh3_64_with_seed;
+
+use json;
+use numeric;
+use output::chomp;
+use partition::Partitions;
+
+/// A field that forms part of the key, with its per-field modifiers
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyField {
+    /// 0-indexed column number
+    pub index: usize,
+    /// Compare case-insensitively (spec suffix 'i')
+    pub ignore_case: bool,
+    /// Compare as a decimal number (spec suffix 'n')
+    pub numeric: bool,
+    /// Compare as canonicalized JSON (spec suffix 'j')
+    pub json: bool,
+}
+
+impl KeyField {
+    pub fn new(index: usize) -> KeyField {
+        KeyField { index, ignore_case: false, numeric: false, json: false }
+    }
+}
+
+/// What to do with rows that `--line-regex` doesn't match, or whose value is
+/// outside every --partition-by range
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoMatch {
+    /// Emit the row without deduplicating it
+    Pass,
+    /// Drop the row
+    Skip,
+    /// Abort the run
+    Error,
+}
+
+impl FromStr for NoMatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<NoMatch, String> {
+        match s {
+            "pass" => Ok(NoMatch::Pass),
+            "skip" => Ok(NoMatch::Skip),
+            "error" => Ok(NoMatch::Error),
+            _ => Err(format!("unknown no-match policy '{}'", s)),
+        }
+    }
+}
+
+/// What to do with keys longer than --max-key-bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LongKey {
+    /// Keep only the first bytes, so keys differing after them are duplicates
+    Truncate,
+    /// Replace the key with its hash, as --key-xxhash does for every key
+    Hash,
+    /// Drop the row
+    Skip,
+    /// Abort the run
+    Error,
+}
+
+impl FromStr for LongKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LongKey, String> {
+        match s {
+            "truncate" => Ok(LongKey::Truncate),
+            "hash" => Ok(LongKey::Hash),
+            "skip" => Ok(LongKey::Skip),
+            "error" => Ok(LongKey::Error),
+            _ => Err(format!("unknown long-key policy '{}'", s)),
+        }
+    }
+}
+
+/// The key extracted from a row
+pub struct Key {
+    /// Normalized key fields joined by tabs, or their hash in hex if hashing
+    pub bytes: Vec<u8>,
+    /// 0-indexed number of the first key field absent from the row
+    pub missing_field: Option<usize>,
+    /// The row didn't match the line regex, so has no key
+    pub unmatched: bool,
+    /// The row's --partition-by value is in no range, so no label was added
+    pub unpartitioned: bool,
+}
+
+/// Extracts keys from rows. `fields` must be sorted by index.
+pub struct KeyBuilder {
+    fields: Vec<KeyField>,
+    splitter: Regex,
+    /// Set when the key is exactly the first N tab-separated fields, unmodified,
+    /// so it can be copied straight from the start of the row
+    prefix_fields: Option<usize>,
+    /// Take the key from this regex's capture groups instead of fields
+    line_regex: Option<Regex>,
+    /// Canonical forms for key values
+    aliases: HashMap<Vec<u8>, Vec<u8>>,
+    /// Decimal places numeric fields are rounded to
+    numeric_precision: Option<u32>,
+    /// Replace keys with their 64-bit XXH3 hash
+    hashed: bool,
+    hash_seed: u64,
+    hash_secret: Option<Vec<u8>>,
+    /// Find tab-separated fields allowing for double-quoted values
+    quoted: bool,
+    /// Add the label of the range a field's value falls in
+    partitions: Option<Partitions>,
+}
+
+impl KeyBuilder {
+    pub fn new(fields: &[KeyField], splitter: Regex) -> KeyBuilder {
+        let is_prefix = splitter.as_str() == r"\t" && fields.iter().enumerate()
+            .all(|(i, f)| f.index == i && *f == KeyField::new(i));
+        KeyBuilder {
+            fields: fields.to_owned(),
+            splitter,
+            prefix_fields: if is_prefix { Some(fields.len()) } else { None },
+            line_regex: None,
+            aliases: HashMap::new(),
+            numeric_precision: None,
+            hashed: false,
+            hash_seed: 0,
+            hash_secret: None,
+            quoted: false,
+            partitions: None,
+        }
+    }
+
+    /// Replace each key with its 64-bit XXH3 hash, written as 16 hex digits.
+    /// Keys are then fixed-size, at the cost of a tiny chance that two
+    /// different keys collide and are treated as duplicates.
+    pub fn hashed(mut self, yes: bool) -> KeyBuilder {
+        self.hashed = yes;
+        self
+    }
+
+    /// Salts the hash of `hashed` keys, so that inputs can't be crafted to
+    /// collide without knowing the seed
+    pub fn hash_seed(mut self, seed: u64) -> KeyBuilder {
+        self.hash_seed = seed;
+        self
+    }
+
+    /// Hashes `hashed` keys with HMAC-SHA256 under `secret` instead, so the
+    /// hashes can't be linked to the same keys elsewhere without it
+    pub fn hash_secret(mut self, secret: &[u8]) -> KeyBuilder {
+        self.hash_secret = Some(secret.to_owned());
+        self
+    }
+
+    /// Parse double quotes when finding tab-separated fields, so a quoted
+    /// value may contain tabs, and key on the unquoted values. `""` inside
+    /// quotes stands for one quote.
+    pub fn quoted(mut self, yes: bool) -> KeyBuilder {
+        if yes {
+            self.prefix_fields = None;
+        }
+        self.quoted = yes;
+        self
+    }
+
+    /// Round numeric ('n') fields to `places` decimal places before comparing
+    pub fn numeric_precision(mut self, places: u32) -> KeyBuilder {
+        self.numeric_precision = Some(places);
+        self
+    }
+
+    /// Rewrite key values found in `aliases` to their canonical form before
+    /// any field modifiers are applied
+    pub fn aliases(mut self, aliases: HashMap<Vec<u8>, Vec<u8>>) -> KeyBuilder {
+        if !aliases.is_empty() {
+            self.prefix_fields = None;
+        }
+        self.aliases = aliases;
+        self
+    }
+
+    /// Add the label of the `partitions` range holding each row's value of
+    /// their field to its key, after a tab
+    pub fn partitions(mut self, partitions: Partitions) -> KeyBuilder {
+        self.partitions = Some(partitions);
+        self
+    }
+
+    fn canonical<'a>(&'a self, value: &'a [u8]) -> &'a [u8] {
+        if self.aliases.is_empty() {
+            return value;
+        }
+        self.aliases.get(value).map_or(value, |canonical| canonical.as_slice())
+    }
+
+    /// Key rows on the capture groups of `regex` applied to the whole line
+    /// (or the whole match, if it has no groups), joined by tabs
+    pub fn line_regex(mut self, regex: Regex) -> KeyBuilder {
+        self.line_regex = Some(regex);
+        self
+    }
+
+    /// Whether every key field ends within `prefix`, i.e. a key built from the
+    /// prefix would be the same as one built from the whole row
+    pub fn complete_in(&self, prefix: &[u8]) -> bool {
+        let last = self.fields.last().map_or(0, |f| f.index)
+            .max(self.partitions.as_ref().map_or(0, |partitions| partitions.field));
+        self.splitter.splitn(prefix, last + 2).count() == last + 2
+    }
+
+    pub fn build(&self, line: &[u8]) -> Key {
+        let mut key = self.build_unhashed(line);
+        if let Some(partitions) = self.partitions.as_ref().filter(|_| !key.unmatched) {
+            let value = if self.quoted {
+                split_quoted(chomp(line)).into_iter().nth(partitions.field).map(|value| value.into_owned())
+            }
+            else {
+                self.splitter.split(chomp(line)).nth(partitions.field).map(|value| value.to_owned())
+            };
+            match value.as_ref().and_then(|value| partitions.label(value)) {
+                Some(label) => {
+                    key.bytes.push(b'\t');
+                    key.bytes.extend_from_slice(label.as_bytes());
+                }
+                None => key.unpartitioned = true,
+            }
+        }
+        if self.hashed && !key.unmatched {
+            key.bytes = hash(&key.bytes, self.hash_seed, self.hash_secret.as_deref());
+        }
+        key
+    }
+
+    fn build_unhashed(&self, line: &[u8]) -> Key {
+        if let Some(ref regex) = self.line_regex {
+            return self.build_from_regex(regex, chomp(line));
+        }
+        if let Some(count) = self.prefix_fields {
+            return build_prefix(chomp(line), count);
+        }
+
+        if self.quoted {
+            let columns = split_quoted(chomp(line));
+            return self.build_from_columns(columns.iter().map(|column| column.as_ref()));
+        }
+        self.build_from_columns(self.splitter.split(chomp(line)))
+    }
+
+    fn build_from_columns<'a, I: Iterator<Item = &'a [u8]>>(&'a self, mut columns: I) -> Key {
+        let mut key = Key { bytes: vec![], missing_field: None, unmatched: false, unpartitioned: false };
+        let mut last_idx = 0;
+
+        for (i, field) in self.fields.iter().enumerate() {
+            match columns.nth(field.index - last_idx) {
+                Some(column) => {
+                    if i > 0 {
+                        key.bytes.push(b'\t');
+                    }
+                    let column = self.canonical(column);
+                    let canonical_json = if field.json { json::canonicalize(column) } else { None };
+                    let column = canonical_json.as_ref().map_or(column, |json| json.as_slice());
+                    let normalized = if field.numeric {
+                        numeric::normalize(column, self.numeric_precision)
+                    }
+                    else {
+                        None
+                    };
+                    let column = normalized.as_ref().map_or(column, |n| n.as_slice());
+                    if field.ignore_case {
+                        push_lowercase(&mut key.bytes, column);
+                    }
+                    else {
+                        key.bytes.extend_from_slice(column);
+                    }
+                    last_idx = field.index + 1;
+                }
+                None => {
+                    key.missing_field = Some(field.index);
+                    break;
+                }
+            }
+        }
+        key
+    }
+
+    fn build_from_regex(&self, regex: &Regex, row: &[u8]) -> Key {
+        let mut key = Key { bytes: vec![], missing_field: None, unmatched: false, unpartitioned: false };
+        match regex.captures(row) {
+            None => key.unmatched = true,
+            Some(captures) if captures.len() == 1 => key.bytes.extend_from_slice(self.canonical(&captures[0])),
+            Some(captures) => {
+                for (i, group) in captures.iter().skip(1).enumerate() {
+                    if i > 0 {
+                        key.bytes.push(b'\t');
+                    }
+                    if let Some(group) = group {
+                        key.bytes.extend_from_slice(self.canonical(group.as_bytes()));
+                    }
+                }
+            }
+        }
+        key
+    }
+}
+
+/// Splits `row` on tabs outside double quotes, unquoting quoted values
+fn split_quoted(row: &[u8]) -> Vec<Cow<'_, [u8]>> {
+    let mut columns = vec![];
+    let mut rest = row;
+    loop {
+        if !rest.starts_with(b"\"") {
+            let end = rest.iter().position(|&b| b == b'\t').unwrap_or(rest.len());
+            columns.push(Cow::Borrowed(&rest[..end]));
+            if end == rest.len() {
+                return columns;
+            }
+            rest = &rest[end + 1..];
+            continue;
+        }
+
+        let mut value = vec![];
+        let mut i = 1;
+        // Inside the quotes, then any stray bytes after the closing quote
+        let mut quoted = true;
+        while i < rest.len() {
+            match rest[i] {
+                b'"' if quoted && rest.get(i + 1) == Some(&b'"') => {
+                    value.push(b'"');
+                    i += 1;
+                }
+                b'"' if quoted => quoted = false,
+                b'\t' if !quoted => break,
+                b => value.push(b),
+            }
+            i += 1;
+        }
+        columns.push(Cow::Owned(value));
+        if i >= rest.len() {
+            return columns;
+        }
+        rest = &rest[i + 1..];
+    }
+}
+
+/// A key's 64-bit XXH3 hash salted with `seed`, as 16 hex digits. Given a
+/// `secret`, the first 64 bits of its HMAC-SHA256 under it instead.
+pub fn hash(key: &[u8], seed: u64, secret: Option<&[u8]>) -> Vec<u8> {
+    let hash = match secret {
+        Some(secret) => {
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+            mac.update(key);
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&mac.finalize().into_bytes()[..8]);
+            u64::from_be_bytes(bytes)
+        }
+        None => xxh3_64_with_seed(key, seed),
+    };
+    format!("{:016x}", hash).into_bytes()
+}
+
+/// Builds a key from the first `count` tab-separated fields of `row`
+fn build_prefix(row: &[u8], count: usize) -> Key {
+    let mut tabs = row.iter().enumerate().filter(|&(_, &b)| b == b'\t').map(|(i, _)| i);
+    match tabs.nth(count - 1) {
+        Some(end) => Key { bytes: row[..end].to_owned(), missing_field: None, unmatched: false, unpartitioned: false },
+        None => {
+            let present = row.iter().filter(|&&b| b == b'\t').count() + 1;
+            Key {
+                bytes: row.to_owned(),
+                missing_field: if present < count { Some(present) } else { None },
+                unmatched: false,
+                unpartitioned: false,
+            }
+        }
+    }
+}
+
+/// Parses a field spec such as '1,3i' into key fields sorted by index
+pub fn parse_field_spec(arg: &str) -> Result<Vec<KeyField>, Box<dyn error::Error>> {
+    let mut fields : Vec<KeyField> = vec![];
+    for field in arg.split(',') {
+        // Trailing letters are per-field modifiers
+        let digits = field.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+        let index = digits.parse::<usize>()?;
+        if index == 0 {
+            return Err("output field is 1-indexed; 0 is not valid".into());
+        }
+        // Convert to 0-indexed
+        let mut key_field = KeyField::new(index - 1);
+        for modifier in field[digits.len()..].chars() {
+            match modifier {
+                'i' => key_field.ignore_case = true,
+                'n' => key_field.numeric = true,
+                'j' => key_field.json = true,
+                _ => return Err(format!("unknown modifier '{}' for field {}", modifier, index).into()),
+            }
+        }
+        fields.push(key_field);
+    }
+
+    if fields.is_empty() {
+        return Err("no fields specified".into());
+    }
+    normalize_fields(fields)
+}
+
+/// Sorts fields by index and removes duplicates
+pub fn normalize_fields(mut fields: Vec<KeyField>) -> Result<Vec<KeyField>, Box<dyn error::Error>> {
+    fields.sort_by_key(|f| f.index);
+    fields.dedup();
+    for pair in fields.windows(2) {
+        if pair[0].index == pair[1].index {
+            return Err(format!("field {} given twice with different modifiers", pair[0].index + 1).into());
+        }
+    }
+    Ok(fields)
+}
+
+/// Loads a two-column, tab-separated file mapping key values to canonical forms
+pub fn load_aliases(path: &str) -> io::Result<HashMap<Vec<u8>, Vec<u8>>> {
+    let mut aliases = HashMap::new();
+    for (i, line) in fs::read(path)?.split(|&b| b == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            continue;
+        }
+        let mut columns = line.splitn(2, |&b| b == b'\t');
+        match (columns.next(), columns.next()) {
+            (Some(alias), Some(canonical)) => {
+                aliases.insert(alias.to_owned(), canonical.to_owned());
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("{}:{}: expected two tab-separated columns", path, i + 1))),
+        }
+    }
+    Ok(aliases)
+}
+
+/// Loads a file of key values, one per line, as keys built by `keys` (see
+/// `Config::value_key_builder`)
+pub fn load_keys(path: &str, keys: &KeyBuilder) -> io::Result<HashSet<Vec<u8>>> {
+    let mut set = HashSet::new();
+    for line in fs::read(path)?.split(|&b| b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if !line.is_empty() {
+            set.insert(keys.build(line).bytes);
+        }
+    }
+    Ok(set)
+}
+
+/// Lowercases UTF-8 text fully; invalid UTF-8 is lowercased as ASCII only
+fn push_lowercase(out: &mut Vec<u8>, value: &[u8]) {
+    match str::from_utf8(value) {
+        Ok(text) => out.extend_from_slice(text.to_lowercase().as_bytes()),
+        Err(_) => out.extend(value.iter().map(|b| b.to_ascii_lowercase())),
+    }
+}
@@ -0,0 +1,78 @@
+//! Print the first row only for a given key.
+//!
+//! The command-line tool is a thin wrapper around `run`. `run_from` reads
+//! readers supplied as `config::Input`s instead of named inputs, and
+//! `run_with` writes rows through a custom `output::RowFormat`. `Deduplicator`
+//! offers the same keep-first decisions as an incremental byte-stream filter,
+//! and `SharedDeduplicator` as one that many threads can feed at once.
+
+extern crate bzip2;
+// Generated binding code names ::core, which needs declaring in a 2015 crate
+#[cfg(any(feature = "python", feature = "wasm"))]
+extern crate core;
+extern crate flate2;
+extern crate hmac;
+#[cfg(feature = "python")]
+extern crate pyo3;
+extern crate regex;
+extern crate serde_json;
+extern crate sha2;
+#[cfg(feature = "http")]
+extern crate ureq;
+#[cfg(feature = "wasm")]
+extern crate wasm_bindgen;
+extern crate xxhash_rust;
+extern crate xz2;
+extern crate zstd;
+
+mod advise;
+pub mod agg;
+mod atomic;
+mod clusters;
+pub mod compress;
+pub mod config;
+pub mod diagnostics;
+mod engine;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod filter;
+mod glob;
+pub mod http;
+mod index;
+mod json;
+pub mod keep;
+pub mod key;
+mod lines;
+mod lookup;
+pub mod mask;
+mod numeric;
+#[cfg(feature = "http")]
+mod objects;
+pub mod output;
+pub mod partition;
+mod pipes;
+mod profile;
+mod progress;
+#[cfg(feature = "python")]
+mod python;
+mod seen;
+mod state;
+mod stats;
+mod summary;
+pub mod template;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
+mod trace;
+mod tsvfirst;
+pub mod utf8;
+mod verify;
+mod warnings;
+#[cfg(feature = "wasm")]
+mod wasm;
+
+pub use advise::advise;
+pub use config::Config;
+pub use engine::{Deduplicator, SharedDeduplicator, Stream};
+pub use lookup::lookup;
+pub use tsvfirst::{run, run_from, run_with};
+pub use verify::verify;
@@ -0,0 +1,100 @@
+use std::io;
+use std::io::{BufRead, Write};
+
+use regex::bytes::Regex;
+
+use output::chomp;
+
+/// Like `read_until(b'\n')`, but stops once `buf` holds `limit` bytes.
+/// Returns the number of bytes read.
+pub fn read_line_limited(reader: &mut dyn BufRead, buf: &mut Vec<u8>, limit: usize) -> io::Result<usize> {
+    let mut read = 0;
+    while buf.len() < limit {
+        let (done, used) = {
+            let available = match reader.fill_buf() {
+                Ok(available) => available,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if available.is_empty() {
+                break;
+            }
+            let wanted = available.len().min(limit - buf.len());
+            match available[..wanted].iter().position(|&b| b == b'\n') {
+                Some(i) => {
+                    buf.extend_from_slice(&available[..=i]);
+                    (true, i + 1)
+                }
+                None => {
+                    buf.extend_from_slice(&available[..wanted]);
+                    (false, wanted)
+                }
+            }
+        };
+        reader.consume(used);
+        read += used;
+        if done {
+            break;
+        }
+    }
+    Ok(read)
+}
+
+/// Copies the remainder of the current line (up to and including its newline)
+/// to `out`, or discards it if `out` is None. Returns the number of bytes read
+/// and whether the line ended with a newline (rather than end of input).
+pub fn copy_rest_of_line(reader: &mut dyn BufRead, mut out: Option<&mut dyn Write>) -> io::Result<(usize, bool)> {
+    let mut read = 0;
+    let mut terminated = false;
+    loop {
+        let (done, used) = {
+            let available = match reader.fill_buf() {
+                Ok(available) => available,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if available.is_empty() {
+                break;
+            }
+            let (done, used) = match available.iter().position(|&b| b == b'\n') {
+                Some(i) => (true, i + 1),
+                None => (false, available.len()),
+            };
+            if let Some(ref mut out) = out {
+                out.write_all(&available[..used])?;
+            }
+            (done, used)
+        };
+        reader.consume(used);
+        read += used;
+        if done {
+            terminated = true;
+            break;
+        }
+    }
+    Ok((read, terminated))
+}
+
+/// Pads `line` with empty fields, each after `separator`, until it has `count`
+/// fields as split by `splitter`. With `truncate`, longer lines are cut back to
+/// `count` fields. The line ending, if any, is kept.
+pub fn fit_fields(line: &mut Vec<u8>, splitter: &Regex, count: usize, truncate: bool, separator: &[u8]) {
+    let ending = line.split_off(chomp(line).len());
+    let mut fields = 1;
+    let mut cut = None;
+    for delimiter in splitter.find_iter(line) {
+        if fields == count {
+            cut = Some(delimiter.start());
+            break;
+        }
+        fields += 1;
+    }
+    match cut {
+        Some(at) if truncate => line.truncate(at),
+        Some(_) => {}
+        None => for _ in fields..count {
+            line.extend_from_slice(separator);
+        },
+    }
+    line.extend(ending);
+}
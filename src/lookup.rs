@@ -0,0 +1,32 @@
+use std::error;
+use std::fs;
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+
+use config::Config;
+use index;
+
+/// Writes the rows of `data`, an output written with `--index index_path`,
+/// whose key is `value` (the key field values joined by tabs). Returns how
+/// many rows were found.
+///
+/// `config` must have the key settings used to write the output (fields and
+/// their modifiers, --numeric-precision, --alias-map and --key-xxhash), so
+/// that `value` is normalized the same way. The index is searched by hash.
+pub fn lookup(config: &Config, index_path: &str, data: &str, value: &[u8], out: &mut dyn Write)
+              -> Result<usize, Box<dyn error::Error>> {
+    let hash = index::hash(&config.value_key_builder()?.build(value).bytes);
+
+    let entries = index::read(index_path)?;
+    let start = entries.partition_point(|&(entry, _)| entry < hash);
+    let mut reader = BufReader::new(fs::File::open(data)?);
+    let mut found = 0;
+    let mut row = vec![];
+    for &(_, offset) in entries[start..].iter().take_while(|&&(entry, _)| entry == hash) {
+        reader.seek(SeekFrom::Start(offset))?;
+        row.clear();
+        reader.read_until(b'\n', &mut row)?;
+        out.write_all(&row)?;
+        found += 1;
+    }
+    Ok(found)
+}
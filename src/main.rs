@@ -1,24 +1,129 @@
 #[macro_use]
 extern crate clap;
+extern crate tsvfirst;
 
+use std::env;
 use std::error;
+use std::fs;
 use std::io;
-use clap::Arg;
+use std::mem;
+use std::path::Path;
+use clap::{Arg, ArgMatches, SubCommand};
 
-mod config;
-mod tsvfirst;
+use tsvfirst::compress::{Compression, Format};
+use tsvfirst::config::{Config, CountDistinct, Rotate};
+use tsvfirst::diagnostics::ErrorFormat;
+use tsvfirst::filter::Predicate;
+use tsvfirst::http::is_url;
+use tsvfirst::keep::{Keep, TieBreak};
+use tsvfirst::key::{normalize_fields, parse_field_spec, KeyField, LongKey, NoMatch};
+use tsvfirst::mask::FieldMask;
+use tsvfirst::output::{GroupSeparator, OutputFormat, SqlDialect};
+use tsvfirst::partition::Partitions;
+use tsvfirst::template::Template;
+use tsvfirst::utf8::Utf8Mode;
 
-use config::Config;
+type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
 
-type Result<T> = std::result::Result<T, Box<error::Error>>;
+/// What to do once the command line is parsed
+enum Command {
+    Run(Config),
+    /// Run on each input separately, replacing it with the output
+    InPlace { config: Config, backup_suffix: Option<String> },
+    Verify { config: Config, output: String },
+    Advise(Config),
+    Lookup { config: Config, index: String, data: String, key: String },
+}
 
 fn main() -> Result<()> {
-    let config = get_config();
-    let mut out = io::stdout();
-    tsvfirst::run(&config, &mut out)
+    match get_command() {
+        Command::Run(config) => {
+            let mut output = config.get_output()?;
+            tsvfirst::run(&config, &mut output)?;
+            Ok(output.commit()?)
+        }
+        Command::InPlace { config, backup_suffix } => rewrite_in_place(config, backup_suffix.as_deref()),
+        Command::Advise(config) => {
+            let mut output = config.get_output()?;
+            tsvfirst::advise(&config, &mut output)?;
+            Ok(output.commit()?)
+        }
+        Command::Verify { config, output } => {
+            if let Some(difference) = tsvfirst::verify(&config, &output)? {
+                eprintln!("tsvfirst: {}", difference);
+                ::std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::Lookup { config, index, data, key } => {
+            if tsvfirst::lookup(&config, &index, &data, unescape(&key).as_bytes(), &mut io::stdout())? == 0 {
+                eprintln!("tsvfirst: key not found");
+                ::std::process::exit(1);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Replaces each of the config's inputs with its own deduplicated rows,
+/// first keeping a copy with `backup_suffix` added to its name if given
+fn rewrite_in_place(mut config: Config, backup_suffix: Option<&str>) -> Result<()> {
+    // Checked for every file before any is replaced
+    let paths = mem::take(&mut config.inputs);
+    let compressions = match config.compress {
+        Some(compression) => vec![Some(compression); paths.len()],
+        None => paths.iter().map(|path| in_place_compression(path)).collect::<Result<_>>()?,
+    };
+    for (path, compression) in paths.into_iter().zip(compressions) {
+        config.inputs = vec![path.clone()];
+        config.output = Some(path.clone());
+        config.compress = compression;
+        let mut output = config.get_output()?;
+        tsvfirst::run(&config, &mut output)?;
+        if let Some(suffix) = backup_suffix {
+            back_up(&path, suffix)?;
+        }
+        output.commit()?;
+    }
+    Ok(())
+}
+
+/// How to compress the file at `path` when replacing it, to match how it is
+/// compressed now. Fails for formats that can't be written, and for
+/// uncompressed files named as if compressed, which would otherwise be
+/// compressed to match their name.
+fn in_place_compression(path: &str) -> Result<Option<Compression>> {
+    match Format::of_file(path).map_err(|e| format!("{}: {}", path, e))? {
+        Some(format) => match format.compression() {
+            Some(compression) => Ok(Some(compression)),
+            None => Err(format!("{}: can't rewrite {} files in place", path, format.name()).into()),
+        },
+        None if Compression::for_path(path).is_some() => {
+            Err(format!("{}: isn't compressed, so can't be rewritten in place under its name", path).into())
+        }
+        None => Ok(None),
+    }
 }
 
-fn get_config() -> Config {
+/// Keeps a copy of `path` with `suffix` added to its name, replacing any
+/// earlier backup. A hard link is tried first, as it costs nothing.
+fn back_up(path: &str, suffix: &str) -> Result<()> {
+    back_up_with(path, suffix, |from, to| fs::hard_link(from, to))
+}
+
+/// As for `back_up`, trying `link` before copying
+fn back_up_with<F>(path: &str, suffix: &str, link: F) -> Result<()>
+where F: Fn(&str, &str) -> io::Result<()> {
+    let backup = format!("{}{}", path, suffix);
+    let result = match fs::remove_file(&backup) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => link(path, &backup).or_else(|_| fs::copy(path, &backup).map(|_| ())),
+    };
+    result.map_err(|e| format!("{}: {}", backup, e).into())
+}
+
+/// Parses the command line
+fn get_command() -> Command {
     let args = app_from_crate!()
         .usage("tsvfirst [-f 1,2] [-s] [-w] <file or stdin>")
         .arg(Arg::with_name("fields")
@@ -31,12 +136,349 @@ fn get_config() -> Config {
             .long_help(
 "One or more columns to use when determining the uniqueness of a row. Columns
 are specified by their number, starting from column 1. Multiple columns should
-be joined with a comma."))
+be joined with a comma.
+
+A column number may be followed by modifiers that change how it is compared:
+  i  compare case-insensitively
+  n  compare as a decimal number, so '1e3', '1000' and '+1000.0' are equal
+     (see --numeric-precision); values that aren't numbers compare as text
+  j  compare as JSON, ignoring whitespace, object member order and number
+     formatting (see --json-field); values that aren't JSON compare as text
+e.g. '2i,3' matches column 2 ignoring case and column 3 exactly."))
+
+        .arg(Arg::with_name("json-field")
+            .long("json-field")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("FIELD")
+            .help("Compare key field FIELD as JSON (the 'j' field modifier)")
+            .long_help(
+"Parse key field FIELD as JSON and compare it in a canonical form, with object
+members sorted by name, no insignificant whitespace and numbers normalized as
+for the 'n' modifier, so blobs that differ only in member order or formatting
+are the same key. Equivalent to adding 'j' to FIELD in -f. May be repeated."))
+
+        .arg(Arg::with_name("numeric-precision")
+            .long("numeric-precision")
+            .takes_value(true)
+            .value_name("PLACES")
+            .help("Round numeric ('n') key fields to PLACES decimal places")
+            .long_help(
+"Round the values of numeric key fields (those with the 'n' modifier) half away
+from zero to PLACES decimal places before comparing them, so that e.g. with
+--numeric-precision 2, '1.004' and '1.0' are the same key."))
+
+        .arg(Arg::with_name("key-first-n-fields")
+            .long("key-first-n-fields")
+            .takes_value(true)
+            .value_name("N")
+            .conflicts_with("fields")
+            .help("Use the first N fields as the key (shorthand for -f 1,...,N)")
+            .long_help(
+"Use the first N fields as the key and treat the rest of the row as payload.
+Equivalent to -f 1,2,...,N, but rows may have any number of trailing fields
+and, for tab-separated input, the key is taken directly from the start of the
+row without splitting the remainder."))
+
+        .arg(Arg::with_name("key-xxhash")
+            .long("key-xxhash")
+            .help("Compare rows by a 64-bit hash of their key instead of the key itself")
+            .long_help(
+"Replace each key with its 64-bit XXH3 hash before deduplicating. Memory use
+per distinct key is then fixed however long the key fields are, and keys need
+not be kept in memory in the clear. Two different keys have a tiny (about 1 in
+10^19 per pair) chance of colliding, in which case the later row is dropped.
+
+The hash is salted with a random seed chosen for each run, so input can't be
+crafted to collide, unless the hashes are written out (with --emit-key-hash or
+--index), when it is 0 so that runs agree. See --hash-seed."))
+
+        .arg(Arg::with_name("hash-seed")
+            .long("hash-seed")
+            .takes_value(true)
+            .value_name("N")
+            .help("Salt key hashes with N rather than a seed chosen per run")
+            .long_help(
+"Salt the hashes of --key-xxhash (and of keys once --memory-limit is passed)
+with N, so that runs hash alike and their --emit-key-hash columns and --index
+files agree, e.g. for lookup --hash-seed N."))
+
+        .arg(Arg::with_name("hash-secret-file")
+            .long("hash-secret-file")
+            .takes_value(true)
+            .value_name("FILE")
+            .conflicts_with_all(&["hash-seed", "hash-secret-env"])
+            .help("Hash keys with HMAC-SHA256 under the secret in FILE")
+            .long_help(
+"Replace --key-xxhash (and --long-key hash) hashes with the first 64 bits of the
+key's HMAC-SHA256 under the secret held in FILE, less any trailing newline.
+Hashes written out with --emit-key-hash or --index can then only be matched to
+keys, or to the hashes of other datasets, by someone holding the secret; a
+seed, being 64 bits and unkeyed, can be searched for. Runs with the same secret
+hash alike."))
+
+        .arg(Arg::with_name("hash-secret-env")
+            .long("hash-secret-env")
+            .takes_value(true)
+            .value_name("VAR")
+            .conflicts_with("hash-seed")
+            .help("As --hash-secret-file, with the secret in environment variable VAR"))
+
+        .arg(Arg::with_name("max-key-bytes")
+            .long("max-key-bytes")
+            .takes_value(true)
+            .value_name("N")
+            .help("Limit keys to N bytes, handling longer ones per --long-key")
+            .long_help(
+"Handle keys longer than N bytes per --long-key, so that rows with huge key
+fields can't take unbounded memory to remember. How many keys were over the
+limit is written to standard error at the end of the run."))
+
+        .arg(Arg::with_name("long-key")
+            .long("long-key")
+            .takes_value(true)
+            .value_name("POLICY")
+            .possible_values(&["truncate", "hash", "skip", "error"])
+            .default_value("hash")
+            .help("What to do with keys longer than --max-key-bytes")
+            .long_help(
+"What to do with keys longer than --max-key-bytes: 'truncate' keeps their first
+N bytes, so keys alike up to there are duplicates; 'hash' replaces them with
+their hash, as --key-xxhash does; 'skip' drops the row and 'error' aborts the
+run."))
+
+        .arg(Arg::with_name("count")
+            .short("c")
+            .long("count")
+            .help("Prefix each output row with the number of rows with its key, like uniq -c")
+            .long_help(
+"Add the number of rows with each kept row's key as a new first column (before
+any --emit-key-hash column). Rows are held back until their count is final, as
+for --keep: until the end of the input or, with -s, until their key ends."))
+
+        .arg(Arg::with_name("count-distinct")
+            .long("count-distinct")
+            .help("Write only the number of distinct keys instead of the rows")
+            .long_help(
+"Instead of writing the kept rows, write the number of distinct keys in the
+input (rows passed by --no-match pass or --always-keep-from aren't counted).
+Can't be used with options that change the output or hold rows back."))
+
+        .arg(Arg::with_name("count-distinct-per-file")
+            .long("count-distinct-per-file")
+            .conflicts_with("count-distinct")
+            .help("Write each input's name and number of distinct keys instead of the rows")
+            .long_help(
+"As for --count-distinct, but count each input's keys separately and write a
+line per input: its name, a tab and the count."))
+
+        .arg(Arg::with_name("pipe-per-key")
+            .long("pipe-per-key")
+            .takes_value(true)
+            .value_name("COMMAND")
+            .help("Write each key's kept rows to COMMAND, run with {key} replaced by the key")
+            .long_help(
+"Instead of writing kept rows to the output, write them to the standard input of
+COMMAND, run by sh -c once per key with '{key}' replaced by the key (quoted for
+the shell), e.g. --pipe-per-key 'handle-customer {key}'. The commands' own
+output goes to tsvfirst's. At most --pipe-jobs commands are open at once; when
+another is needed the least recently used is closed first, so with unsorted
+input a key's rows may be split over several runs of its command. tsvfirst fails
+if a command does. Requires tsv output."))
+
+        .arg(Arg::with_name("pipe-jobs")
+            .long("pipe-jobs")
+            .takes_value(true)
+            .value_name("N")
+            .default_value("8")
+            .help("The most --pipe-per-key commands to have open at once"))
+
+        .arg(Arg::with_name("emit-key-hash")
+            .long("emit-key-hash")
+            .requires("key-xxhash")
+            .help("Prefix each output row with its --key-xxhash hash as a new column")
+            .long_help(
+"Add the key hash, as 16 hex digits, as a new first column of each output row
+(empty for rows without a key), so downstream joins can use the compact hash
+rather than long composite keys. Requires --key-xxhash."))
+
+        .arg(Arg::with_name("where")
+            .long("where")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("CONDITION")
+            .conflicts_with("max-row-buffer")
+            .help("Ignore rows not meeting CONDITION, e.g. '3 > 100'")
+            .long_help(
+"Ignore rows that don't meet CONDITION, of the form 'FIELD OP VALUE' with OP one
+of <, <=, >, >=, == and !=. If the field and VALUE are both numbers they are
+compared numerically; otherwise only == and != can hold, comparing text (so
+'2 == ERROR' works). Ignored rows are neither deduplicated nor written, and
+don't count towards keys seen. May be repeated; rows must meet every condition
+(including any --match-field)."))
+
+        .arg(Arg::with_name("match-field")
+            .long("match-field")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("FIELD:/REGEX/")
+            .conflicts_with("max-row-buffer")
+            .help("Ignore rows whose FIELD doesn't match REGEX, e.g. '2:/^ERR/'")
+            .long_help(
+"Ignore rows whose field FIELD does not match the regular expression REGEX,
+as for --where. May be repeated."))
+
+        .arg(Arg::with_name("having")
+            .long("having")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("CONDITION")
+            .help("Only write kept rows meeting CONDITION, e.g. 'count >= 2'")
+            .long_help(
+"Only write kept rows that meet CONDITION, checked after deduplication: rows
+failing it are still recorded, so later rows with the same key are dropped as
+usual. CONDITION is as for --where, or 'count OP VALUE' to compare the number
+of rows with the row's key, e.g. 'count >= 2' writes only keys that have
+duplicates. Using count holds kept rows back as for --keep. May be repeated."))
+
+        .arg(Arg::with_name("duplicates")
+            .short("d")
+            .long("duplicates")
+            .help("Only write keys that occur more than once, like uniq -d")
+            .long_help(
+"Only write keys that occur more than once: the kept row of each key with
+duplicates is written, and keys seen once are dropped. Shorthand for --having
+'count >= 2', so rows are held back as for --keep, until the end of the input
+or (with -s) until their key ends."))
+
+        .arg(Arg::with_name("unique")
+            .short("u")
+            .long("unique")
+            .conflicts_with_all(&["duplicates", "all-duplicates"])
+            .help("Only write rows whose key occurs exactly once, like uniq -u")
+            .long_help(
+"Only write rows whose key occurs exactly once in the input, dropping every row
+of keys that repeat. Shorthand for --having 'count == 1', so rows are held back
+as for --keep, until the end of the input or (with -s) until their key ends."))
+
+        .arg(Arg::with_name("all-duplicates")
+            .short("D")
+            .long("all-duplicates")
+            .conflicts_with("duplicates")
+            .help("Write every row of keys that occur more than once, like uniq -D")
+            .long_help(
+"Write every row whose key occurs more than once, and drop keys seen only once.
+A key's first row is held in memory until a second row shows it is duplicated,
+when both are written; later rows are written as they are read. Rows of a key
+keep their order, but with unsorted input may come after rows of other keys
+that were read later. Only remembers the current key's row with -s. Can't be
+combined with row-holding options (--keep, --having, --count, {count} in
+--format) or with --output-format decisions, --mark-dupes, --clusters,
+--max-row-buffer, --max-per-key, --index, --stats-per-file or
+--across-files-only."))
+
+        .arg(Arg::with_name("invert")
+            .long("invert")
+            .help("Write only the rows that would be dropped")
+            .long_help(
+"Write only the rows that would otherwise be dropped, i.e. the duplicates (and
+rows failing --having), so they can be audited or diffed against the normal
+output. Rows exempt from deduplication, such as those passed by --no-match
+pass or --always-keep-from, aren't written. Requires tsv output and --keep
+first or nth, and can't be combined with count in --having, --agg, --count,
+-D, --group, --count-distinct or --mark-dupes."))
+
+        .arg(Arg::with_name("line-regex")
+            .long("line-regex")
+            .takes_value(true)
+            .value_name("PATTERN")
+            .conflicts_with_all(&["fields", "key-first-n-fields", "mask-key", "max-row-buffer"])
+            .help("Key on the capture groups of PATTERN matched against the whole line")
+            .long_help(
+"Instead of selecting fields, apply the regular expression PATTERN to the whole
+line and use its capture groups (joined by tabs) as the key, or the whole match
+if PATTERN has no groups. Rows that don't match are handled per --no-match."))
+
+        .arg(Arg::with_name("no-match")
+            .long("no-match")
+            .takes_value(true)
+            .value_name("POLICY")
+            .possible_values(&["pass", "skip", "error"])
+            .default_value("pass")
+            .help("What to do with rows --line-regex doesn't match")
+            .long_help(
+"What to do with rows that --line-regex doesn't match: 'pass' writes them
+without deduplicating, 'skip' drops them and 'error' aborts the run."))
+
+        .arg(Arg::with_name("partition-by")
+            .long("partition-by")
+            .takes_value(true)
+            .value_name("FIELD:RANGES")
+            .conflicts_with_all(&["always-keep-from", "index", "rules"])
+            .help("Keep rows per key per range of a numeric field, e.g. 2:0-100,100-1000,1000-")
+            .long_help(
+"Bucket the numbers in FIELD into RANGES, separated by commas, and add the
+bucket to each key, so that a row is kept per key per range. Each range is
+LOW-HIGH, holding values from LOW up to but not including HIGH, or LOW- for
+every value from LOW up. A value in more than one range goes in the first;
+rows whose value is in none, or isn't a number, are handled per
+--outside-partitions. Can't be combined with --always-keep-from, --index or
+--rules, whose keys are field values alone."))
+
+        .arg(Arg::with_name("outside-partitions")
+            .long("outside-partitions")
+            .takes_value(true)
+            .value_name("POLICY")
+            .possible_values(&["pass", "skip", "error"])
+            .default_value("pass")
+            .help("What to do with rows in no --partition-by range")
+            .long_help(
+"What to do with rows whose --partition-by value is in none of the ranges:
+'pass' writes them without deduplicating, 'skip' drops them and 'error' aborts
+the run."))
+
+        .arg(Arg::with_name("alias-map")
+            .long("alias-map")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Rewrite key values to canonical forms listed in FILE")
+            .long_help(
+"Load a two-column, tab-separated FILE mapping key values to canonical forms
+(e.g. 'UK<tab>GB') and rewrite each key field value before deduplication, so
+known synonyms count as the same key. Values are matched exactly, before any
+field modifiers such as 'i' are applied. Output rows are not changed."))
 
         .arg(Arg::with_name("whitespace")
             .long("whitespace")
             .short("w")
-            .help("Split fields whitespace instead of tabs"))
+            .help("Split fields on whitespace instead of tabs")
+            .long_help(
+"Split fields on runs of whitespace instead of tabs. In UTF-8 input this
+includes Unicode spaces such as the no-break space U+00A0; add
+--whitespace-mode ascii to split on ASCII whitespace only, which is faster."))
+
+        .arg(Arg::with_name("quoted")
+            .long("quoted")
+            .conflicts_with_all(&["whitespace", "line-regex", "mask-key", "max-row-buffer"])
+            .help("Allow for double-quoted key fields that may contain tabs")
+            .long_help(
+"When finding key fields, treat a field starting with a double quote as quoted:
+it runs to the closing quote, so may contain tabs, with '\"\"' standing for a
+quote, and its key value is the unquoted text. Only the key is affected: rows
+are still written exactly as read, and --where, --format and the like split on
+every tab. Quoted values can't span lines."))
+
+        .arg(Arg::with_name("whitespace-mode")
+            .long("whitespace-mode")
+            .takes_value(true)
+            .value_name("MODE")
+            .possible_values(&["unicode", "ascii"])
+            .default_value("unicode")
+            .help("Which characters -w treats as whitespace"))
 
         .arg(Arg::with_name("sorted")
             .long("sorted")
@@ -49,52 +491,1424 @@ for those fields appear consecutively). This is faster because tsvfirst only nee
 to compare the previous and current rows to determine uniqueness, rather than
 tracking all previously seen values."))
 
+        .arg(Arg::with_name("keep")
+            .long("keep")
+            .takes_value(true)
+            .value_name("POLICY")
+            .possible_values(&["first", "nth", "last", "lexmin", "lexmax", "random"])
+            .default_value("first")
+            .help("Which row to keep for each key")
+            .long_help(
+"Which row to keep for each key. 'first' keeps the first row seen and writes it
+immediately (see also --max-per-key). 'nth' keeps the Nth row seen (set N with
+--nth), also writing it immediately, and drops keys with fewer than N rows;
+e.g. '--keep nth --nth 2' writes each key's first retry. 'last' keeps the last
+row seen, e.g. the newest record of an append-only log. 'lexmin' and 'lexmax'
+keep the row that sorts first or last, comparing whole rows bytewise, so the
+output doesn't depend on the order of the input. 'random' keeps a row chosen
+uniformly at random (see --seed).
+
+Policies other than 'first' and 'nth' hold each key's best row in memory until
+the end of the input (or, with -s/--sorted-by, until its key or group ends),
+write kept rows in order of their key's first appearance, and can't be used
+with --output-format decisions, --mark-dupes, --clusters, --max-row-buffer,
+--stats-per-file or --across-files-only."))
+
+        .arg(Arg::with_name("max-by")
+            .long("max-by")
+            .takes_value(true)
+            .value_name("FIELD")
+            .conflicts_with("min-by")
+            .help("Keep the row whose FIELD is numerically largest for each key")
+            .long_help(
+"Keep, for each key, the row whose field FIELD is the largest number, e.g. the
+highest score per user. Rows where it isn't a number only win if no row's is;
+ties keep the earlier row unless --tie-break says otherwise. Rows are held
+back as for --keep lexmax, which this replaces."))
+
+        .arg(Arg::with_name("min-by")
+            .long("min-by")
+            .takes_value(true)
+            .value_name("FIELD")
+            .help("Keep the row whose FIELD is numerically smallest for each key")
+            .long_help(
+"As for --max-by, but keep the row whose field FIELD is the smallest number."))
+
+        .arg(Arg::with_name("tie-break")
+            .long("tie-break")
+            .takes_value(true)
+            .value_name("RULE,...")
+            .help("How --max-by/--min-by choose between tied rows, e.g. 'longest,last'")
+            .long_help(
+"Choose between rows tied under --max-by or --min-by by each RULE in turn until
+one decides: 'first' keeps the earlier row and 'last' the later one; 'longest'
+keeps the longer row; 'max:FIELD' and 'min:FIELD' keep the row whose field
+FIELD is the larger or smaller number. Rows still tied after every RULE keep
+the earlier row, e.g. '--max-by 2 --tie-break min:3,last'."))
+
+        .arg(Arg::with_name("rules")
+            .long("rules")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Choose the keep policy of keys matching patterns in FILE")
+            .long_help(
+"Apply a different keep policy to the keys matching each rule in FILE, one rule
+per line: a POLICY, a tab, 'exact' or 'regex', a tab and the pattern. An exact
+pattern is a key's field values joined by tabs, normalized as for the input; a
+regex matches anywhere in the key (its fields joined by tabs), so anchor it
+with ^ and $ to match whole keys. POLICY is first, last, lexmin, lexmax,
+random, max-by:FIELD or min-by:FIELD, as for --keep, --max-by and --min-by, or
+skip to drop all of the key's rows. The first rule a key matches applies; keys
+matching none follow --keep. Blank lines and lines starting with '#' are
+ignored. Rows are held back as for --keep lexmax, e.g.
+
+  last\texact\tadmin
+  max-by:3\tregex\t^user-
+  skip\tregex\t^test-"))
+
+        .arg(Arg::with_name("agg")
+            .long("agg")
+            .takes_value(true)
+            .value_name("FUNCTION(FIELD),...")
+            .help("Append columns aggregating each key's values, e.g. 'sum(3),mean(4)'")
+            .long_help(
+"Append a column to each kept row for each FUNCTION(FIELD), combining the
+field's values over all of the key's rows, e.g. 'sum(3),mean(4)' adds the
+total of field 3 and the average of field 4. FUNCTION is one of sum, mean,
+min, max (which ignore values that aren't numbers), first or last. Rows
+missing the field are ignored. Rows are held back as for --keep, which picks
+the row the columns are added to."))
+
+        .arg(Arg::with_name("nth")
+            .long("nth")
+            .takes_value(true)
+            .value_name("N")
+            .help("The occurrence of each key to keep with --keep nth"))
+
+        .arg(Arg::with_name("max-per-key")
+            .long("max-per-key")
+            .takes_value(true)
+            .value_name("N")
+            .help("Keep the first N rows of each key rather than just one")
+            .long_help(
+"Keep up to N rows for each key: the first N seen, written as they are read.
+Later rows are dropped as duplicates. Requires --keep first."))
+
+        .arg(Arg::with_name("seed")
+            .long("seed")
+            .takes_value(true)
+            .value_name("N")
+            .help("Seed the choices of --keep random, so that runs repeat them"))
+
+        .arg(Arg::with_name("max-dupe-ratio")
+            .long("max-dupe-ratio")
+            .takes_value(true)
+            .value_name("RATIO")
+            .help("Fail if more than RATIO (e.g. 0.2) of the rows are duplicates")
+            .long_help(
+"Exit with an error if the fraction of rows dropped as duplicates exceeds RATIO,
+a number between 0 and 1. This is a data-quality gate for upstream bugs that
+cause mass duplication: the ratio is checked continuously once 10000 rows have
+been read, so a bad run fails fast, and again at the end of the input."))
+
+        .arg(Arg::with_name("always-keep-from")
+            .long("always-keep-from")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Keep every row whose key is listed in FILE")
+            .long_help(
+"Never drop rows whose key is listed in FILE, one key per line (key fields
+joined by tabs, in -f order), e.g. sentinel or system rows. Their rows are all
+written, and don't count towards keys seen. Key values are normalized as for
+the input, so --fields modifiers and --alias-map apply."))
+
+        .arg(Arg::with_name("across-files-only")
+            .long("across-files-only")
+            .help("Only drop rows whose key first appeared in an earlier input")
+            .long_help(
+"Only treat a row as a duplicate if its key first appeared in an earlier input
+file. Rows repeating a key within the file it first appeared in are all kept,
+which suits inputs that are each trusted internally but overlap one another.
+Requires --keep first."))
+
+        .arg(Arg::with_name("pad-fields")
+            .long("pad-fields")
+            .takes_value(true)
+            .value_name("N")
+            .conflicts_with("max-row-buffer")
+            .help("Pad rows with fewer than N fields with empty ones")
+            .long_help(
+"Add empty fields to the end of rows with fewer than N, so that every row has
+at least N fields before keys are built and rows are written. Fields are added
+after a tab, or a space with -w. See also --truncate-fields."))
+
+        .arg(Arg::with_name("truncate-fields")
+            .long("truncate-fields")
+            .requires("pad-fields")
+            .help("With --pad-fields N, also cut rows down to N fields")
+            .long_help(
+"With --pad-fields N, also drop any fields after the Nth, so that every row has
+exactly N fields."))
+
+        .arg(Arg::with_name("skip-footer")
+            .long("skip-footer")
+            .takes_value(true)
+            .value_name("N")
+            .conflicts_with("max-row-buffer")
+            .help("Ignore the last N rows of each input, e.g. summary rows")
+            .long_help(
+"Ignore the last N rows of each input, such as the totals and summary rows
+that end many exports, so they are neither deduplicated nor written. Each
+row is held back until N more rows of its input have been read."))
+
+        .arg(Arg::with_name("footer-regex")
+            .long("footer-regex")
+            .takes_value(true)
+            .value_name("PATTERN")
+            .conflicts_with("max-row-buffer")
+            .help("Ignore the rest of an input from the first row matching PATTERN")
+            .long_help(
+"Treat the first row of an input matching the regular expression PATTERN as
+the start of its footer: that row and all following rows of the input are
+ignored. For example, --footer-regex '^Total' drops a trailing totals section."))
+
+        .arg(Arg::with_name("head")
+            .long("head")
+            .takes_value(true)
+            .value_name("N")
+            .help("Stop after writing N rows")
+            .long_help(
+"Stop after writing N kept rows and exit without reading the rest of the input.
+Useful for previewing the deduplicated output of a large file."))
+
+        .arg(Arg::with_name("max-row-buffer")
+            .long("max-row-buffer")
+            .takes_value(true)
+            .value_name("SIZE")
+            .help("Stream rows longer than SIZE bytes instead of buffering them, e.g. 64M")
+            .long_help(
+"Rows longer than SIZE bytes (suffixes K, M and G are accepted) are not held in
+memory: the key is taken from the start of the row, the keep/drop decision is
+made, and the rest of the row is copied to the output (or skipped) in chunks.
+Only the key fields themselves need to fit in memory. Requires tsv or
+decisions output, --utf8 ignore and no --mask-key."))
+
+        .arg(Arg::with_name("memory-limit")
+            .long("memory-limit")
+            .takes_value(true)
+            .value_name("SIZE")
+            .help("Switch to remembering key hashes once keys use about SIZE bytes, e.g. 2G")
+            .long_help(
+"A soft limit on the memory used to remember keys (suffixes K, M and G are
+accepted), estimated from the key lengths. When it is passed, the keys seen so
+far are replaced by their 64-bit XXH3 hashes and later keys are remembered the
+same way, as with --key-xxhash, and a notice is written to stderr. If even the
+hashes pass the limit, tsvfirst fails."))
+
+        .arg(Arg::with_name("no-adaptive-memory")
+            .long("no-adaptive-memory")
+            .requires("memory-limit")
+            .help("Fail at --memory-limit instead of switching to key hashes"))
+
+        .arg(Arg::with_name("utf8")
+            .long("utf8")
+            .takes_value(true)
+            .value_name("MODE")
+            .possible_values(&["strict", "lossy", "ignore"])
+            .default_value("ignore")
+            .help("How to handle rows that are not valid UTF-8")
+            .long_help(
+"How to handle rows that are not valid UTF-8. 'strict' aborts with the position
+of the first invalid sequence. 'lossy' replaces invalid sequences with U+FFFD
+in both the key and the emitted row (and reports them via --errors). 'ignore'
+skips validation and passes bytes through untouched; case-insensitive fields
+then only fold ASCII letters in rows that are not valid UTF-8."))
+
+        .arg(Arg::with_name("ensure-eol")
+            .long("ensure-eol")
+            .help("End a final row that lacks a newline with one")
+            .long_help(
+"If the last row of an input has no trailing newline, write it with one, so
+that every output row is newline-terminated (and rows from the following input
+don't run on from it). For --output-format tsv only; the other formats always
+end their records. See also --keep-eol."))
+
+        .arg(Arg::with_name("keep-eol")
+            .long("keep-eol")
+            .conflicts_with("ensure-eol")
+            .help("Write a final row without a newline as-is (the default)"))
+
+        .arg(Arg::with_name("byte-exact")
+            .long("byte-exact")
+            .help("Guarantee kept rows are written byte-for-byte as read")
+            .long_help(
+"Guarantee that every row written is byte-for-byte identical to the input row,
+including its line ending (CRLF, or none on a final row) and any trailing
+fields. Options that rewrite rows, such as --mask-key, --mark-dupes,
+--utf8 lossy or a non-tsv --output-format, are rejected, as are --keep
+policies other than first and nth and counts in --having, which hold rows
+back and end a final row with a newline."))
+
+        .arg(Arg::with_name("output")
+            .short("o")
+            .long("output")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Write to FILE rather than stdout, replacing it only on success")
+            .long_help(
+"Write the results to FILE rather than stdout. They are written to a temporary
+file in the same directory, which replaces FILE only once the run succeeds, so
+FILE is never left holding partial output and can even be one of the inputs.
+A replaced FILE keeps its permissions."))
+
+        .arg(Arg::with_name("compress")
+            .long("compress")
+            .takes_value(true)
+            .value_name("METHOD[:LEVEL]")
+            .help("Compress the output with gzip or zstd, e.g. 'zstd:19'")
+            .long_help(
+"Compress the output with METHOD, gzip or zstd, optionally at LEVEL: 0 to 9
+for gzip (default 6), 1 to 22 for zstd (default 3). Without it, --output files
+ending in .gz or .zst are compressed to match. Can't be used with --index,
+whose offsets are into the uncompressed output."))
+
+        .arg(Arg::with_name("rotate-lines")
+            .long("rotate-lines")
+            .takes_value(true)
+            .value_name("N")
+            .requires("output")
+            .conflicts_with("rotate-size")
+            .help("Split --output into numbered files of N lines each")
+            .long_help(
+"Write --output FILE as numbered files of up to N lines each, the number going
+before FILE's extensions, e.g. out.0001.tsv, out.0002.tsv for out.tsv. Each
+file replaces any of the same name as soon as it is full, and is compressed
+as FILE would be. Can't be used with sql output or --index."))
+
+        .arg(Arg::with_name("rotate-size")
+            .long("rotate-size")
+            .takes_value(true)
+            .value_name("SIZE")
+            .requires("output")
+            .help("Split --output into numbered files of at most SIZE bytes, e.g. 1G")
+            .long_help(
+"As for --rotate-lines, but start a new file before a line would take the
+current one over SIZE bytes (with an optional K, M or G suffix), counted
+before compression. A single longer line gets a file to itself."))
+
+        .arg(Arg::with_name("state")
+            .long("state")
+            .takes_value(true)
+            .value_name("FILE")
+            .conflicts_with_all(&["in-place", "verify", "advise"])
+            .help("Carry on from the progress saved in FILE by the last run, then update it")
+            .long_help(
+"Save in FILE how far the input has been read and every key seen, and on later
+runs carry on from there: only rows added to the input since are read, and
+keys seen by earlier runs count as seen, so only newly kept rows are written.
+The first run, before FILE exists, reads the whole input. Only complete lines
+are read, so a row still being written is left for the next run. Requires a
+single uncompressed input file, and can't be used with -s/--sorted-by,
+--memory-limit, --keep policies that hold rows back (or --agg, --count or
+counts in --having), -D, --count-distinct, --head, --skip-footer or
+--footer-regex."))
+
+        .arg(Arg::with_name("append")
+            .long("append")
+            .requires_all(&["state", "output"])
+            .conflicts_with_all(&["compress", "rotate-lines", "rotate-size"])
+            .help("Append newly kept rows to --output rather than replacing it")
+            .long_help(
+"With --state, add the rows kept to the end of the --output file rather than
+replacing it, so incremental runs keep a complete output cheaply. The file is
+first cut back to its length when the state was saved, dropping any rows left
+by a run that failed partway. Unless FILE already exists, the output must be
+empty or missing. Can't be used with compressed output."))
+
+        .arg(Arg::with_name("in-place")
+            .long("in-place")
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true)
+            .value_name("SUFFIX")
+            .help("Rewrite each input file with its own deduplicated rows")
+            .long_help(
+"Instead of writing to stdout, replace each input file with its deduplicated
+rows. Files are processed independently, so a key is only a duplicate within
+its own file. Each file is only replaced once it has been processed, as with
+--output. With '--in-place=SUFFIX', the original is kept alongside with SUFFIX
+added to its name, e.g. '--in-place=.bak'. Files compressed with gzip or zstd
+are written back compressed the same way, unless --compress says otherwise;
+bzip2 and xz files can't be rewritten. Can't be used with standard input,
+--output, --advise, --count-distinct or options that write other files
+(--index, --clusters, --summary, --dupes-to, --pipe-per-key)."))
+
+        .arg(Arg::with_name("output-format")
+            .long("output-format")
+            .takes_value(true)
+            .value_name("FORMAT")
+            .possible_values(&["tsv", "sql", "decisions"])
+            .default_value("tsv")
+            .help("Format of the rows written to standard output")
+            .long_help(
+"Format of the rows written to standard output. 'tsv' writes kept rows exactly
+as they were read. 'sql' writes them as multi-row INSERT statements for the
+table given by --table, with each field quoted as a string literal. Quotes
+are doubled; with --sql-dialect mysql backslashes are doubled too, whereas
+postgres output relies on its default standard_conforming_strings.
+'decisions' writes one JSON object per input row (kept or dropped) with its
+key, action, occurrence number, source and line number."))
+
+        .arg(Arg::with_name("format")
+            .long("format")
+            .takes_value(true)
+            .value_name("TEMPLATE")
+            .help("Write kept rows through TEMPLATE, e.g. '{1}\\t{3}\\t{count}'")
+            .long_help(
+"Write each kept row through TEMPLATE instead of as read. Placeholders in
+braces are replaced: {N} by field N, {key} by the key, {count} by the number of
+rows with the key, {occurrence} by which of them was kept, {filename} by the
+input name and {line} by the line number within it. '{{' and '}}' are literal
+braces and the escapes '\\t' and '\\\\' stand for a tab and a backslash. A
+newline is added to each row. Using {count} holds kept rows back until the end
+of the input (or of the key, with -s), as for --keep. Can't be combined with
+other --output-format options, --mark-dupes or --max-row-buffer."))
+
+        .arg(Arg::with_name("keys-only")
+            .long("keys-only")
+            .conflicts_with("format")
+            .help("Write only the key fields of each kept row, joined by tabs")
+            .long_help(
+"Write just the key fields of each kept row, as they appear in the row, joined
+by tabs (or the --line-regex key), instead of the whole row. With --count, each
+is preceded by its count. Shorthand for a --format listing the key fields."))
+
+        .arg(Arg::with_name("group")
+            .long("group")
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true)
+            .value_name("METHOD")
+            .possible_values(&["separate", "prepend", "append", "both"])
+            .help("With -s, write every row, with blank lines between key groups")
+            .long_help(
+"With -s, write every row instead of deduplicating, marking each group of rows
+with the same key with blank lines, like uniq --group: '--group' or
+'--group=separate' puts one between groups, 'prepend' before each group,
+'append' after each group and 'both' before and after, with one line between
+groups. Requires tsv output (or --format) and --keep first."))
+
+        .arg(Arg::with_name("table")
+            .long("table")
+            .takes_value(true)
+            .value_name("NAME")
+            .required_if("output-format", "sql")
+            .help("Table name to INSERT into with --output-format sql")
+            .long_help(
+"Table name to INSERT into with --output-format sql. Each dot-separated part,
+as in 'schema.table', is written as-is if it is a plain name (letters, digits
+and underscores, not starting with a digit), and otherwise quoted as an
+identifier for --sql-dialect."))
+
+        .arg(Arg::with_name("sql-dialect")
+            .long("sql-dialect")
+            .takes_value(true)
+            .value_name("DIALECT")
+            .possible_values(&["postgres", "mysql"])
+            .default_value("postgres")
+            .help("SQL flavour for --output-format sql: postgres or mysql")
+            .long_help(
+"The database --output-format sql is written for. 'postgres' quotes unusual
+table names in double quotes, as standard SQL does, and writes backslashes in
+values as-is; 'mysql' quotes names in backticks and escapes backslashes."))
+
+        .arg(Arg::with_name("mark-dupes")
+            .long("mark-dupes")
+            .takes_value(true)
+            .value_name("PREFIX")
+            .help("Write duplicate rows too, prefixed with PREFIX, e.g. 'DUP\\t'")
+            .long_help(
+"Instead of dropping duplicate rows, write every row and prefix the duplicates
+with PREFIX, leaving downstream tools to decide what to do with them. The
+escapes '\\t' and '\\\\' in PREFIX stand for a tab and a backslash, so
+'DUP\\t' adds a leading column. Requires tsv output."))
+
+        .arg(Arg::with_name("mask-key")
+            .long("mask-key")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("[FIELD:]MASK")
+            .help("Mask key field values in the output, e.g. 'x***' or '2:=REDACTED'")
+            .long_help(
+"Hide the values of key fields in the output (deduplication still uses the
+real values). MASK is either '=TEXT', replacing the whole value with TEXT, or a
+pattern of leading and/or trailing 'x's around a mask character: each 'x' keeps
+one character from that end and every other character is replaced by the mask
+character. For example 'x***' turns 'alice' into 'a****' and '#xxxx' turns a
+card number into '############1234'. Prefix with 'FIELD:' to mask only that key
+field; otherwise the mask applies to all key fields. May be repeated."))
+
+        .arg(Arg::with_name("index")
+            .long("index")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Write a binary index of each kept row's key and output offset to FILE")
+            .long_help(
+"Write a compact binary index to FILE mapping a 64-bit hash of each kept row's
+key to the byte offset of the row in the output, sorted by hash, so the row for
+a key can later be found without rescanning the output (see 'tsvfirst lookup').
+Requires tsv output."))
+
+        .arg(Arg::with_name("clusters")
+            .long("clusters")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Write each input row's duplicate cluster and role to FILE")
+            .long_help(
+"Write a tab-separated line to FILE for every input row: its cluster number (one
+per distinct key, numbered in order of first appearance), its role ('original'
+for the row that was kept, 'duplicate' otherwise), its source name and its line
+number. Full duplicate groups can then be reconstructed from the input even
+though the output only contains the kept rows."))
+
+        .arg(Arg::with_name("summary")
+            .long("summary")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Write each key and its number of rows to FILE")
+            .long_help(
+"Write a tab-separated line to FILE for every distinct key: the key (as
+compared, so after any field modifiers, and as a hash with --key-xxhash) and
+the number of input rows with it, in order of the key's first appearance.
+Counts are held in memory until the end of the input, or with -s only until
+the key ends. Rows without a key, or listed by --always-keep-from, are left
+out."))
+
+        .arg(Arg::with_name("dupes-to")
+            .long("dupes-to")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Write the dropped rows to FILE, as read")
+            .long_help(
+"Write every row that is dropped (the duplicates, and rows failing --having)
+to FILE exactly as read, while kept rows are written as usual, saving a second
+run with --invert. Has the same restrictions as --invert, and can't be
+combined with it."))
+
+        .arg(Arg::with_name("errors")
+            .long("errors")
+            .takes_value(true)
+            .value_name("FORMAT")
+            .possible_values(&["text", "json"])
+            .help("Report non-fatal input problems (e.g. short rows) as text or json")
+            .long_help(
+"Report non-fatal problems with the input, such as rows missing a key field,
+to standard error (or the file given by --errors-file). 'text' writes one
+human-readable line per problem; 'json' writes one JSON object per problem with
+its category, source, line number, byte offset and message. Without this
+option such problems are ignored."))
+
+        .arg(Arg::with_name("errors-file")
+            .long("errors-file")
+            .takes_value(true)
+            .value_name("FILE")
+            .requires("errors")
+            .help("Write --errors reports to FILE instead of standard error"))
+
+        .arg(Arg::with_name("no-warnings")
+            .long("no-warnings")
+            .help("Don't warn when the options look wrong for the input")
+            .long_help(
+"Don't write warnings to standard error about options that look like mistakes
+for the input, each given at most once: a key field that most rows lack, -w
+on rows that look tab-separated with spaces inside fields, or -s when a key
+reappears after other keys. The first two are judged on the first 1000 rows,
+and -s on the keys of those rows."))
+
+        .arg(Arg::with_name("advise")
+            .long("advise")
+            .help("Analyse the start of the input and suggest flags, then exit")
+            .long_help(
+"Instead of deduplicating, read the first 10000 rows of the input and report
+the detected delimiter, the distribution of column counts, how many distinct
+keys there are, and whether rows with the same key appear consecutively. Then
+suggest flags (such as -s or -w) suited to the data and exit."))
+
+        .arg(Arg::with_name("verify")
+            .long("verify")
+            .takes_value(true)
+            .value_name("OUTPUT")
+            .help("Check that OUTPUT holds exactly the rows these options keep, then exit")
+            .long_help(
+"Instead of writing the kept rows, check that the file OUTPUT holds exactly
+them, in order, as if written by a run with the same options and inputs. The
+first difference is reported to stderr with its line numbers, with exit status
+1. Requires tsv output, and can't be used with --format, --mark-dupes,
+--group, --count-distinct, --pipe-per-key, --max-row-buffer, --output,
+--compress, --in-place or --advise."))
+
+        .arg(Arg::with_name("trace-keys")
+            .long("trace-keys")
+            .takes_value(true)
+            .value_name("REGEX")
+            .conflicts_with("key-xxhash")
+            .help("Log every decision about keys matching REGEX to stderr")
+            .long_help(
+"Log to standard error each decision about a row whose key matches the regular
+expression REGEX: where the row was read, its key and occurrence number, and,
+if it was dropped, where the key's kept row was read. Keys are as compared, so
+after any field modifiers, with key fields joined by tabs. With --keep
+policies that wait for all of a key's rows, only the chosen rows are logged.
+Can't be used with --key-xxhash, whose keys are hashes."))
+
+        .arg(Arg::with_name("profile")
+            .long("profile")
+            .help("Report time spent in each processing phase to stderr")
+            .long_help(
+"At the end of the run, report to standard error how much time was spent
+reading input, splitting rows and building keys, looking up and recording
+keys, and writing output. Useful for choosing flags such as --sorted."))
+
+        .arg(Arg::with_name("stats-per-file")
+            .long("stats-per-file")
+            .help("Report rows read, kept and duplicated per input to stderr")
+            .long_help(
+"At the end of the run, write a tab-separated table to standard error with a
+row per input: its name, the rows read from it, the rows kept, and the rows
+dropped as duplicates, split into duplicates of keys first seen in the same
+input ('dupes_within') and in an earlier input ('dupes_across'), and whether
+it was 'read' or 'skipped' by --skip-bad-inputs. Requires
+--keep first."))
+
+        .arg(Arg::with_name("progress")
+            .long("progress")
+            .help("Show rows and bytes processed, and throughput, on stderr")
+            .long_help(
+"Show a progress line on standard error, redrawn every second, with the number
+of rows and bytes read so far and the recent throughput. This works on pipes
+too; give --expected-lines or --expected-bytes for a percentage and ETA."))
+
+        .arg(Arg::with_name("expected-lines")
+            .long("expected-lines")
+            .takes_value(true)
+            .value_name("N")
+            .requires("progress")
+            .help("Expected number of input rows, for a --progress ETA"))
+
+        .arg(Arg::with_name("expected-bytes")
+            .long("expected-bytes")
+            .takes_value(true)
+            .value_name("SIZE")
+            .requires("progress")
+            .help("Expected input size, e.g. 20G, for a --progress ETA"))
+
+        .arg(Arg::with_name("group-by")
+            .long("group-by")
+            .takes_value(true)
+            .value_name("SPEC")
+            .conflicts_with("line-regex")
+            .help("Keep the first row per key within each group of these fields")
+            .long_help(
+"Partition the rows by the given fields and keep the first row for each key
+within each partition, e.g. '--group-by 1 -f 3' keeps the first event per user
+(column 3) for each day (column 1). The group fields are added to the key.
+If the input is sorted by the group fields, add --sorted-by with the same spec
+so that keys are forgotten at each group boundary."))
+
+        .arg(Arg::with_name("sorted-by")
+            .long("sorted-by")
+            .takes_value(true)
+            .value_name("SPEC")
+            .conflicts_with_all(&["sorted", "line-regex"])
+            .help("Assume the input is grouped by these key fields, e.g. '1'")
+            .long_help(
+"Declare that the input is grouped by some of the fields in -f: all rows with
+the same values for those fields appear consecutively. Keys are only tracked
+within the current group and forgotten when it changes, so memory use is
+proportional to the number of distinct keys in the largest group rather than
+in the whole input. For example, use '-f 1,3 --sorted-by 1' for a file sorted
+by column 1. The fields must be a subset of those in -f and --group-by."))
+
+        .arg(Arg::with_name("files-from")
+            .long("files-from")
+            .takes_value(true)
+            .value_name("FILE")
+            .conflicts_with_all(&["FILENAME", "files0-from"])
+            .help("Read the input filenames from FILE, one per line")
+            .long_help(
+"Read the names of the inputs from FILE, one per line, instead of from the
+command line; '-' reads them from standard input. Useful when there are too
+many to pass as arguments. Empty lines are ignored."))
+
+        .arg(Arg::with_name("files0-from")
+            .long("files0-from")
+            .takes_value(true)
+            .value_name("FILE")
+            .conflicts_with("FILENAME")
+            .help("Read the input filenames from FILE, each ended by a NUL byte")
+            .long_help(
+"Like --files-from, but with names ended by NUL bytes rather than newlines, so
+they may contain any other character, as written by find -print0."))
+
+        .arg(Arg::with_name("glob")
+            .long("glob")
+            .takes_value(true)
+            .value_name("PATTERN")
+            .help("Only read files matching PATTERN from directory inputs")
+            .long_help(
+"Of the files found under directory inputs, only read those whose names match
+PATTERN, where '*' matches any run of characters and '?' any one, e.g.
+--glob '*.tsv'. Quote it so the shell doesn't expand it."))
+
+        .arg(Arg::with_name("skip-bad-inputs")
+            .long("skip-bad-inputs")
+            .help("Warn about and skip input files that can't be opened")
+            .long_help(
+"If an input file is missing or can't be opened (e.g. permission denied),
+write a warning naming it to standard error and carry on with the remaining
+inputs, rather than aborting the run. The number skipped is reported at the
+end, and --stats-per-file lists them with the status 'skipped'."))
+
         .arg(Arg::with_name("FILENAME")
             .multiple(true)
             .help("Input filename/s (defaults to standard input)")
             .long_help(
 "One or more filenames to use as input: all files will be processed in order
 as if concatenated. If no filenames specified, defaults to standard input.
-The filename of '-' (a single dash) is also taken to mean standard input."))
+The filename of '-' (a single dash) is also taken to mean standard input.
+A directory stands for the files in it and its subdirectories (see --glob),
+in order of their paths, skipping hidden ones.
+http:// and https:// URLs are fetched, their response bodies read as files,
+as are s3://BUCKET/KEY and gs://BUCKET/KEY objects. S3 requests are signed
+with AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and AWS_SESSION_TOKEN from the
+environment, in the region AWS_REGION; GCS requests use the token in
+GOOGLE_OAUTH_ACCESS_TOKEN. Without credentials, objects are read anonymously.
+Inputs compressed with gzip, zstd, bzip2 or xz (as told from their first
+bytes, whatever their name) are decompressed as they are read."))
+
+        .subcommand(SubCommand::with_name("lookup")
+            .about("Print the row for a key from an output written with --index")
+            .arg(Arg::with_name("index")
+                .long("index")
+                .takes_value(true)
+                .value_name("FILE")
+                .required(true)
+                .help("Index written by --index"))
+            .arg(Arg::with_name("fields")
+                .short("f")
+                .long("fields")
+                .takes_value(true)
+                .value_name("SPEC")
+                .help("Key fields and modifiers used when writing the output [default: 1]"))
+            .arg(Arg::with_name("numeric-precision")
+                .long("numeric-precision")
+                .takes_value(true)
+                .value_name("PLACES")
+                .help("--numeric-precision used when writing the output"))
+            .arg(Arg::with_name("alias-map")
+                .long("alias-map")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("--alias-map used when writing the output"))
+            .arg(Arg::with_name("key-xxhash")
+                .long("key-xxhash")
+                .help("The output was written with --key-xxhash"))
+            .arg(Arg::with_name("hash-seed")
+                .long("hash-seed")
+                .takes_value(true)
+                .value_name("N")
+                .default_value("0")
+                .help("--hash-seed used when writing the output"))
+            .arg(Arg::with_name("hash-secret-file")
+                .long("hash-secret-file")
+                .takes_value(true)
+                .value_name("FILE")
+                .conflicts_with("hash-secret-env")
+                .help("--hash-secret-file used when writing the output"))
+            .arg(Arg::with_name("hash-secret-env")
+                .long("hash-secret-env")
+                .takes_value(true)
+                .value_name("VAR")
+                .help("--hash-secret-env used when writing the output"))
+            .arg(Arg::with_name("KEY")
+                .required(true)
+                .help("Key field values, separated by '\\t' escapes or tabs"))
+            .arg(Arg::with_name("OUTPUT")
+                .required(true)
+                .help("The output file the index describes")))
         .get_matches();
 
-    // Fields may be a CSV
-    let field_spec = args.value_of("fields").unwrap_or("1");
-    let fields = parse_field_spec(field_spec).unwrap_or_else(|ref e| {
-        println!("Error parsing field index(es): {}", e.description());
-        println!("{}", args.usage());
-        ::std::process::exit(1);
-    });
+    if let Some(lookup) = args.subcommand_matches("lookup") {
+        return lookup_command(lookup);
+    }
+
+    let mut fields = if args.is_present("key-first-n-fields") {
+        let count = value_t_or_exit!(args, "key-first-n-fields", usize);
+        if count == 0 {
+            exit_with_usage(&args, "--key-first-n-fields must be at least 1");
+        }
+        (0..count).map(KeyField::new).collect()
+    }
+    else {
+        // Fields may be a CSV
+        let field_spec = args.value_of("fields").unwrap_or("1");
+        parse_field_spec(field_spec).unwrap_or_else(|e| {
+            exit_with_usage(&args, &format!("Error parsing field index(es): {}", e))
+        })
+    };
+
+    if let Some(spec) = args.value_of("group-by") {
+        let mut group_fields = parse_field_spec(spec).unwrap_or_else(|e| {
+            exit_with_usage(&args, &format!("Error in --group-by: {}", e))
+        });
+        group_fields.extend(fields);
+        fields = normalize_fields(group_fields).unwrap_or_else(|e| {
+            exit_with_usage(&args, &format!("Error in --group-by: {}", e))
+        });
+    }
+
+    for field in args.values_of("json-field").into_iter().flatten() {
+        let index = match field.parse::<usize>() {
+            Ok(n) if n > 0 => n - 1,
+            _ => exit_with_usage(&args, &format!("Error in --json-field: invalid field number '{}'", field)),
+        };
+        match fields.iter_mut().find(|f| f.index == index) {
+            Some(key_field) => key_field.json = true,
+            None => exit_with_usage(&args, &format!("Error in --json-field: field {} is not a key field", field)),
+        }
+    }
 
     let mut config = Config::new()
         .fields(&fields)
         .sorted(args.is_present("sorted"))
-        .whitespace(args.is_present("whitespace"));
+        .whitespace(args.is_present("whitespace"))
+        .quoted(args.is_present("quoted"))
+        .ascii_whitespace(args.value_of("whitespace-mode") == Some("ascii"))
+        .profile(args.is_present("profile"))
+        .progress(args.is_present("progress"))
+        .stats_per_file(args.is_present("stats-per-file"))
+        .across_files_only(args.is_present("across-files-only"))
+        .skip_bad_inputs(args.is_present("skip-bad-inputs"))
+        .key_xxhash(args.is_present("key-xxhash"))
+        .emit_key_hash(args.is_present("emit-key-hash"))
+        .count_prefix(args.is_present("count"))
+        .utf8(value_t_or_exit!(args, "utf8", Utf8Mode))
+        .output_format(value_t_or_exit!(args, "output-format", OutputFormat))
+        .keep(value_t_or_exit!(args, "keep", Keep));
+
+    if let Some(path) = args.value_of("always-keep-from") {
+        config = config.always_keep_from(path);
+    }
+    config = config.all_duplicates(args.is_present("all-duplicates"));
+    if args.is_present("max-per-key") {
+        let n = value_t_or_exit!(args, "max-per-key", u64);
+        if n == 0 || config.keep != Keep::First {
+            exit_with_usage(&args, "--max-per-key must be at least 1 and requires --keep first");
+        }
+        config = config.max_per_key(n);
+    }
+    let extreme_by = args.value_of("max-by").map(|field| ("max-by", field))
+        .or_else(|| args.value_of("min-by").map(|field| ("min-by", field)));
+    if let Some((option, field)) = extreme_by {
+        if args.occurrences_of("keep") > 0 {
+            exit_with_usage(&args, &format!("--{} can't be combined with --keep", option));
+        }
+        let index = match field.parse::<usize>() {
+            Ok(n) if n > 0 => n - 1,
+            _ => exit_with_usage(&args, &format!("Error in --{}: invalid field number '{}'", option, field)),
+        };
+        config = config.keep(if option == "max-by" { Keep::MaxBy(index) } else { Keep::MinBy(index) });
+    }
+    if let Some(spec) = args.value_of("tie-break") {
+        if extreme_by.is_none() {
+            exit_with_usage(&args, "--tie-break requires --max-by or --min-by");
+        }
+        for rule in spec.split(',') {
+            match rule.parse::<TieBreak>() {
+                Ok(tie_break) => config = config.tie_break(tie_break),
+                Err(e) => exit_with_usage(&args, &format!("Error in --tie-break: {}", e)),
+            }
+        }
+    }
+    if let Some(path) = args.value_of("rules") {
+        if matches!(config.keep, Keep::Nth(_)) || config.max_per_key != 1 {
+            exit_with_usage(&args, "--rules can't be combined with --keep nth or --max-per-key");
+        }
+        config = config.keep_rules(path);
+    }
+    if let Some(spec) = args.value_of("agg") {
+        for item in spec.split(',') {
+            match item.parse() {
+                Ok(aggregation) => config = config.aggregate(aggregation),
+                Err(e) => exit_with_usage(&args, &format!("Error in --agg: {}", e)),
+            }
+        }
+    }
+    if args.is_present("seed") {
+        if config.keep != Keep::Random {
+            exit_with_usage(&args, "--seed requires --keep random");
+        }
+        config = config.seed(value_t_or_exit!(args, "seed", u64));
+    }
+    if args.value_of("keep") == Some("nth") {
+        if !args.is_present("nth") {
+            exit_with_usage(&args, "--keep nth requires --nth");
+        }
+        let n = value_t_or_exit!(args, "nth", u64);
+        if n == 0 {
+            exit_with_usage(&args, "--nth counts from 1");
+        }
+        if config.across_files_only {
+            exit_with_usage(&args, "--keep nth can't be used with --across-files-only");
+        }
+        config = config.keep(Keep::Nth(n));
+    }
+    else if args.is_present("nth") {
+        exit_with_usage(&args, "--nth requires --keep nth");
+    }
+    if !config.aggregations.is_empty() && (matches!(config.keep, Keep::Nth(_)) || config.max_per_key != 1) {
+        exit_with_usage(&args, "--agg can't be combined with --keep nth or --max-per-key");
+    }
+
+    if args.is_present("errors") {
+        config = config.errors(Some(value_t_or_exit!(args, "errors", ErrorFormat)));
+    }
+    config = config.warnings(!args.is_present("no-warnings"));
+    if let Some(pattern) = args.value_of("trace-keys") {
+        config = config.trace_keys(pattern);
+    }
+    for condition in args.values_of("where").into_iter().flatten() {
+        let predicate = condition.parse::<Predicate>().unwrap_or_else(|e| {
+            exit_with_usage(&args, &format!("Error in --where: {}", e))
+        });
+        if predicate.uses_count() {
+            exit_with_usage(&args, "Error in --where: count is only known after deduplication (see --having)");
+        }
+        config = config.filter(predicate);
+    }
+    if args.is_present("duplicates") {
+        config = config.having("count >= 2".parse().unwrap());
+    }
+    if args.is_present("unique") {
+        config = config.having("count == 1".parse().unwrap());
+    }
+    for condition in args.values_of("having").into_iter().flatten() {
+        let predicate = condition.parse::<Predicate>().unwrap_or_else(|e| {
+            exit_with_usage(&args, &format!("Error in --having: {}", e))
+        });
+        config = config.having(predicate);
+    }
+    for condition in args.values_of("match-field").into_iter().flatten() {
+        let predicate = Predicate::parse_match(condition).unwrap_or_else(|e| {
+            exit_with_usage(&args, &format!("Error in --match-field: {}", e))
+        });
+        config = config.filter(predicate);
+    }
+    if let Some(secret) = hash_secret(&args) {
+        let long_keys_hashed = args.is_present("max-key-bytes") && args.value_of("long-key") == Some("hash");
+        if !args.is_present("key-xxhash") && !long_keys_hashed {
+            exit_with_usage(&args, "--hash-secret-file and --hash-secret-env need hashed keys");
+        }
+        config = config.hash_secret(&secret);
+    }
+    if args.is_present("hash-seed") {
+        config = config.hash_seed(value_t_or_exit!(args, "hash-seed", u64));
+    }
+    else if args.is_present("emit-key-hash") || args.is_present("index") {
+        // Written hashes must match those of other runs
+        config = config.hash_seed(0);
+    }
+    if let Some(path) = args.value_of("index") {
+        if config.output_format != OutputFormat::Tsv {
+            exit_with_usage(&args, "--index requires tsv output");
+        }
+        config = config.index(path);
+    }
+    if let Some(path) = args.value_of("summary") {
+        config = config.summary(path);
+    }
+    if let Some(path) = args.value_of("clusters") {
+        config = config.clusters(path);
+    }
+    if let Some(path) = args.value_of("errors-file") {
+        config = config.errors_file(path);
+    }
+    for spec in args.values_of("mask-key").into_iter().flatten() {
+        let mask = spec.parse::<FieldMask>().unwrap_or_else(|e| {
+            exit_with_usage(&args, &format!("Error in --mask-key: {}", e))
+        });
+        if let Some(field) = mask.field {
+            if !fields.iter().any(|f| f.index == field) {
+                exit_with_usage(&args, &format!("Error in --mask-key: field {} is not a key field", field + 1));
+            }
+        }
+        config = config.mask_key(mask);
+    }
+    if args.is_present("max-dupe-ratio") {
+        let ratio = value_t_or_exit!(args, "max-dupe-ratio", f64);
+        if !(0.0..=1.0).contains(&ratio) {
+            exit_with_usage(&args, "--max-dupe-ratio must be between 0 and 1");
+        }
+        config = config.max_dupe_ratio(ratio);
+    }
+    if args.is_present("pad-fields") {
+        let count = value_t_or_exit!(args, "pad-fields", usize);
+        if count == 0 {
+            exit_with_usage(&args, "--pad-fields must be at least 1");
+        }
+        config = config.pad_fields(count, args.is_present("truncate-fields"));
+    }
+    if args.is_present("skip-footer") {
+        config = config.skip_footer(value_t_or_exit!(args, "skip-footer", usize));
+    }
+    if let Some(pattern) = args.value_of("footer-regex") {
+        config = config.footer_regex(pattern);
+    }
+    if args.is_present("head") {
+        config = config.head(value_t_or_exit!(args, "head", usize));
+    }
+    if let Some(size) = args.value_of("max-row-buffer") {
+        let bytes = parse_size(size).unwrap_or_else(|e| {
+            exit_with_usage(&args, &format!("Error in --max-row-buffer: {}", e))
+        });
+        if config.output_format == OutputFormat::Sql || config.utf8 != Utf8Mode::Ignore || !config.mask_key.is_empty() {
+            exit_with_usage(&args, "--max-row-buffer requires tsv or decisions output, --utf8 ignore and no --mask-key");
+        }
+        config = config.max_row_buffer(bytes);
+    }
+    if let Some(size) = args.value_of("memory-limit") {
+        let bytes = parse_size(size).unwrap_or_else(|e| {
+            exit_with_usage(&args, &format!("Error in --memory-limit: {}", e))
+        });
+        config = config.memory_limit(bytes)
+            .adaptive_memory(!args.is_present("no-adaptive-memory"));
+    }
+    if args.is_present("expected-lines") {
+        config = config.expected_lines(value_t_or_exit!(args, "expected-lines", u64));
+    }
+    if let Some(size) = args.value_of("expected-bytes") {
+        let bytes = parse_size(size).unwrap_or_else(|e| {
+            exit_with_usage(&args, &format!("Error in --expected-bytes: {}", e))
+        });
+        config = config.expected_bytes(bytes as u64);
+    }
+    if args.is_present("numeric-precision") {
+        config = config.numeric_precision(value_t_or_exit!(args, "numeric-precision", u32));
+    }
+    if let Some(path) = args.value_of("alias-map") {
+        config = config.alias_map(path);
+    }
+    if let Some(spec) = args.value_of("sorted-by") {
+        let group_fields = parse_field_spec(spec).unwrap_or_else(|e| {
+            exit_with_usage(&args, &format!("Error in --sorted-by: {}", e))
+        });
+        // Group fields are compared the same way as the key fields
+        let mut sorted_by = vec![];
+        for group_field in group_fields {
+            match fields.iter().find(|f| f.index == group_field.index) {
+                Some(field) => sorted_by.push(field.clone()),
+                None => exit_with_usage(&args, &format!("Error in --sorted-by: field {} is not a key field", group_field.index + 1)),
+            }
+        }
+        config = config.sorted_by(&sorted_by);
+    }
+    if args.is_present("max-key-bytes") {
+        let limit = value_t_or_exit!(args, "max-key-bytes", usize);
+        if limit == 0 {
+            exit_with_usage(&args, "--max-key-bytes must be at least 1");
+        }
+        config = config.max_key_bytes(limit, value_t_or_exit!(args, "long-key", LongKey));
+    }
+    else if args.occurrences_of("long-key") > 0 {
+        exit_with_usage(&args, "--long-key requires --max-key-bytes");
+    }
+    if let Some(pattern) = args.value_of("glob") {
+        config = config.glob(pattern);
+    }
+    if args.is_present("partition-by") {
+        config = config.partition_by(value_t_or_exit!(args, "partition-by", Partitions),
+            value_t_or_exit!(args, "outside-partitions", NoMatch));
+    }
+    else if args.occurrences_of("outside-partitions") > 0 {
+        exit_with_usage(&args, "--outside-partitions requires --partition-by");
+    }
+    if let Some(pattern) = args.value_of("line-regex") {
+        config = config.line_regex(pattern)
+            .no_match(value_t_or_exit!(args, "no-match", NoMatch));
+    }
+    if let Some(prefix) = args.value_of("mark-dupes") {
+        if config.output_format != OutputFormat::Tsv {
+            exit_with_usage(&args, "--mark-dupes requires tsv output");
+        }
+        config = config.mark_dupes(unescape(prefix).as_bytes());
+    }
+    let keys_only = if !args.is_present("keys-only") {
+        None
+    }
+    else {
+        let mut parts : Vec<String> = config.fields.iter().map(|f| format!("{{{}}}", f.index + 1)).collect();
+        if config.line_regex.is_some() {
+            parts = vec!["{key}".to_owned()];
+        }
+        if config.count_prefix {
+            parts.insert(0, "{count}".to_owned());
+        }
+        Some(parts.join("\t"))
+    };
+    if let Some(template) = keys_only.or_else(|| args.value_of("format").map(unescape)) {
+        let option = if args.is_present("keys-only") { "--keys-only" } else { "--format" };
+        let template = template.parse::<Template>().unwrap_or_else(|e| {
+            exit_with_usage(&args, &format!("Error in {}: {}", option, e))
+        });
+        if config.output_format != OutputFormat::Tsv || config.mark_dupes.is_some() || config.max_row_buffer.is_some() {
+            exit_with_usage(&args, &format!("{} can't be used with other output formats, --mark-dupes or --max-row-buffer", option));
+        }
+        // The template would see the extra column as the row's first field
+        if config.emit_key_hash || (config.count_prefix && option == "--format") {
+            exit_with_usage(&args, &format!("{} can't be used with --emit-key-hash or --count (use {{key}} or {{count}})", option));
+        }
+        config = config.template(template);
+    }
+    if config.buffers_rows() && (config.output_format == OutputFormat::Decisions || config.mark_dupes.is_some()
+            || config.clusters.is_some() || config.max_row_buffer.is_some() || config.stats_per_file || config.across_files_only) {
+        exit_with_usage(&args, "--keep other than 'first' (or --rules, --agg, --count, or counts in --format or --having) can't be used with --output-format decisions, --mark-dupes, --clusters, --max-row-buffer, --stats-per-file or --across-files-only");
+    }
+    if args.is_present("group") {
+        let separator = args.value_of("group").unwrap_or("separate").parse::<GroupSeparator>().unwrap();
+        if !config.sorted || config.buffers_rows() || config.all_duplicates || config.count_distinct.is_some()
+                || config.output_format != OutputFormat::Tsv || config.mark_dupes.is_some() || config.max_per_key != 1 {
+            exit_with_usage(&args, "--group requires -s, tsv output and --keep first, and can't be combined with --having, --count, -D, --count-distinct, --mark-dupes or --max-per-key");
+        }
+        config = config.group_separator(separator);
+    }
+    if config.all_duplicates && (config.buffers_rows() || !config.having.is_empty() || config.output_format == OutputFormat::Decisions
+            || config.mark_dupes.is_some() || config.clusters.is_some() || config.max_row_buffer.is_some()
+            || config.max_per_key != 1 || config.index.is_some() || config.stats_per_file || config.across_files_only) {
+        exit_with_usage(&args, "--all-duplicates can't be combined with --keep, --having, --count, {count} in --format, --output-format decisions, --mark-dupes, --clusters, --max-row-buffer, --max-per-key, --index, --stats-per-file or --across-files-only");
+    }
+    if let Some(command) = args.value_of("pipe-per-key") {
+        let jobs = value_t_or_exit!(args, "pipe-jobs", usize);
+        if jobs == 0 {
+            exit_with_usage(&args, "--pipe-jobs must be at least 1");
+        }
+        if config.output_format != OutputFormat::Tsv || config.template.is_some() || config.mark_dupes.is_some()
+                || config.index.is_some() || config.max_row_buffer.is_some() || config.group_separator.is_some() {
+            exit_with_usage(&args, "--pipe-per-key requires tsv output, and can't be combined with --format, --mark-dupes, --index, --max-row-buffer or --group");
+        }
+        config = config.pipe_per_key(command, jobs);
+    }
+    else if args.occurrences_of("pipe-jobs") > 0 {
+        exit_with_usage(&args, "--pipe-jobs requires --pipe-per-key");
+    }
+    if args.is_present("count-distinct") {
+        config = config.count_distinct(CountDistinct::Total);
+    }
+    else if args.is_present("count-distinct-per-file") {
+        config = config.count_distinct(CountDistinct::PerFile);
+    }
+    if config.count_distinct.is_some() && (config.buffers_rows() || config.all_duplicates
+            || config.output_format != OutputFormat::Tsv || config.template.is_some() || config.mark_dupes.is_some()
+            || config.emit_key_hash || config.index.is_some() || config.max_row_buffer.is_some() || config.pipe_per_key.is_some()
+            || config.head.is_some()) {
+        exit_with_usage(&args, "--count-distinct can't be combined with --keep, --having, --count, -D, --output-format, --format, --mark-dupes, --emit-key-hash, --index, --max-row-buffer, --pipe-per-key or --head");
+    }
+    if let Some(path) = args.value_of("dupes-to") {
+        if config.buffers_rows() || config.all_duplicates || config.group_separator.is_some() || config.count_distinct.is_some()
+                || config.output_format != OutputFormat::Tsv || config.mark_dupes.is_some() || args.is_present("invert") {
+            exit_with_usage(&args, "--dupes-to requires tsv output and --keep first or nth, and can't be combined with count in --having, --agg, --count, -D, --group, --count-distinct, --mark-dupes or --invert");
+        }
+        config = config.dupes_to(path);
+    }
+    if args.is_present("invert") {
+        if config.buffers_rows() || config.all_duplicates || config.group_separator.is_some() || config.count_distinct.is_some()
+                || config.output_format != OutputFormat::Tsv || config.mark_dupes.is_some() {
+            exit_with_usage(&args, "--invert requires tsv output and --keep first or nth, and can't be combined with count in --having, --agg, --count, -D, --group, --count-distinct or --mark-dupes");
+        }
+        config = config.invert(true);
+    }
+    if let Some(table) = args.value_of("table") {
+        config = config.table(table);
+    }
+    config = config.sql_dialect(value_t_or_exit!(args, "sql-dialect", SqlDialect));
+    config = config.ensure_eol(args.is_present("ensure-eol"));
+    if args.is_present("byte-exact") {
+        config = config.byte_exact(true);
+        if let Some(option) = config.rewriting_option() {
+            exit_with_usage(&args, &format!("{} rewrites rows, so cannot be used with --byte-exact", option));
+        }
+    }
 
     if let Some(inputs) = args.values_of("FILENAME") {
         for input in inputs {
             config = config.add_input(input);
         }
     }
-    config
+    if let Some(path) = args.value_of("files-from") {
+        config = config.files_from(path, b'\n');
+    }
+    if let Some(path) = args.value_of("files0-from") {
+        config = config.files_from(path, b'\0');
+    }
+    if let Some(path) = args.value_of("output") {
+        config = config.output(path);
+    }
+    if let Some(method) = args.value_of("compress") {
+        match method.parse::<Compression>() {
+            Ok(compression) => config = config.compress(compression),
+            Err(e) => exit_with_usage(&args, &format!("Error in --compress: {}", e)),
+        }
+    }
+    if let Some(path) = args.value_of("state") {
+        if config.inputs.len() != 1 || config.files_from.is_some()
+                || config.inputs.iter().any(|input| input == "-" || is_url(input) || Path::new(input).is_dir()) {
+            exit_with_usage(&args, "--state requires a single input file");
+        }
+        if config.sorted || !config.sorted_by.is_empty() || config.memory_limit.is_some() || config.buffers_rows()
+                || config.all_duplicates || config.count_distinct.is_some() || config.head.is_some()
+                || config.skip_footer > 0 || config.footer_regex.is_some() {
+            exit_with_usage(&args, "--state can't be used with -s, --sorted-by, --memory-limit, --keep policies that hold rows back, --agg, --count, counts in --having, -D, --count-distinct, --head, --skip-footer or --footer-regex");
+        }
+        config = config.state(path).append(args.is_present("append"));
+    }
+    if args.is_present("rotate-lines") || args.is_present("rotate-size") {
+        if config.output_format == OutputFormat::Sql || config.index.is_some() {
+            exit_with_usage(&args, "--rotate-lines and --rotate-size can't be used with sql output or --index");
+        }
+        let rotate = if args.is_present("rotate-lines") {
+            Rotate::Lines(value_t_or_exit!(args, "rotate-lines", u64))
+        }
+        else {
+            Rotate::Bytes(parse_size(args.value_of("rotate-size").unwrap()).unwrap_or_else(|e| {
+                exit_with_usage(&args, &format!("Error in --rotate-size: {}", e))
+            }) as u64)
+        };
+        if rotate == Rotate::Lines(0) || rotate == Rotate::Bytes(0) {
+            exit_with_usage(&args, "--rotate-lines and --rotate-size must be at least 1");
+        }
+        config = config.rotate(rotate);
+    }
+    if config.index.is_some() && config.output_compression().is_some() {
+        exit_with_usage(&args, "--index can't be used with compressed output");
+    }
+    if let Some(output) = args.value_of("verify") {
+        if config.output_format != OutputFormat::Tsv || config.template.is_some() || config.mark_dupes.is_some()
+                || config.group_separator.is_some() || config.count_distinct.is_some() || config.pipe_per_key.is_some()
+                || config.max_row_buffer.is_some() || config.output.is_some() || config.compress.is_some()
+                || args.is_present("in-place") || args.is_present("advise") {
+            exit_with_usage(&args, "--verify requires tsv output, and can't be used with --format, --mark-dupes, --group, --count-distinct, --pipe-per-key, --max-row-buffer, --output, --compress, --in-place or --advise");
+        }
+        return Command::Verify { config, output: output.into() };
+    }
+    if args.is_present("in-place") {
+        if config.inputs.is_empty() || config.inputs.iter().any(|input| input == "-" || is_url(input) || Path::new(input).is_dir())
+                || config.output.is_some() || args.is_present("advise") || config.count_distinct.is_some() || config.index.is_some()
+                || config.clusters.is_some() || config.summary.is_some() || config.dupes_to.is_some()
+                || config.pipe_per_key.is_some() {
+            exit_with_usage(&args, "--in-place requires input files, and can't be used with standard input, URLs, directories, --output, --advise, --count-distinct, --index, --clusters, --summary, --dupes-to or --pipe-per-key");
+        }
+        let backup_suffix = args.value_of("in-place").map(|suffix| suffix.to_owned());
+        if backup_suffix.as_ref().is_some_and(|suffix| suffix.is_empty()) {
+            exit_with_usage(&args, "--in-place=SUFFIX needs a non-empty SUFFIX");
+        }
+        Command::InPlace { config, backup_suffix }
+    }
+    else if args.is_present("advise") {
+        Command::Advise(config)
+    }
+    else {
+        Command::Run(config)
+    }
 }
 
-fn parse_field_spec(arg: &str) -> Result<Vec<usize>> {
-    let mut fields = vec![];
-    for field in arg.split(',') {
-        let field = field.parse::<usize>()?;
-        if field == 0 {
-            return Err("output field is 1-indexed; 0 is not valid".into());
+fn lookup_command(args: &ArgMatches) -> Command {
+    let fields = parse_field_spec(args.value_of("fields").unwrap_or("1")).unwrap_or_else(|e| {
+        exit_with_usage(args, &format!("Error parsing field index(es): {}", e))
+    });
+    let mut config = Config::new()
+        .fields(&fields)
+        .key_xxhash(args.is_present("key-xxhash"))
+        .hash_seed(value_t_or_exit!(args, "hash-seed", u64));
+    if let Some(secret) = hash_secret(args) {
+        config = config.hash_secret(&secret);
+    }
+    if args.is_present("numeric-precision") {
+        config = config.numeric_precision(value_t_or_exit!(args, "numeric-precision", u32));
+    }
+    if let Some(path) = args.value_of("alias-map") {
+        config = config.alias_map(path);
+    }
+    Command::Lookup {
+        config,
+        index: args.value_of("index").unwrap().into(),
+        data: args.value_of("OUTPUT").unwrap().into(),
+        key: args.value_of("KEY").unwrap().into(),
+    }
+}
+
+/// The secret given by --hash-secret-file or --hash-secret-env, if any
+fn hash_secret(args: &ArgMatches) -> Option<Vec<u8>> {
+    let mut secret = if let Some(path) = args.value_of("hash-secret-file") {
+        fs::read(path).unwrap_or_else(|e| {
+            exit_with_usage(args, &format!("Error reading --hash-secret-file {}: {}", path, e))
+        })
+    }
+    else if let Some(var) = args.value_of("hash-secret-env") {
+        match env::var_os(var) {
+            Some(value) => value.into_string().unwrap_or_else(|_| {
+                exit_with_usage(args, &format!("--hash-secret-env {} isn't valid UTF-8", var))
+            }).into_bytes(),
+            None => exit_with_usage(args, &format!("--hash-secret-env {} isn't set", var)),
         }
-        // Convert to 0-indexed
-        fields.push(field - 1);
+    }
+    else {
+        return None;
+    };
+    while secret.last().is_some_and(|&b| b == b'\n' || b == b'\r') {
+        secret.pop();
+    }
+    if secret.is_empty() {
+        exit_with_usage(args, "The hash secret is empty");
+    }
+    Some(secret)
+}
+
+fn exit_with_usage(args: &ArgMatches, message: &str) -> ! {
+    println!("{}", message);
+    println!("{}", args.usage());
+    ::std::process::exit(1);
+}
+
+/// Replaces the escapes '\\t' and '\\\\' with a tab and a backslash
+fn unescape(arg: &str) -> String {
+    let mut out = String::new();
+    let mut chars = arg.chars().peekable();
+    while let Some(c) = chars.next() {
+        let escaped = match (c, chars.peek()) {
+            ('\\', Some('t')) => '\t',
+            ('\\', Some('\\')) => '\\',
+            _ => {
+                out.push(c);
+                continue;
+            }
+        };
+        chars.next();
+        out.push(escaped);
+    }
+    out
+}
+
+/// Parses a byte count with an optional K, M or G (binary) suffix
+fn parse_size(arg: &str) -> Result<usize> {
+    let (digits, multiplier) = match arg.chars().last() {
+        Some('K') | Some('k') => (&arg[..arg.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&arg[..arg.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&arg[..arg.len() - 1], 1 << 30),
+        _ => (arg, 1),
+    };
+    let size = digits.parse::<usize>()?.checked_mul(multiplier).ok_or("size is too large")?;
+    if size == 0 {
+        return Err("size must be greater than 0".into());
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
+    use std::process;
+
+    use tsvfirst::compress;
+
+    /// A fresh directory for one test's files
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("tsvfirst-in-place-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    fn rewrite(path: &Path, backup_suffix: Option<&str>) -> Result<()> {
+        rewrite_in_place(Config::new().add_input(path.to_str().unwrap()), backup_suffix)
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut writer = Compression::Gzip(6).writer(vec![]).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn rewrites_with_backup() {
+        let dir = scratch("backup");
+        let path = dir.join("a.tsv");
+        fs::write(&path, "a\t1\na\t2\nb\t3\n").unwrap();
+        // An earlier backup is replaced
+        fs::write(dir.join("a.tsv.bak"), "old\n").unwrap();
+        rewrite(&path, Some(".bak")).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"a\t1\nb\t3\n");
+        assert_eq!(fs::read(dir.join("a.tsv.bak")).unwrap(), b"a\t1\na\t2\nb\t3\n");
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    if fields.is_empty() {
-        return Err("no fields specified".into());
+    #[test]
+    fn backs_up_by_copying_if_linking_fails() {
+        let dir = scratch("copy");
+        let path = dir.join("a.tsv");
+        fs::write(&path, "a\n").unwrap();
+        fs::write(dir.join("a.tsv.bak"), "old\n").unwrap();
+        let unlinkable = |_: &str, _: &str| Err(io::Error::other("cross-device link"));
+        back_up_with(path.to_str().unwrap(), ".bak", unlinkable).unwrap();
+        assert_eq!(fs::read(dir.join("a.tsv.bak")).unwrap(), b"a\n");
+        // A copy, not a link, so changing the original leaves it be
+        fs::write(&path, "b\n").unwrap();
+        assert_eq!(fs::read(dir.join("a.tsv.bak")).unwrap(), b"a\n");
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    fields.sort();
-    fields.dedup();
-    Ok(fields)
+    #[test]
+    fn rewrites_without_backup() {
+        let dir = scratch("no-backup");
+        let path = dir.join("a.tsv");
+        fs::write(&path, "a\t1\na\t2\n").unwrap();
+        rewrite(&path, None).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"a\t1\n");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_gzip_compression_whatever_the_name() {
+        let dir = scratch("gzip");
+        let path = dir.join("a");
+        fs::write(&path, gzip(b"a\t1\na\t2\n")).unwrap();
+        rewrite(&path, None).unwrap();
+        let mut rows = vec![];
+        compress::reader(fs::File::open(&path).unwrap()).unwrap().read_to_end(&mut rows).unwrap();
+        assert!(fs::read(&path).unwrap().starts_with(b"\x1f\x8b"));
+        assert_eq!(rows, b"a\t1\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_unwritable_or_misnamed_files() {
+        let dir = scratch("refused");
+        let plain = dir.join("a.tsv");
+        let bzip2 = dir.join("b.tsv.bz2");
+        let fake = dir.join("c.tsv.gz");
+        fs::write(&plain, "a\na\n").unwrap();
+        fs::write(&bzip2, b"BZh91AY&SY...").unwrap();
+        fs::write(&fake, "a\na\n").unwrap();
+        for bad in [&bzip2, &fake] {
+            let config = Config::new().add_input(plain.to_str().unwrap()).add_input(bad.to_str().unwrap());
+            assert!(rewrite_in_place(config, None).is_err());
+        }
+        // No file is replaced if any would be refused
+        assert_eq!(fs::read(&plain).unwrap(), b"a\na\n");
+        assert_eq!(fs::read(&fake).unwrap(), b"a\na\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
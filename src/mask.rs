@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::str;
+use std::str::FromStr;
+
+use regex::bytes::Regex;
+
+use key::KeyField;
+use output::chomp;
+
+/// How to hide the value of a key field in output
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mask {
+    /// Replace the whole value, e.g. `=REDACTED`
+    Fixed(Vec<u8>),
+    /// Keep the first and last few characters, replacing each of the others
+    /// with a mask character, e.g. `x***` or `***xxxx`
+    Reveal { first: usize, last: usize, with: char },
+}
+
+impl FromStr for Mask {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Mask, String> {
+        if let Some(text) = s.strip_prefix('=') {
+            return Ok(Mask::Fixed(text.as_bytes().to_owned()));
+        }
+
+        let first = s.len() - s.trim_start_matches('x').len();
+        let last = s.len() - s.trim_end_matches('x').len();
+        let middle = if first < s.len() { &s[first..s.len() - last] } else { "" };
+        let with = match middle.chars().next() {
+            Some(c) if middle.chars().all(|m| m == c) => c,
+            _ => return Err(format!("invalid mask '{}': expected '=TEXT' or a pattern like 'x***'", s)),
+        };
+        Ok(Mask::Reveal { first, last, with })
+    }
+}
+
+impl Mask {
+    pub fn apply(&self, value: &[u8], out: &mut Vec<u8>) {
+        match *self {
+            Mask::Fixed(ref text) => out.extend_from_slice(text),
+            Mask::Reveal { first, last, with } => match str::from_utf8(value) {
+                Ok(text) => {
+                    let count = text.chars().count();
+                    for (i, c) in text.chars().enumerate() {
+                        let c = if i < first || i + last >= count { c } else { with };
+                        let mut buf = [0; 4];
+                        out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                    }
+                }
+                // Not text, so mask byte by byte
+                Err(_) => {
+                    let mut buf = [0; 4];
+                    let with = with.encode_utf8(&mut buf).as_bytes();
+                    for (i, &b) in value.iter().enumerate() {
+                        if i < first || i + last >= value.len() {
+                            out.push(b);
+                        }
+                        else {
+                            out.extend_from_slice(with);
+                        }
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// A `--mask-key` argument: `[FIELD:]MASK`. Without a field the mask applies
+/// to every key field.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldMask {
+    /// 0-indexed column number
+    pub field: Option<usize>,
+    pub mask: Mask,
+}
+
+impl FromStr for FieldMask {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FieldMask, String> {
+        if let Some(colon) = s.find(':') {
+            if let Ok(field) = s[..colon].parse::<usize>() {
+                if field == 0 {
+                    return Err("mask field is 1-indexed; 0 is not valid".into());
+                }
+                let mask = s[colon + 1..].parse()?;
+                return Ok(FieldMask { field: Some(field - 1), mask });
+            }
+        }
+        Ok(FieldMask { field: None, mask: s.parse()? })
+    }
+}
+
+/// Rewrites rows with their key fields masked, preserving delimiters
+pub struct Masker {
+    masks: HashMap<usize, Mask>,
+    splitter: Regex,
+}
+
+impl Masker {
+    /// Field-specific masks take precedence over one applying to all key fields
+    pub fn new(masks: &[FieldMask], key_fields: &[KeyField], splitter: Regex) -> Masker {
+        let mut by_field = HashMap::new();
+        for mask in masks.iter().filter(|m| m.field.is_none()) {
+            for field in key_fields {
+                by_field.insert(field.index, mask.mask.clone());
+            }
+        }
+        for mask in masks {
+            if let Some(field) = mask.field {
+                by_field.insert(field, mask.mask.clone());
+            }
+        }
+        Masker { masks: by_field, splitter }
+    }
+
+    pub fn apply(&self, line: &[u8]) -> Vec<u8> {
+        let row = chomp(line);
+        let mut out = Vec::with_capacity(line.len());
+        let mut start = 0;
+        let delimiters = self.splitter.find_iter(row).map(|m| (m.start(), m.end()));
+        for (index, (end, next)) in delimiters.chain(Some((row.len(), row.len()))).enumerate() {
+            match self.masks.get(&index) {
+                Some(mask) => mask.apply(&row[start..end], &mut out),
+                None => out.extend_from_slice(&row[start..end]),
+            }
+            out.extend_from_slice(&row[end..next]);
+            start = next;
+        }
+        out.extend_from_slice(&line[row.len()..]);
+        out
+    }
+}
@@ -0,0 +1,128 @@
+/// Largest exponent written out in full; beyond it the canonical form uses
+/// scientific notation to avoid pathological zero padding
+const MAX_PLAIN_EXPONENT: i64 = 30;
+
+/// Rewrites a decimal number (optionally signed, with a fraction and/or an
+/// exponent, e.g. `-1.5e3`) into a canonical form, so that `1e3`, `1000` and
+/// `+1000.0` are all `1000`. With `precision`, the value is first rounded
+/// half away from zero to that many decimal places. Returns None if `value`
+/// is not a number.
+///
+/// Works on the decimal digits directly, so large integers keep full precision.
+pub fn normalize(value: &[u8], precision: Option<u32>) -> Option<Vec<u8>> {
+    let value = trim(value);
+    let (negative, rest) = match value.first() {
+        Some(b'-') => (true, &value[1..]),
+        Some(b'+') => (false, &value[1..]),
+        _ => (false, value),
+    };
+
+    // Split into mantissa and exponent
+    let (mantissa, exponent) = match rest.iter().position(|&b| b == b'e' || b == b'E') {
+        Some(e) => (&rest[..e], parse_exponent(&rest[e + 1..])?),
+        None => (rest, 0),
+    };
+    let (int_part, frac_part) = match mantissa.iter().position(|&b| b == b'.') {
+        Some(dot) => (&mantissa[..dot], &mantissa[dot + 1..]),
+        None => (mantissa, &mantissa[mantissa.len()..]),
+    };
+    if int_part.is_empty() && frac_part.is_empty() {
+        return None;
+    }
+    if !int_part.iter().chain(frac_part).all(u8::is_ascii_digit) {
+        return None;
+    }
+
+    // value = digits * 10^exponent
+    let mut digits : Vec<u8> = int_part.iter().chain(frac_part).cloned().collect();
+    let mut exponent = exponent.checked_sub(frac_part.len() as i64)?;
+
+    if let Some(precision) = precision {
+        let drop = -(precision as i64) - exponent;
+        if drop > 0 {
+            round_off(&mut digits, drop as usize);
+            exponent += drop;
+        }
+    }
+
+    // Strip leading and trailing zeros
+    let leading = digits.iter().take_while(|&&d| d == b'0').count();
+    digits.drain(..leading);
+    while digits.last() == Some(&b'0') {
+        digits.pop();
+        exponent += 1;
+    }
+    if digits.is_empty() {
+        return Some(b"0".to_vec());
+    }
+
+    let mut out = vec![];
+    if negative {
+        out.push(b'-');
+    }
+    if (0..=MAX_PLAIN_EXPONENT).contains(&exponent) {
+        out.extend_from_slice(&digits);
+        out.resize(out.len() + exponent as usize, b'0');
+    }
+    else if exponent < 0 && -exponent <= MAX_PLAIN_EXPONENT + digits.len() as i64 {
+        let point = digits.len() as i64 + exponent;
+        if point <= 0 {
+            out.extend_from_slice(b"0.");
+            out.resize(out.len() + (-point) as usize, b'0');
+            out.extend_from_slice(&digits);
+        }
+        else {
+            out.extend_from_slice(&digits[..point as usize]);
+            out.push(b'.');
+            out.extend_from_slice(&digits[point as usize..]);
+        }
+    }
+    else {
+        out.extend_from_slice(&digits);
+        out.extend_from_slice(format!("e{}", exponent).as_bytes());
+    }
+    Some(out)
+}
+
+/// Removes the last `drop` digits, rounding half away from zero
+fn round_off(digits: &mut Vec<u8>, drop: usize) {
+    if drop > digits.len() {
+        digits.clear();
+        return;
+    }
+    let round_up = digits[digits.len() - drop] >= b'5';
+    let keep = digits.len() - drop;
+    digits.truncate(keep);
+    if !round_up {
+        return;
+    }
+    for d in digits.iter_mut().rev() {
+        if *d == b'9' {
+            *d = b'0';
+        }
+        else {
+            *d += 1;
+            return;
+        }
+    }
+    digits.insert(0, b'1');
+}
+
+fn parse_exponent(s: &[u8]) -> Option<i64> {
+    let (negative, digits) = match s.first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+    if digits.is_empty() || digits.len() > 15 || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let magnitude = digits.iter().fold(0i64, |n, &d| n * 10 + (d - b'0') as i64);
+    Some(if negative { -magnitude } else { magnitude })
+}
+
+fn trim(value: &[u8]) -> &[u8] {
+    let start = value.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(value.len());
+    let end = value.iter().rposition(|b| !b.is_ascii_whitespace()).map_or(start, |i| i + 1);
+    &value[start..end]
+}
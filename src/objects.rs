@@ -0,0 +1,125 @@
+//! s3:// and gs:// inputs, fetched over HTTPS. S3 requests are signed
+//! (Signature Version 4) with credentials from AWS_ACCESS_KEY_ID,
+//! AWS_SECRET_ACCESS_KEY and AWS_SESSION_TOKEN, in the region from AWS_REGION
+//! or AWS_DEFAULT_REGION; GCS requests carry GOOGLE_OAUTH_ACCESS_TOKEN as a
+//! bearer token. Without credentials, objects are fetched anonymously, which
+//! works for public buckets.
+
+use std::env;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use ureq;
+
+/// Sent as the content hash of a GET, which has no body to hash
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// Whether an input name is an object storage URL
+pub fn is_object_url(name: &str) -> bool {
+    name.starts_with("s3://") || name.starts_with("gs://")
+}
+
+/// Builds the request fetching an s3://BUCKET/KEY or gs://BUCKET/KEY object
+pub fn request(url: &str) -> io::Result<ureq::Request> {
+    let (scheme, rest) = url.split_at(url.find("://").unwrap_or(0) + 3);
+    let (bucket, key) = match rest.find('/') {
+        Some(slash) if slash > 0 && slash + 1 < rest.len() => (&rest[..slash], &rest[slash + 1..]),
+        _ => return Err(io::Error::other(format!("expected {}BUCKET/KEY", scheme))),
+    };
+    let path = format!("/{}", uri_encode(key));
+    if scheme == "gs://" {
+        let request = ureq::get(&format!("https://storage.googleapis.com/{}{}", bucket, path));
+        return Ok(match env::var("GOOGLE_OAUTH_ACCESS_TOKEN") {
+            Ok(token) => request.set("Authorization", &format!("Bearer {}", token)),
+            Err(_) => request,
+        });
+    }
+
+    let region = env::var("AWS_REGION").or_else(|_| env::var("AWS_DEFAULT_REGION"))
+        .unwrap_or_else(|_| "us-east-1".into());
+    let host = format!("{}.s3.{}.amazonaws.com", bucket, region);
+    let mut request = ureq::get(&format!("https://{}{}", host, path));
+    let (key_id, secret) = match (env::var("AWS_ACCESS_KEY_ID"), env::var("AWS_SECRET_ACCESS_KEY")) {
+        (Ok(key_id), Ok(secret)) => (key_id, secret),
+        _ => return Ok(request),
+    };
+    let timestamp = timestamp(SystemTime::now());
+    // Sorted by name, as signing requires
+    let mut headers = vec![
+        ("host".to_owned(), host),
+        ("x-amz-content-sha256".to_owned(), UNSIGNED_PAYLOAD.to_owned()),
+        ("x-amz-date".to_owned(), timestamp.clone()),
+    ];
+    if let Ok(token) = env::var("AWS_SESSION_TOKEN") {
+        headers.push(("x-amz-security-token".into(), token));
+    }
+    let authorization = authorization(&key_id, &secret, &region, &path, &headers, UNSIGNED_PAYLOAD, &timestamp);
+    for (name, value) in &headers[1..] {
+        request = request.set(name, value);
+    }
+    Ok(request.set("Authorization", &authorization))
+}
+
+/// The Authorization header value signing an S3 GET of `path` with `headers`,
+/// which must be lowercase and sorted by name
+fn authorization(key_id: &str, secret: &str, region: &str, path: &str, headers: &[(String, String)],
+                 payload_hash: &str, timestamp: &str) -> String {
+    let date = &timestamp[..8];
+    let scope = format!("{}/{}/s3/aws4_request", date, region);
+    let mut canonical_headers = String::new();
+    for (name, value) in headers {
+        canonical_headers.push_str(&format!("{}:{}\n", name, value.trim()));
+    }
+    let signed_headers = headers.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(";");
+    let canonical_request = format!("GET\n{}\n\n{}\n{}\n{}", path, canonical_headers, signed_headers, payload_hash);
+    let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", timestamp, scope,
+        hex(&Sha256::digest(canonical_request.as_bytes())));
+
+    let mut key = hmac(format!("AWS4{}", secret).as_bytes(), date.as_bytes());
+    for part in &[region, "s3", "aws4_request"] {
+        key = hmac(&key, part.as_bytes());
+    }
+    format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}", key_id, scope, signed_headers,
+        hex(&hmac(&key, string_to_sign.as_bytes())))
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Percent-encodes an object key as S3 expects, leaving '/' separators alone
+fn uri_encode(key: &str) -> String {
+    let mut encoded = String::new();
+    for &b in key.as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// The time in UTC as YYYYMMDDTHHMMSSZ
+fn timestamp(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0);
+    let (days, second) = ((seconds / 86400) as i64, seconds % 86400);
+    // Howard Hinnant's days-to-civil algorithm
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", year, month, day, second / 3600, second / 60 % 60, second % 60)
+}
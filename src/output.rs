@@ -0,0 +1,386 @@
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+
+use regex::bytes::Regex;
+
+/// Number of rows grouped into a single multi-row INSERT statement
+const SQL_BATCH_ROWS: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputFormat {
+    Tsv,
+    Sql,
+    Decisions,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OutputFormat, String> {
+        match s {
+            "tsv" => Ok(OutputFormat::Tsv),
+            "sql" => Ok(OutputFormat::Sql),
+            "decisions" => Ok(OutputFormat::Decisions),
+            _ => Err(format!("unknown output format '{}'", s)),
+        }
+    }
+}
+
+/// The SQL flavour --output-format sql writes for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SqlDialect {
+    /// Standard SQL: identifiers in double quotes
+    Postgres,
+    /// Identifiers in backticks, and backslash escapes in strings
+    Mysql,
+}
+
+impl FromStr for SqlDialect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SqlDialect, String> {
+        match s {
+            "postgres" => Ok(SqlDialect::Postgres),
+            "mysql" => Ok(SqlDialect::Mysql),
+            _ => Err(format!("unknown SQL dialect '{}'", s)),
+        }
+    }
+}
+
+/// Where --group writes a blank line around each group of rows with a key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GroupSeparator {
+    /// Between groups
+    Separate,
+    /// Before each group
+    Prepend,
+    /// After each group
+    Append,
+    /// Before and after each group, with one line between groups
+    Both,
+}
+
+impl FromStr for GroupSeparator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<GroupSeparator, String> {
+        match s {
+            "separate" => Ok(GroupSeparator::Separate),
+            "prepend" => Ok(GroupSeparator::Prepend),
+            "append" => Ok(GroupSeparator::Append),
+            "both" => Ok(GroupSeparator::Both),
+            _ => Err(format!("unknown group separator '{}'", s)),
+        }
+    }
+}
+
+impl GroupSeparator {
+    /// Whether a group starting after `started` earlier groups needs a
+    /// blank line first
+    pub fn before_group(self, started: bool) -> bool {
+        match self {
+            GroupSeparator::Separate | GroupSeparator::Append => started,
+            GroupSeparator::Prepend | GroupSeparator::Both => true,
+        }
+    }
+
+    /// Whether the last group needs a blank line after it
+    pub fn after_last(self) -> bool {
+        self == GroupSeparator::Append || self == GroupSeparator::Both
+    }
+}
+
+/// The keep/drop decision made for a single input row
+pub struct Decision<'a> {
+    pub line: &'a [u8],
+    pub key: &'a [u8],
+    pub kept: bool,
+    /// How many times the key has been seen, including this row
+    pub occurrence: u64,
+    /// Total rows with the key, if known when the row is written
+    pub count: Option<u64>,
+    pub source: &'a str,
+    /// 1-indexed line number within the source
+    pub line_number: u64,
+}
+
+/// Serializes decisions to the output stream. Most formats only write kept rows.
+pub trait RowFormat {
+    fn write(&mut self, out: &mut dyn Write, decision: &Decision) -> io::Result<()>;
+
+    /// Called once after the last row has been written
+    fn finish(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes nothing, for modes that only report counts
+pub struct NullFormat;
+
+impl RowFormat for NullFormat {
+    fn write(&mut self, _out: &mut dyn Write, _decision: &Decision) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes rows exactly as they were read. With a duplicate marker, dropped
+/// rows are written too, prefixed by the marker.
+pub struct TsvFormat {
+    mark_dupes: Option<Vec<u8>>,
+}
+
+impl TsvFormat {
+    pub fn new(mark_dupes: Option<Vec<u8>>) -> TsvFormat {
+        TsvFormat { mark_dupes }
+    }
+}
+
+impl RowFormat for TsvFormat {
+    fn write(&mut self, out: &mut dyn Write, decision: &Decision) -> io::Result<()> {
+        if decision.kept {
+            out.write_all(decision.line)?;
+        }
+        else if let Some(ref marker) = self.mark_dupes {
+            out.write_all(marker)?;
+            out.write_all(decision.line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Passes writes through, counting the bytes written
+pub struct CountingWriter<'a> {
+    inner: &'a mut dyn Write,
+    pub written: u64,
+}
+
+impl<'a> CountingWriter<'a> {
+    pub fn new(inner: &'a mut dyn Write) -> CountingWriter<'a> {
+        CountingWriter { inner, written: 0 }
+    }
+}
+
+impl<'a> Write for CountingWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes rows as batched `INSERT INTO <table> VALUES (...), (...);` statements,
+/// with every field emitted as a quoted string literal
+pub struct SqlFormat {
+    table: String,
+    splitter: Regex,
+    dialect: SqlDialect,
+    rows_in_batch: usize,
+}
+
+impl SqlFormat {
+    pub fn new(table: &str, splitter: Regex) -> SqlFormat {
+        SqlFormat {
+            table: table.into(),
+            splitter,
+            dialect: SqlDialect::Postgres,
+            rows_in_batch: 0,
+        }
+    }
+
+    /// Quotes names and values as `dialect` does, rather than as standard SQL
+    pub fn dialect(mut self, dialect: SqlDialect) -> SqlFormat {
+        self.dialect = dialect;
+        self
+    }
+}
+
+impl RowFormat for SqlFormat {
+    fn write(&mut self, out: &mut dyn Write, decision: &Decision) -> io::Result<()> {
+        if !decision.kept {
+            return Ok(());
+        }
+
+        if self.rows_in_batch == 0 {
+            out.write_all(b"INSERT INTO ")?;
+            write_sql_name(out, &self.table, self.dialect)?;
+            out.write_all(b" VALUES\n(")?;
+        }
+        else {
+            out.write_all(b",\n(")?;
+        }
+
+        for (i, field) in self.splitter.split(chomp(decision.line)).enumerate() {
+            if i > 0 {
+                out.write_all(b", ")?;
+            }
+            write_sql_string(out, field, self.dialect)?;
+        }
+        out.write_all(b")")?;
+
+        self.rows_in_batch += 1;
+        if self.rows_in_batch == SQL_BATCH_ROWS {
+            out.write_all(b";\n")?;
+            self.rows_in_batch = 0;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        if self.rows_in_batch > 0 {
+            out.write_all(b";\n")?;
+            self.rows_in_batch = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Writes one JSON object per input row describing its decision, e.g.
+/// `{"key":"a","action":"drop","occurrence":2,"source":"-","line":5}`
+pub struct DecisionsFormat;
+
+impl RowFormat for DecisionsFormat {
+    fn write(&mut self, out: &mut dyn Write, decision: &Decision) -> io::Result<()> {
+        out.write_all(b"{\"key\":")?;
+        write_json_string(out, decision.key)?;
+        write!(out, ",\"action\":\"{}\",\"occurrence\":{},\"source\":",
+            if decision.kept { "keep" } else { "drop" }, decision.occurrence)?;
+        write_json_string(out, decision.source.as_bytes())?;
+        writeln!(out, ",\"line\":{}}}", decision.line_number)
+    }
+}
+
+/// Quotes a value as a JSON string. Invalid UTF-8 is replaced with U+FFFD.
+pub fn write_json_string(out: &mut dyn Write, value: &[u8]) -> io::Result<()> {
+    out.write_all(b"\"")?;
+    for c in String::from_utf8_lossy(value).chars() {
+        match c {
+            '"' => out.write_all(b"\\\"")?,
+            '\\' => out.write_all(b"\\\\")?,
+            '\n' => out.write_all(b"\\n")?,
+            '\r' => out.write_all(b"\\r")?,
+            '\t' => out.write_all(b"\\t")?,
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+            c => write!(out, "{}", c)?,
+        }
+    }
+    out.write_all(b"\"")
+}
+
+/// Quotes a value as an SQL string literal, doubling embedded quotes. MySQL
+/// also treats backslashes as escapes, so for it they are doubled too and NULs
+/// written as `\0`; standard SQL (and PostgreSQL, with its default
+/// standard_conforming_strings) takes every other byte as-is.
+fn write_sql_string(out: &mut dyn Write, value: &[u8], dialect: SqlDialect) -> io::Result<()> {
+    out.write_all(b"'")?;
+    for part in value.split_inclusive(|&b| b == b'\'' || b == b'\\' || b == 0) {
+        let (&last, rest) = part.split_last().unwrap();
+        match (last, dialect) {
+            (b'\'', _) => {
+                out.write_all(rest)?;
+                out.write_all(b"''")?;
+            }
+            (b'\\', SqlDialect::Mysql) => {
+                out.write_all(rest)?;
+                out.write_all(br"\\")?;
+            }
+            (0, SqlDialect::Mysql) => {
+                out.write_all(rest)?;
+                out.write_all(br"\0")?;
+            }
+            _ => out.write_all(part)?,
+        }
+    }
+    out.write_all(b"'")
+}
+
+/// Writes each dot-separated part of a table name as-is if it is a plain
+/// identifier, otherwise quoted as one for the dialect, doubling the quote
+/// character within it
+fn write_sql_name(out: &mut dyn Write, name: &str, dialect: SqlDialect) -> io::Result<()> {
+    let quote = match dialect {
+        SqlDialect::Postgres => '"',
+        SqlDialect::Mysql => '`',
+    };
+    for (i, part) in name.split('.').enumerate() {
+        if i > 0 {
+            out.write_all(b".")?;
+        }
+        if is_plain_name(part) {
+            out.write_all(part.as_bytes())?;
+        }
+        else {
+            write!(out, "{}{}{}", quote, part.replace(quote, &quote.to_string().repeat(2)), quote)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether `name` is a valid unquoted identifier: `[A-Za-z_][A-Za-z0-9_]*`
+fn is_plain_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Strips a trailing LF or CRLF from the line
+pub fn chomp(line: &[u8]) -> &[u8] {
+    match line.strip_suffix(b"\n") {
+        Some(line) => line.strip_suffix(b"\r").unwrap_or(line),
+        None => line,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sql(table: &str, dialect: SqlDialect, line: &[u8]) -> String {
+        let mut format = SqlFormat::new(table, Regex::new(r"\t").unwrap()).dialect(dialect);
+        let mut out = vec![];
+        format.write(&mut out, &Decision {
+            line, key: b"a", kept: true, occurrence: 1, count: None, source: "-", line_number: 1,
+        }).unwrap();
+        format.finish(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn sql_plain_table_is_unquoted() {
+        assert_eq!(sql("public.rows_2", SqlDialect::Postgres, b"a\n"), "INSERT INTO public.rows_2 VALUES\n('a');\n");
+        assert_eq!(sql("public.rows_2", SqlDialect::Mysql, b"a\n"), "INSERT INTO public.rows_2 VALUES\n('a');\n");
+    }
+
+    #[test]
+    fn sql_other_table_is_quoted() {
+        assert_eq!(sql("rows\"; DROP TABLE users; --", SqlDialect::Postgres, b"a\n"),
+            "INSERT INTO \"rows\"\"; DROP TABLE users; --\" VALUES\n('a');\n");
+        assert_eq!(sql("my`rows.2x", SqlDialect::Mysql, b"a\n"), "INSERT INTO `my``rows`.`2x` VALUES\n('a');\n");
+    }
+
+    fn sql_string(value: &[u8], dialect: SqlDialect) -> String {
+        let mut out = vec![];
+        write_sql_string(&mut out, value, dialect).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn sql_strings_are_escaped() {
+        let value = b"it's\ta\\\nb\\";
+        assert_eq!(sql_string(value, SqlDialect::Postgres), "'it''s\ta\\\nb\\'");
+        assert_eq!(sql_string(value, SqlDialect::Mysql), "'it''s\ta\\\\\nb\\\\'");
+        assert_eq!(sql_string(br"\'", SqlDialect::Mysql), r"'\\'''");
+        assert_eq!(sql_string(b"a\0b", SqlDialect::Mysql), r"'a\0b'");
+        assert_eq!(sql_string(b"", SqlDialect::Mysql), "''");
+    }
+
+    #[test]
+    fn sql_rows_are_escaped() {
+        assert_eq!(sql("t", SqlDialect::Mysql, b"x\\\ty'); DROP TABLE t; --\n"),
+            "INSERT INTO t VALUES\n('x\\\\', 'y''); DROP TABLE t; --');\n");
+    }
+}
@@ -0,0 +1,94 @@
+use std::str;
+use std::str::FromStr;
+
+/// Numeric ranges that a field's values are bucketed into, for --partition-by
+#[derive(Debug, Clone, PartialEq)]
+pub struct Partitions {
+    /// 0-indexed column number
+    pub field: usize,
+    ranges: Vec<Range>,
+}
+
+/// Values from `low` up to but not including `high`, named by their spec
+#[derive(Debug, Clone, PartialEq)]
+struct Range {
+    low: f64,
+    high: f64,
+    label: String,
+}
+
+impl FromStr for Partitions {
+    type Err = String;
+
+    /// FIELD:RANGE,RANGE,... where FIELD is 1-indexed and each RANGE is
+    /// LOW-HIGH, or LOW- for no upper bound, e.g. `2:0-100,100-1000,1000-`
+    fn from_str(s: &str) -> Result<Partitions, String> {
+        let (field, ranges) = match s.find(':') {
+            Some(colon) => (&s[..colon], &s[colon + 1..]),
+            None => return Err(format!("expected FIELD:RANGES in '{}'", s)),
+        };
+        let field = match field.parse::<usize>() {
+            Ok(field) if field > 0 => field - 1,
+            _ => return Err(format!("invalid field '{}'", field)),
+        };
+        let ranges = ranges.split(',').map(parse_range).collect::<Result<Vec<_>, _>>()?;
+        Ok(Partitions { field, ranges })
+    }
+}
+
+fn parse_range(spec: &str) -> Result<Range, String> {
+    let invalid = || format!("invalid range '{}' (expected LOW-HIGH or LOW-)", spec);
+    // The first character may be a minus sign rather than the separator
+    let dash = spec.char_indices().skip(1).find(|&(_, c)| c == '-').map(|(i, _)| i).ok_or_else(invalid)?;
+    let low : f64 = spec[..dash].parse().map_err(|_| invalid())?;
+    let high : f64 = match &spec[dash + 1..] {
+        "" => f64::INFINITY,
+        high => high.parse().map_err(|_| invalid())?,
+    };
+    if low.is_nan() || high.is_nan() || low >= high {
+        return Err(invalid());
+    }
+    Ok(Range { low, high, label: spec.to_owned() })
+}
+
+impl Partitions {
+    /// The label of the first range holding `value`, if it is a number in any
+    pub fn label(&self, value: &[u8]) -> Option<&str> {
+        let value : f64 = str::from_utf8(value).ok()?.trim().parse().ok()?;
+        self.ranges.iter().find(|range| range.low <= value && value < range.high).map(|range| range.label.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_include_low_and_exclude_high() {
+        let partitions : Partitions = "2:-10-0,0-100,100-".parse().unwrap();
+        assert_eq!(partitions.field, 1);
+        assert_eq!(partitions.label(b"-10"), Some("-10-0"));
+        assert_eq!(partitions.label(b"-0.5"), Some("-10-0"));
+        assert_eq!(partitions.label(b"0"), Some("0-100"));
+        assert_eq!(partitions.label(b"99.999"), Some("0-100"));
+        assert_eq!(partitions.label(b" 100 "), Some("100-"));
+        assert_eq!(partitions.label(b"1e300"), Some("100-"));
+        assert_eq!(partitions.label(b"-10.01"), None);
+        assert_eq!(partitions.label(b"abc"), None);
+        assert_eq!(partitions.label(b""), None);
+    }
+
+    #[test]
+    fn overlapping_ranges_use_the_first() {
+        let partitions : Partitions = "1:0-10,5-20".parse().unwrap();
+        assert_eq!(partitions.label(b"7"), Some("0-10"));
+        assert_eq!(partitions.label(b"10"), Some("5-20"));
+    }
+
+    #[test]
+    fn rejects_bad_specs() {
+        for spec in ["0-10", "0:0-10", "x:0-10", "1:10-0", "1:5-5", "1:-", "1:abc-1", "1:0-10,", "1:nan-1"] {
+            assert!(spec.parse::<Partitions>().is_err(), "{}", spec);
+        }
+    }
+}
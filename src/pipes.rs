@@ -0,0 +1,116 @@
+use std::io;
+use std::io::Write;
+use std::process::{Child, Command, Stdio};
+
+use output::{Decision, RowFormat};
+
+/// Writes each kept row to the standard input of a command run for its key:
+/// `{key}` in the command is replaced by the key, shell-quoted, and the
+/// command is run by `sh -c`. At most `jobs` commands are open at once; when
+/// another is needed the least recently used is closed, so with unsorted input
+/// a key's rows may be split over several runs of its command.
+pub struct PipeFormat {
+    command: String,
+    jobs: usize,
+    /// Open commands, least recently used first
+    open: Vec<(Vec<u8>, Child)>,
+}
+
+impl PipeFormat {
+    pub fn new(command: &str, jobs: usize) -> PipeFormat {
+        PipeFormat { command: command.to_owned(), jobs, open: vec![] }
+    }
+
+    fn spawn(&self, key: &[u8]) -> io::Result<Child> {
+        let command = self.command.replace("{key}", &shell_quote(&String::from_utf8_lossy(key)));
+        Command::new("sh").arg("-c").arg(command).stdin(Stdio::piped()).spawn()
+    }
+}
+
+impl RowFormat for PipeFormat {
+    fn write(&mut self, _out: &mut dyn Write, decision: &Decision) -> io::Result<()> {
+        if !decision.kept {
+            return Ok(());
+        }
+        let position = self.open.iter().position(|(key, _)| key.as_slice() == decision.key);
+        let (key, mut child) = match position {
+            Some(i) => self.open.remove(i),
+            None => {
+                if self.open.len() >= self.jobs {
+                    let (key, child) = self.open.remove(0);
+                    close(&key, child)?;
+                }
+                (decision.key.to_owned(), self.spawn(decision.key)?)
+            }
+        };
+        match child.stdin.as_mut().unwrap().write_all(decision.line) {
+            Ok(()) => self.open.push((key, child)),
+            // A command may stop reading early; that is only an error if it fails
+            Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => close(&key, child)?,
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+        for (key, child) in self.open.drain(..) {
+            close(&key, child)?;
+        }
+        Ok(())
+    }
+}
+
+/// Ends a command's input and waits for it, failing if it did
+fn close(key: &[u8], mut child: Child) -> io::Result<()> {
+    drop(child.stdin.take());
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("--pipe-per-key command for '{}' failed: {}",
+            String::from_utf8_lossy(key), status)));
+    }
+    Ok(())
+}
+
+/// Quotes `value` as a single word for sh
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn kept<'a>(line: &'a [u8], key: &'a [u8]) -> Decision<'a> {
+        Decision { line, key, kept: true, occurrence: 1, count: None, source: "-", line_number: 1 }
+    }
+
+    #[test]
+    fn pipes_rows_to_each_key_command() {
+        let dir = env::temp_dir().join(format!("tsvfirst-pipes-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        // With one job at a time, a's command is closed and rerun
+        let mut format = PipeFormat::new(&format!("cat >> {}/{{key}}", dir.display()), 1);
+        let mut out = vec![];
+        format.write(&mut out, &kept(b"a\t1\n", b"a")).unwrap();
+        format.write(&mut out, &kept(b"it's\t2\n", b"it's")).unwrap();
+        format.write(&mut out, &Decision { kept: false, ..kept(b"a\t3\n", b"a") }).unwrap();
+        format.write(&mut out, &kept(b"a\t4\n", b"a")).unwrap();
+        format.finish(&mut out).unwrap();
+        assert!(out.is_empty());
+        assert_eq!(fs::read(dir.join("a")).unwrap(), b"a\t1\na\t4\n");
+        assert_eq!(fs::read(dir.join("it's")).unwrap(), b"it's\t2\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fails_if_a_command_fails() {
+        let mut format = PipeFormat::new("cat > /dev/null; exit 3", 4);
+        format.write(&mut vec![], &kept(b"a\n", b"a")).unwrap();
+        let error = format.finish(&mut vec![]).unwrap_err();
+        assert!(error.to_string().starts_with("--pipe-per-key command for 'a' failed"));
+    }
+}
@@ -0,0 +1,66 @@
+use std::io;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// Stages of processing that time is attributed to
+#[derive(Debug, Clone, Copy)]
+pub enum Phase {
+    /// Opening inputs, reading lines and validating them
+    Read,
+    /// Splitting rows and building keys
+    Key,
+    /// Seen-set lookups and inserts
+    Dedup,
+    /// Formatting and writing output
+    Write,
+}
+
+const PHASES: [(Phase, &str); 4] = [
+    (Phase::Read, "read"),
+    (Phase::Key, "split/key"),
+    (Phase::Dedup, "dedup"),
+    (Phase::Write, "write"),
+];
+
+/// Accumulates wall-clock time per phase. When disabled, marks cost nothing.
+pub struct Profiler {
+    last: Option<Instant>,
+    totals: [Duration; 4],
+}
+
+impl Profiler {
+    pub fn new(enabled: bool) -> Profiler {
+        Profiler {
+            last: if enabled { Some(Instant::now()) } else { None },
+            totals: [Duration::default(); 4],
+        }
+    }
+
+    /// Attributes the time since the previous mark to `phase`
+    pub fn mark(&mut self, phase: Phase) {
+        if let Some(last) = self.last {
+            let now = Instant::now();
+            self.totals[phase as usize] += now - last;
+            self.last = Some(now);
+        }
+    }
+
+    pub fn report(&self, out: &mut dyn Write) -> io::Result<()> {
+        if self.last.is_none() {
+            return Ok(());
+        }
+        let total : Duration = self.totals.iter().sum();
+        writeln!(out, "tsvfirst profile:")?;
+        for &(phase, name) in &PHASES {
+            let spent = self.totals[phase as usize];
+            let percent = if total.as_secs_f64() > 0.0 {
+                100.0 * spent.as_secs_f64() / total.as_secs_f64()
+            }
+            else {
+                0.0
+            };
+            writeln!(out, "  {:<10} {:>10.3}s {:>6.1}%", name, spent.as_secs_f64(), percent)?;
+        }
+        writeln!(out, "  {:<10} {:>10.3}s", "total", total.as_secs_f64())
+    }
+}
@@ -0,0 +1,141 @@
+use std::io;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// How often the progress line is redrawn
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Rows between clock checks, so that `read` stays cheap
+const ROWS_PER_CHECK: u64 = 1024;
+
+/// Weight of the latest interval in the moving-average throughput
+const SMOOTHING: f64 = 0.3;
+
+/// Reports rows and bytes processed to stderr as the input is read. This
+/// doesn't need input sizes, so works on pipes; expected totals, if given,
+/// add a percentage and ETA. When disabled, reads cost nothing.
+pub struct Progress {
+    state: Option<State>,
+}
+
+struct State {
+    expected_rows: Option<u64>,
+    expected_bytes: Option<u64>,
+    rows: u64,
+    bytes: u64,
+    /// Counts and time at the previous redraw
+    last: (u64, u64, Instant),
+    /// Smoothed rows/s and bytes/s
+    rates: Option<(f64, f64)>,
+    unchecked_rows: u64,
+}
+
+impl Progress {
+    pub fn new(enabled: bool, expected_rows: Option<u64>, expected_bytes: Option<u64>) -> Progress {
+        let state = if enabled {
+            Some(State {
+                expected_rows,
+                expected_bytes,
+                rows: 0,
+                bytes: 0,
+                last: (0, 0, Instant::now()),
+                rates: None,
+                unchecked_rows: 0,
+            })
+        }
+        else {
+            None
+        };
+        Progress { state }
+    }
+
+    /// Records `rows` more rows and `bytes` more bytes read, redrawing the
+    /// progress line if it is due
+    pub fn read(&mut self, rows: u64, bytes: u64) -> io::Result<()> {
+        if let Some(ref mut state) = self.state {
+            state.rows += rows;
+            state.bytes += bytes;
+            state.unchecked_rows += rows;
+            if state.unchecked_rows >= ROWS_PER_CHECK {
+                state.unchecked_rows = 0;
+                if state.last.2.elapsed() >= INTERVAL {
+                    state.update_rates();
+                    state.draw(&mut io::stderr())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Draws the final counts and ends the progress line
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(ref mut state) = self.state {
+            state.update_rates();
+            let mut stderr = io::stderr();
+            state.draw(&mut stderr)?;
+            writeln!(stderr)?;
+        }
+        Ok(())
+    }
+}
+
+impl State {
+    fn update_rates(&mut self) {
+        let (last_rows, last_bytes, last_time) = self.last;
+        let now = Instant::now();
+        let seconds = (now - last_time).as_secs_f64();
+        if seconds <= 0.0 {
+            return;
+        }
+        let rows = (self.rows - last_rows) as f64 / seconds;
+        let bytes = (self.bytes - last_bytes) as f64 / seconds;
+        self.rates = Some(match self.rates {
+            None => (rows, bytes),
+            Some((avg_rows, avg_bytes)) => (
+                avg_rows + SMOOTHING * (rows - avg_rows),
+                avg_bytes + SMOOTHING * (bytes - avg_bytes),
+            ),
+        });
+        self.last = (self.rows, self.bytes, now);
+    }
+
+    fn draw(&self, out: &mut dyn Write) -> io::Result<()> {
+        let (row_rate, byte_rate) = self.rates.unwrap_or((0.0, 0.0));
+        write!(out, "\rtsvfirst: {} rows, {}B ({} rows/s, {}B/s)",
+            scaled(self.rows as f64), scaled(self.bytes as f64), scaled(row_rate), scaled(byte_rate))?;
+
+        // Prefer bytes for the estimate: row lengths vary less than row counts
+        let estimate = match (self.expected_bytes, self.expected_rows) {
+            (Some(expected), _) => Some((self.bytes, expected, byte_rate)),
+            (None, Some(expected)) => Some((self.rows, expected, row_rate)),
+            (None, None) => None,
+        };
+        if let Some((done, expected, rate)) = estimate {
+            let percent = 100.0 * done as f64 / expected.max(1) as f64;
+            write!(out, " {:.0}%", percent.min(100.0))?;
+            if rate > 0.0 && done < expected {
+                let seconds = ((expected - done) as f64 / rate) as u64;
+                write!(out, " ETA {}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)?;
+            }
+        }
+        // Clear the remains of a longer previous line
+        write!(out, "\x1b[K")?;
+        out.flush()
+    }
+}
+
+/// Formats a count with a K, M or G (decimal) suffix
+fn scaled(n: f64) -> String {
+    if n >= 1e9 {
+        format!("{:.1}G", n / 1e9)
+    }
+    else if n >= 1e6 {
+        format!("{:.1}M", n / 1e6)
+    }
+    else if n >= 1e3 {
+        format!("{:.1}K", n / 1e3)
+    }
+    else {
+        format!("{:.0}", n)
+    }
+}
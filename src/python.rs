@@ -0,0 +1,93 @@
+//! Python module `pytsvfirst`, enabled by the `python` feature. Build the
+//! extension with `cargo rustc --lib --release --features python --crate-type cdylib`
+//! and install the library as `pytsvfirst.so` (`pytsvfirst.pyd` on Windows).
+//!
+//! ```python
+//! import pytsvfirst
+//! d = pytsvfirst.Deduplicator(fields="1,3i")
+//! for chunk in chunks:
+//!     for record in d.feed(chunk):
+//!         ...
+//! rest = d.finish()
+//! kept = pytsvfirst.dedup_files(["a.tsv", "b.tsv"], fields="2")
+//! ```
+
+// The #[pymethods] expansion trips this lint on PyResult return types
+#![allow(clippy::useless_conversion)]
+
+use std::error;
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use config::Config;
+use engine;
+use key::parse_field_spec;
+use tsvfirst::run;
+
+fn config(fields: &str, sorted: bool, whitespace: bool) -> PyResult<Config> {
+    let fields = parse_field_spec(fields).map_err(value_error)?;
+    Ok(Config::new().fields(&fields).sorted(sorted).whitespace(whitespace))
+}
+
+fn value_error(err: Box<dyn error::Error>) -> PyErr {
+    PyValueError::new_err(err.to_string())
+}
+
+/// Splits `kept` output into one bytes object per record
+fn records<'py>(py: Python<'py>, kept: &[u8]) -> Vec<Bound<'py, PyBytes>> {
+    kept.split_inclusive(|&b| b == b'\n').map(|record| PyBytes::new_bound(py, record)).collect()
+}
+
+/// Incremental deduplicator: feed it chunks of bytes and it returns the kept
+/// records each chunk completes
+#[pyclass]
+struct Deduplicator {
+    inner: engine::Deduplicator,
+}
+
+#[pymethods]
+impl Deduplicator {
+    #[new]
+    #[pyo3(signature = (fields="1", sorted=false, whitespace=false))]
+    fn new(fields: &str, sorted: bool, whitespace: bool) -> PyResult<Deduplicator> {
+        let config = config(fields, sorted, whitespace)?;
+        let inner = engine::Deduplicator::new(&config).map_err(value_error)?;
+        Ok(Deduplicator { inner })
+    }
+
+    /// Returns the kept records (with their newlines) completed by `data`
+    fn feed<'py>(&mut self, py: Python<'py>, data: &[u8]) -> PyResult<Vec<Bound<'py, PyBytes>>> {
+        let mut kept = vec![];
+        self.inner.feed(data, &mut kept).map_err(value_error)?;
+        Ok(records(py, &kept))
+    }
+
+    /// Returns a final record that lacked a trailing newline, if kept
+    fn finish<'py>(&mut self, py: Python<'py>) -> PyResult<Vec<Bound<'py, PyBytes>>> {
+        let mut kept = vec![];
+        self.inner.finish(&mut kept).map_err(value_error)?;
+        Ok(records(py, &kept))
+    }
+}
+
+/// Deduplicates the named files (or "-" for stdin) as the command line tool
+/// would, returning the kept rows as one bytes object
+#[pyfunction]
+#[pyo3(signature = (paths, fields="1", sorted=false, whitespace=false))]
+fn dedup_files<'py>(py: Python<'py>, paths: Vec<String>, fields: &str, sorted: bool, whitespace: bool)
+                    -> PyResult<Bound<'py, PyBytes>> {
+    let config = paths.iter().fold(config(fields, sorted, whitespace)?, |config, path| config.add_input(path));
+    let mut kept = vec![];
+    py.allow_threads(|| run(&config, &mut kept).map_err(|err| err.to_string()))
+        .map_err(PyValueError::new_err)?;
+    Ok(PyBytes::new_bound(py, &kept))
+}
+
+#[pymodule]
+fn pytsvfirst(module: &Bound<PyModule>) -> PyResult<()> {
+    module.add_class::<Deduplicator>()?;
+    module.add_function(wrap_pyfunction!(self::dedup_files, module)?)?;
+    Ok(())
+}
@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+use std::mem;
+
+use xxhash_rust::xxh3::xxh3_64_with_seed;
+
+/// Rough hash table overhead per key beyond the key and entry themselves
+const SLOT_OVERHEAD: usize = 16;
+
+/// What is known about a key after recording an occurrence of it
+#[derive(Debug, Clone, Copy)]
+pub struct Entry {
+    /// Occurrence number of the latest row (1 for the first time it is seen)
+    pub count: u64,
+    /// Sequential number of the key in order of first appearance, from 1
+    pub id: u64,
+    /// Index of the input the key was first seen in
+    pub source: usize,
+}
+
+/// Tracks how many times each key has been seen
+pub struct Seen {
+    sorted: bool,
+    // Count occurrences of previously seen values (if sorted not set)
+    counts: HashMap<Vec<u8>, Entry>,
+    // Replaces `counts` once the memory limit is passed: keyed by key hash
+    hashes: Option<HashMap<u64, Entry>>,
+    // Estimated bytes used by `counts` or `hashes`
+    bytes: usize,
+    memory_limit: Option<usize>,
+    // Whether to switch to `hashes` at the memory limit rather than fail
+    adaptive: bool,
+    hash_seed: u64,
+    // With sorted input only the current run of equal keys matters
+    last: Option<(Vec<u8>, Entry)>,
+    // Input declared grouped: counts only cover the current group
+    group: Option<Vec<u8>>,
+    next_id: u64,
+    source: usize,
+}
+
+impl Seen {
+    pub fn new(sorted: bool) -> Seen {
+        Seen {
+            sorted,
+            counts: HashMap::new(),
+            hashes: None,
+            bytes: 0,
+            memory_limit: None,
+            adaptive: true,
+            hash_seed: 0,
+            last: None,
+            group: None,
+            next_id: 1,
+            source: 0,
+        }
+    }
+
+    /// Sets a soft limit on the memory used to remember keys. When it is
+    /// passed, `check_memory` either switches to remembering 64-bit key hashes
+    /// (if `adaptive`) or fails.
+    pub fn memory_limit(mut self, limit: Option<usize>, adaptive: bool) -> Seen {
+        self.memory_limit = limit;
+        self.adaptive = adaptive;
+        self
+    }
+
+    /// Salts the key hashes used past the memory limit
+    pub fn hash_seed(mut self, seed: u64) -> Seen {
+        self.hash_seed = seed;
+        self
+    }
+
+    /// Declares the index of the input that following keys are read from
+    pub fn start_source(&mut self, source: usize) {
+        self.source = source;
+    }
+
+    /// Declares the group the next key belongs to. For input grouped by a
+    /// subset of the key fields, keys can't recur once their group ends, so
+    /// the counts are reset whenever the group changes.
+    pub fn start_group(&mut self, group: &[u8]) {
+        if self.group.as_ref().is_some_and(|current| current.as_slice() == group) {
+            return;
+        }
+        self.counts.clear();
+        if let Some(ref mut hashes) = self.hashes {
+            hashes.clear();
+        }
+        self.bytes = 0;
+        self.group = Some(group.to_owned());
+    }
+
+    /// Records an occurrence of `key`, returning its occurrence number (1 for
+    /// the first time it is seen)
+    pub fn observe(&mut self, key: &[u8]) -> u64 {
+        self.observe_entry(key).count
+    }
+
+    /// Records an occurrence of `key`, returning its updated entry
+    pub fn observe_entry(&mut self, key: &[u8]) -> Entry {
+        if self.sorted {
+            // Compare against previous value
+            if let Some((ref last_key, ref mut entry)) = self.last {
+                if *last_key == key {
+                    entry.count += 1;
+                    return *entry;
+                }
+            }
+            let entry = self.new_entry();
+            self.last = Some((key.to_owned(), entry));
+            entry
+        }
+        else if self.hashes.is_some() {
+            let hash = xxh3_64_with_seed(key, self.hash_seed);
+            if let Some(entry) = self.hashes.as_mut().unwrap().get_mut(&hash) {
+                entry.count += 1;
+                return *entry;
+            }
+            let entry = self.new_entry();
+            self.hashes.as_mut().unwrap().insert(hash, entry);
+            self.bytes += hashed_size();
+            entry
+        }
+        else {
+            if let Some(entry) = self.counts.get_mut(key) {
+                entry.count += 1;
+                return *entry;
+            }
+            let entry = self.new_entry();
+            self.counts.insert(key.to_owned(), entry);
+            self.bytes += exact_size(key);
+            entry
+        }
+    }
+
+    /// Remembers `key` as seen `count` times already, e.g. by an earlier run
+    pub fn restore(&mut self, key: &[u8], count: u64) {
+        let entry = Entry { count, ..self.new_entry() };
+        self.counts.insert(key.to_owned(), entry);
+        self.bytes += exact_size(key);
+    }
+
+    /// Each key remembered and how many times it was seen, unless only their
+    /// hashes (or, for sorted input, the latest key) are remembered
+    pub fn counts(&self) -> Option<Vec<(Vec<u8>, u64)>> {
+        if self.sorted || self.hashes.is_some() {
+            return None;
+        }
+        Some(self.counts.iter().map(|(key, entry)| (key.clone(), entry.count)).collect())
+    }
+
+    /// Enforces the memory limit, if any, after keys have been observed
+    pub fn check_memory(&mut self) -> Result<(), String> {
+        let limit = match self.memory_limit {
+            Some(limit) if self.bytes > limit => limit,
+            _ => return Ok(()),
+        };
+        if !self.adaptive || self.hashes.is_some() {
+            let how = if self.hashes.is_some() { " even with hashed keys" } else { "" };
+            return Err(format!("remembered keys need more than --memory-limit {} bytes{}", limit, how));
+        }
+
+        // Distinct keys may now collide, with a tiny (about 1 in 2^64 per pair) chance
+        let counts = mem::take(&mut self.counts);
+        let seed = self.hash_seed;
+        let hashes : HashMap<u64, Entry> = counts.into_iter()
+            .map(|(key, entry)| (xxh3_64_with_seed(&key, seed), entry))
+            .collect();
+        self.bytes = hashes.len() * hashed_size();
+        self.hashes = Some(hashes);
+        // Not fatal if stderr is gone
+        let _ = writeln!(io::stderr(),
+            "tsvfirst: keys passed --memory-limit {} bytes; switching to 64-bit key hashes", limit);
+        Ok(())
+    }
+
+    fn new_entry(&mut self) -> Entry {
+        let entry = Entry { count: 1, id: self.next_id, source: self.source };
+        self.next_id += 1;
+        entry
+    }
+}
+
+fn exact_size(key: &[u8]) -> usize {
+    key.len() + mem::size_of::<Vec<u8>>() + mem::size_of::<Entry>() + SLOT_OVERHEAD
+}
+
+fn hashed_size() -> usize {
+    mem::size_of::<u64>() + mem::size_of::<Entry>() + SLOT_OVERHEAD
+}
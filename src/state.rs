@@ -0,0 +1,208 @@
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+
+use atomic::AtomicFile;
+use compress::Format;
+use config::Input;
+
+/// First field of a state file's first line
+const MAGIC: &str = "tsvfirst-state";
+
+/// How far runs on an input have got, saved by --state so that the next run
+/// only reads rows added since
+pub struct State {
+    /// The input the state belongs to
+    pub input: String,
+    /// The end of the rows read so far
+    pub offset: u64,
+    /// The length of the output once the run finished
+    pub output_length: u64,
+    /// Each key seen and how many times
+    pub keys: Vec<(Vec<u8>, u64)>,
+}
+
+impl State {
+    /// Reads the state at `path`. If there is none, it is that of a first run
+    /// on `input`; otherwise it must belong to `input`.
+    pub fn load(path: &str, input: &str) -> io::Result<State> {
+        let text = match fs::read(path) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(State { input: input.to_owned(), offset: 0, output_length: 0, keys: vec![] });
+            }
+            Err(e) => return Err(e),
+        };
+        let mut lines = text.split(|&b| b == b'\n');
+        let (offset, output_length, saved_input) = parse_header(lines.next().unwrap())
+            .ok_or_else(|| invalid(format!("{}: not a --state file", path)))?;
+        if saved_input != input {
+            return Err(invalid(format!("{}: saved for input {}, not {}", path, saved_input, input)));
+        }
+        let mut keys = vec![];
+        for line in lines.filter(|line| !line.is_empty()) {
+            let tab = line.iter().position(|&b| b == b'\t');
+            let count = tab.and_then(|tab| String::from_utf8_lossy(&line[..tab]).parse::<u64>().ok())
+                .ok_or_else(|| invalid(format!("{}: invalid key count", path)))?;
+            keys.push((line[tab.unwrap() + 1..].to_owned(), count));
+        }
+        Ok(State { input: input.to_owned(), offset, output_length, keys })
+    }
+
+    /// Replaces the state at `path`, which is never left half-written
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut file = AtomicFile::create(path)?;
+        writeln!(file, "{}\t{}\t{}\t{}", MAGIC, self.offset, self.output_length, self.input)?;
+        for &(ref key, count) in &self.keys {
+            if key.contains(&b'\n') {
+                return Err(invalid("keys with newlines can't be saved with --state".to_owned()));
+            }
+            write!(file, "{}\t", count)?;
+            file.write_all(key)?;
+            file.write_all(b"\n")?;
+        }
+        file.commit()
+    }
+}
+
+/// The input offset, output length and input name of a state file's first line
+fn parse_header(line: &[u8]) -> Option<(u64, u64, String)> {
+    let line = String::from_utf8_lossy(line);
+    let mut fields = line.splitn(4, '\t');
+    if fields.next() != Some(MAGIC) {
+        return None;
+    }
+    let offset = fields.next()?.parse().ok()?;
+    let output_length = fields.next()?.parse().ok()?;
+    Some((offset, output_length, fields.next()?.to_owned()))
+}
+
+/// The output length saved at `path`, reading no more than the first line, or
+/// `None` if there's no state yet
+pub fn output_length(path: &str) -> io::Result<Option<u64>> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut header = vec![];
+    BufReader::new(file).read_until(b'\n', &mut header)?;
+    match parse_header(header.strip_suffix(b"\n").unwrap_or(&header)) {
+        Some((_, output_length, _)) => Ok(Some(output_length)),
+        None => Err(invalid(format!("{}: not a --state file", path))),
+    }
+}
+
+/// Opens the input file `path` from `offset` up to the end of its last
+/// complete line, as a line without a newline may still be being written.
+/// Returns the input and the offset it ends at.
+pub fn open_from(path: &str, offset: u64) -> io::Result<(Input, u64)> {
+    let mut file = fs::File::open(path)?;
+    let mut head = vec![];
+    (&mut file).take(10).read_to_end(&mut head)?;
+    if let Some(format) = Format::sniff(&head) {
+        return Err(invalid(format!("{}: {} input can't be read from a --state offset", path, format.name())));
+    }
+    let length = file.metadata()?.len();
+    if length < offset {
+        return Err(invalid(format!("{}: shorter than when --state was saved", path)));
+    }
+    let end = last_line_end(&mut file, offset, length)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let input = Input { name: path.to_owned(), reader: Box::new(BufReader::new(file.take(end - offset))) };
+    Ok((input, end))
+}
+
+/// The offset just past the last newline between `start` and `end`, or
+/// `start` if there is none
+fn last_line_end(file: &mut fs::File, start: u64, end: u64) -> io::Result<u64> {
+    let mut block = vec![0; 64 * 1024];
+    let mut block_end = end;
+    while block_end > start {
+        let block_start = start.max(block_end.saturating_sub(block.len() as u64));
+        let block = &mut block[..(block_end - block_start) as usize];
+        file.seek(SeekFrom::Start(block_start))?;
+        file.read_exact(block)?;
+        if let Some(i) = block.iter().rposition(|&b| b == b'\n') {
+            return Ok(block_start + i as u64 + 1);
+        }
+        block_end = block_start;
+    }
+    Ok(start)
+}
+
+/// Opens the output file `path` to append to, first cutting it back to
+/// `length`, its length when the state was saved, in case a later run failed
+/// partway. With no state yet, the file must be empty or missing.
+pub fn open_output(path: &str, length: Option<u64>) -> io::Result<fs::File> {
+    let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+    let current = file.metadata()?.len();
+    match length {
+        Some(length) if length > current => {
+            return Err(invalid(format!("{}: shorter than when --state was saved", path)));
+        }
+        Some(length) => file.set_len(length)?,
+        None if current > 0 => {
+            return Err(invalid(format!("{}: has no --state to carry on from, so can't be appended to", path)));
+        }
+        None => {}
+    }
+    let mut file = file;
+    file.seek(SeekFrom::End(0))?;
+    Ok(file)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn reads_only_complete_lines_from_offset() {
+        let path = env::temp_dir().join(format!("tsvfirst-state-input-{}", process::id()));
+        fs::write(&path, "a\nb\nc\npartial").unwrap();
+        let path = path.to_str().unwrap();
+        let (mut input, end) = open_from(path, 2).unwrap();
+        let mut rows = vec![];
+        input.reader.read_to_end(&mut rows).unwrap();
+        assert_eq!((rows, end), (b"b\nc\n".to_vec(), 6));
+        assert_eq!(open_from(path, 6).unwrap().1, 6);
+        assert_eq!(open_from(path, 20).err().unwrap().to_string(), format!("{}: shorter than when --state was saved", path));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn output_is_cut_back_to_saved_length() {
+        let path = env::temp_dir().join(format!("tsvfirst-state-output-{}", process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, "a\nb\nleft by a failed run\n").unwrap();
+        assert!(open_output(path, None).is_err());
+        assert!(open_output(path, Some(100)).is_err());
+        open_output(path, Some(4)).unwrap().write_all(b"c\n").unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"a\nb\nc\n");
+        fs::remove_file(path).unwrap();
+        open_output(path, None).unwrap().write_all(b"a\n").unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"a\n");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let path = env::temp_dir().join(format!("tsvfirst-state-{}", process::id()));
+        let path = path.to_str().unwrap();
+        let state = State { input: "in.tsv".into(), offset: 12, output_length: 8, keys: vec![(b"a\tb".to_vec(), 2)] };
+        state.save(path).unwrap();
+        assert_eq!(output_length(path).unwrap(), Some(8));
+        let loaded = State::load(path, "in.tsv").unwrap();
+        assert_eq!((loaded.offset, loaded.output_length, loaded.keys), (12, 8, vec![(b"a\tb".to_vec(), 2)]));
+        assert!(State::load(path, "other.tsv").is_err());
+        fs::remove_file(path).unwrap();
+        assert_eq!(State::load(path, "in.tsv").unwrap().offset, 0);
+        assert_eq!(output_length(path).unwrap(), None);
+    }
+}
@@ -0,0 +1,105 @@
+use std::io;
+use std::io::Write;
+
+/// Row counts for one input
+struct FileStats {
+    name: String,
+    rows: u64,
+    kept: u64,
+    /// Duplicates of a key first seen earlier in the same input
+    dupes_within: u64,
+    /// Duplicates of a key first seen in an earlier input
+    dupes_across: u64,
+    /// Couldn't be opened, so wasn't read
+    skipped: bool,
+}
+
+/// Per-input statistics for --stats-per-file. When disabled, updates cost nothing.
+pub struct Stats {
+    enabled: bool,
+    files: Vec<FileStats>,
+}
+
+impl Stats {
+    pub fn new(enabled: bool) -> Stats {
+        Stats { enabled, files: vec![] }
+    }
+
+    /// Starts counting for the next input, which has the index `files.len()`
+    pub fn start_file(&mut self, name: &str) {
+        if self.enabled {
+            self.files.push(FileStats { name: name.to_owned(), rows: 0, kept: 0, dupes_within: 0, dupes_across: 0, skipped: false });
+        }
+    }
+
+    /// Records that the next input was skipped, as if it had been read empty
+    pub fn skip_file(&mut self, name: &str) {
+        self.start_file(name);
+        if let Some(file) = self.current() {
+            file.skipped = true;
+        }
+    }
+
+    fn current(&mut self) -> Option<&mut FileStats> {
+        if self.enabled { self.files.last_mut() } else { None }
+    }
+
+    pub fn row(&mut self) {
+        if let Some(file) = self.current() {
+            file.rows += 1;
+        }
+    }
+
+    pub fn kept(&mut self) {
+        if let Some(file) = self.current() {
+            file.kept += 1;
+        }
+    }
+
+    /// Records a duplicate of a key first seen in the input with index `first_source`
+    pub fn duplicate(&mut self, first_source: usize) {
+        let current = self.files.len().wrapping_sub(1);
+        if let Some(file) = self.current() {
+            if first_source == current {
+                file.dupes_within += 1;
+            }
+            else {
+                file.dupes_across += 1;
+            }
+        }
+    }
+
+    /// Writes a tab-separated table with a row per input
+    pub fn report(&self, out: &mut dyn Write) -> io::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        writeln!(out, "source\trows\tkept\tdupes_within\tdupes_across\tstatus")?;
+        for file in &self.files {
+            let status = if file.skipped { "skipped" } else { "read" };
+            writeln!(out, "{}\t{}\t{}\t{}\t{}\t{}", file.name, file.rows, file.kept, file.dupes_within, file.dupes_across, status)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_skipped_inputs() {
+        let mut stats = Stats::new(true);
+        stats.start_file("a.tsv");
+        stats.row();
+        stats.kept();
+        stats.skip_file("missing.tsv");
+        stats.start_file("b.tsv");
+        stats.row();
+        stats.duplicate(0);
+        let mut out = vec![];
+        stats.report(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "source\trows\tkept\tdupes_within\tdupes_across\tstatus\n\
+            a.tsv\t1\t1\t0\t0\tread\nmissing.tsv\t0\t0\t0\t0\tskipped\nb.tsv\t1\t0\t0\t1\tread\n");
+    }
+}
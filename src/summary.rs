@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::{BufWriter, Write};
+
+/// Writes each key and the number of rows with it, `key<TAB>count`, in order
+/// of the key's first appearance. With sorted input each key is written as
+/// soon as it ends; otherwise all counts are held until `finish`.
+pub struct Summary {
+    out: BufWriter<fs::File>,
+    sorted: bool,
+    /// Position of each key in `counts`
+    index: HashMap<Vec<u8>, usize>,
+    counts: Vec<(Vec<u8>, u64)>,
+}
+
+impl Summary {
+    /// Creates the file up front, so a bad path fails before any work
+    pub fn create(path: &str, sorted: bool) -> io::Result<Summary> {
+        Ok(Summary { out: BufWriter::new(fs::File::create(path)?), sorted, index: HashMap::new(), counts: vec![] })
+    }
+
+    pub fn record(&mut self, key: &[u8]) -> io::Result<()> {
+        if self.sorted {
+            match self.counts.last_mut() {
+                Some(&mut (ref last, ref mut count)) if last.as_slice() == key => *count += 1,
+                _ => {
+                    self.write_counts()?;
+                    self.counts.push((key.to_owned(), 1));
+                }
+            }
+            return Ok(());
+        }
+        match self.index.get(key) {
+            Some(&i) => self.counts[i].1 += 1,
+            None => {
+                self.index.insert(key.to_owned(), self.counts.len());
+                self.counts.push((key.to_owned(), 1));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.write_counts()?;
+        self.index.clear();
+        self.out.flush()
+    }
+
+    fn write_counts(&mut self) -> io::Result<()> {
+        for (key, count) in self.counts.drain(..) {
+            self.out.write_all(&key)?;
+            writeln!(self.out, "\t{}", count)?;
+        }
+        Ok(())
+    }
+}
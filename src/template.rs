@@ -0,0 +1,123 @@
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+
+use regex::bytes::Regex;
+
+use output::{chomp, Decision, RowFormat};
+
+/// One piece of an output template
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(Vec<u8>),
+    /// 0-indexed column number
+    Field(usize),
+    Key,
+    Count,
+    Occurrence,
+    Filename,
+    Line,
+}
+
+/// An output row template such as `{1}\t{3}\t{count}`: placeholders in braces
+/// are replaced by a field (by number) or one of `key`, `count`, `occurrence`,
+/// `filename` and `line`, and `{{`/`}}` stand for literal braces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Template, String> {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest.find('}').ok_or("unclosed '{' in template")?;
+                    let name = &rest[..end];
+                    let part = match name {
+                        "key" => Part::Key,
+                        "count" => Part::Count,
+                        "occurrence" => Part::Occurrence,
+                        "filename" => Part::Filename,
+                        "line" => Part::Line,
+                        _ => match name.parse::<usize>() {
+                            Ok(n) if n > 0 => Part::Field(n - 1),
+                            _ => return Err(format!("unknown template placeholder '{{{}}}'", name)),
+                        },
+                    };
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(literal.into_bytes()));
+                        literal = String::new();
+                    }
+                    parts.push(part);
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => return Err("unmatched '}' in template (use '}}' for a literal brace)".into()),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal.into_bytes()));
+        }
+        Ok(Template { parts })
+    }
+}
+
+impl Template {
+    /// Whether the template needs each key's final row count, which is only
+    /// known once all of the key's rows have been read
+    pub fn uses_count(&self) -> bool {
+        self.parts.contains(&Part::Count)
+    }
+}
+
+/// Writes each kept row through a template, followed by a newline
+pub struct TemplateFormat {
+    template: Template,
+    splitter: Regex,
+}
+
+impl TemplateFormat {
+    pub fn new(template: Template, splitter: Regex) -> TemplateFormat {
+        TemplateFormat { template, splitter }
+    }
+}
+
+impl RowFormat for TemplateFormat {
+    fn write(&mut self, out: &mut dyn Write, decision: &Decision) -> io::Result<()> {
+        if !decision.kept {
+            return Ok(());
+        }
+        let row = chomp(decision.line);
+        let mut fields : Option<Vec<&[u8]>> = None;
+        for part in &self.template.parts {
+            match *part {
+                Part::Literal(ref text) => out.write_all(text)?,
+                Part::Field(i) => {
+                    let fields = fields.get_or_insert_with(|| self.splitter.split(row).collect());
+                    out.write_all(fields.get(i).cloned().unwrap_or(b""))?;
+                }
+                Part::Key => out.write_all(decision.key)?,
+                Part::Count => write!(out, "{}", decision.count.unwrap_or(decision.occurrence))?,
+                Part::Occurrence => write!(out, "{}", decision.occurrence)?,
+                Part::Filename => out.write_all(decision.source.as_bytes())?,
+                Part::Line => write!(out, "{}", decision.line_number)?,
+            }
+        }
+        out.write_all(b"\n")
+    }
+}
@@ -0,0 +1,137 @@
+//! Randomised checks that the different ways of deduplicating agree on the
+//! same input. Built with the `test-util` feature.
+
+use std::error;
+use std::io::Cursor;
+
+use config::{Config, Input};
+use engine::{Deduplicator, SharedDeduplicator};
+use keep::Keep;
+use tsvfirst::run_from;
+
+/// Generates small TSV inputs with many repeated keys, the same for each seed
+pub struct Generator {
+    state: u64,
+}
+
+impl Generator {
+    pub fn new(seed: u64) -> Generator {
+        Generator { state: seed }
+    }
+
+    /// splitmix64
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    /// Up to 200 rows of two or three fields, keyed on the first from a small
+    /// set of keys that share prefixes
+    pub fn input(&mut self) -> Vec<u8> {
+        let keys = 1 + self.below(20);
+        let mut input = vec![];
+        for row in 0..self.below(200) {
+            let key = self.below(keys);
+            let extra = if self.below(2) == 0 { "\tx" } else { "" };
+            input.extend(format!("key{}\t{}{}\n", key * 11, row, extra).into_bytes());
+        }
+        input
+    }
+}
+
+/// Runs every strategy on `input`, failing with a description of the first
+/// whose output differs from a plain run's
+pub fn check(input: &[u8]) -> Result<(), String> {
+    let expected = with_run(&Config::new(), input)?;
+    let strategies = [
+        ("--key-xxhash", Config::new().key_xxhash(true)),
+        ("--memory-limit", Config::new().memory_limit(1000)),
+        ("--keep nth --nth 1", Config::new().keep(Keep::Nth(1))),
+    ];
+    for &(name, ref config) in &strategies {
+        compare(name, input, &expected, with_run(config, input)?)?;
+    }
+    compare("Deduplicator", input, &expected, with_deduplicator(input)?)?;
+    compare("SharedDeduplicator", input, &expected, with_shared(input)?)?;
+
+    // With a stable sort by key, keeping the first row is unaffected
+    let mut rows : Vec<&[u8]> = input.split_inclusive(|&b| b == b'\n').collect();
+    let key = |row: &[u8]| row.split(|&b| b == b'\t').next().map(|key| key.to_owned());
+    rows.sort_by_key(|row| key(row));
+    let sorted = rows.concat();
+    let expected = with_run(&Config::new(), &sorted)?;
+    compare("-s", &sorted, &expected, with_run(&Config::new().sorted(true), &sorted)?)
+}
+
+/// Checks `cases` inputs generated from `seed`
+pub fn check_random(seed: u64, cases: usize) -> Result<(), String> {
+    let mut generator = Generator::new(seed);
+    for _ in 0..cases {
+        check(&generator.input())?;
+    }
+    Ok(())
+}
+
+fn compare(name: &str, input: &[u8], expected: &[u8], actual: Vec<u8>) -> Result<(), String> {
+    if actual == expected {
+        return Ok(());
+    }
+    Err(format!("{} differs from a plain run\ninput:\n{}\nexpected:\n{}\nactual:\n{}", name,
+        String::from_utf8_lossy(input), String::from_utf8_lossy(expected), String::from_utf8_lossy(&actual)))
+}
+
+fn with_run(config: &Config, input: &[u8]) -> Result<Vec<u8>, String> {
+    let input = Input { name: "-".into(), reader: Box::new(Cursor::new(input.to_owned())) };
+    let mut output = vec![];
+    run_from(config, vec![Ok(input)], &mut output).map_err(|e| e.to_string())?;
+    Ok(output)
+}
+
+/// Feeds the input in uneven chunks, so records are split across them
+fn with_deduplicator(input: &[u8]) -> Result<Vec<u8>, String> {
+    let run = || -> Result<Vec<u8>, Box<dyn error::Error>> {
+        let mut dedup = Deduplicator::new(&Config::new())?;
+        let mut output = vec![];
+        for (i, chunk) in input.chunks(7).enumerate() {
+            let (a, b) = chunk.split_at(i % chunk.len().max(1));
+            dedup.feed(a, &mut output)?;
+            dedup.feed(b, &mut output)?;
+        }
+        dedup.finish(&mut output)?;
+        Ok(output)
+    };
+    run().map_err(|e| e.to_string())
+}
+
+fn with_shared(input: &[u8]) -> Result<Vec<u8>, String> {
+    let run = || -> Result<Vec<u8>, Box<dyn error::Error>> {
+        let shared = SharedDeduplicator::new(&Config::new())?;
+        let mut stream = shared.stream();
+        let mut output = vec![];
+        for chunk in input.chunks(5) {
+            stream.feed(chunk, &mut output)?;
+        }
+        stream.finish(&mut output)?;
+        Ok(output)
+    };
+    run().map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strategies_agree() {
+        if let Err(e) = check_random(1, 300) {
+            panic!("{}", e);
+        }
+    }
+}
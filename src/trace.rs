@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+
+use regex::bytes::Regex;
+
+/// Logs every decision about rows whose key matches a pattern to stderr, for
+/// finding out why a row was or wasn't kept
+pub struct Tracer {
+    pattern: Regex,
+    /// Where the first kept row of each traced key came from
+    kept: HashMap<Vec<u8>, String>,
+}
+
+impl Tracer {
+    pub fn new(pattern: Regex) -> Tracer {
+        Tracer { pattern, kept: HashMap::new() }
+    }
+
+    /// Logs whether the `occurrence`th row of `key`, read as `source` line
+    /// `line_number`, was kept
+    pub fn decision(&mut self, key: &[u8], source: &str, line_number: u64, occurrence: u64, kept: bool)
+                    -> io::Result<()> {
+        if !self.pattern.is_match(key) {
+            return Ok(());
+        }
+        let location = format!("{}:{}", source, line_number);
+        let outcome = if kept {
+            self.kept.entry(key.to_owned()).or_insert_with(|| location.clone());
+            "kept".to_owned()
+        }
+        else {
+            match self.kept.get(key) {
+                Some(first) => format!("dropped, as {} was kept", first),
+                None => "dropped, with no row kept yet".to_owned(),
+            }
+        };
+        writeln!(io::stderr(), "tsvfirst: trace: {}: key '{}' occurrence {}: {}",
+            location, String::from_utf8_lossy(key), occurrence, outcome)
+    }
+
+    /// Logs the row of `key` chosen from the `count` it had, for policies that
+    /// pick one only after seeing them all
+    pub fn winner(&self, key: &[u8], source: &str, line_number: u64, occurrence: u64, count: u64)
+                  -> io::Result<()> {
+        if !self.pattern.is_match(key) {
+            return Ok(());
+        }
+        writeln!(io::stderr(), "tsvfirst: trace: {}:{}: key '{}' occurrence {}: kept, out of {} rows",
+            source, line_number, String::from_utf8_lossy(key), occurrence, count)
+    }
+}
@@ -1,71 +1,962 @@
-extern crate regex;
-
+use std::collections::{HashMap, VecDeque};
+use std::fs;
 use std::io;
-use std::collections::HashSet;
+use std::io::{BufWriter, Write};
 use std::error;
 
-use config::Config;
+use regex::bytes::Regex;
+
+use clusters::ClusterFile;
+use config::{BadInput, Config, CountDistinct, Input};
+use diagnostics::{Category, Diagnostics, Location};
+use filter;
+use http;
+use index::Index;
+use keep::{Best, KeepRules, Row};
+use key::{self, KeyBuilder, LongKey, NoMatch};
+use lines;
+use mask::Masker;
+use output::{chomp, CountingWriter, Decision, DecisionsFormat, NullFormat, OutputFormat, RowFormat, SqlFormat, TsvFormat};
+use profile::{Phase, Profiler};
+use pipes::PipeFormat;
+use progress::Progress;
+use seen::Seen;
+use state::{self, State};
+use stats::Stats;
+use summary::Summary;
+use template::TemplateFormat;
+use trace::Tracer;
+use utf8::{self, Utf8Mode};
+use warnings::Warnings;
+
+/// Rows read before --max-dupe-ratio is checked during the run, so that a few
+/// early duplicates don't abort it
+const DUPE_RATIO_MIN_ROWS: u64 = 10000;
 
-pub fn run<W>(config: &Config, output: &mut W) -> Result<(), Box<error::Error>>
+pub fn run<W>(config: &Config, output: &mut W) -> Result<(), Box<dyn error::Error>>
 where W: io::Write {
-    let delim = if config.whitespace { r"\s+" } else { r"\t" };
-    let splitter = regex::bytes::Regex::new(delim)?;
-
-    // Construct a HashSet to track previously seen values (if sorted not set)
-    let mut seen = HashSet::new();
-    let mut last : Option<Vec<u8>> = None;
-
-    let mut reader = config.get_reader()?;
-    let mut line : Vec<u8> = vec![];
-    while let Ok(_) = reader.read_until(0x0A as u8, &mut line) {
-        if line.is_empty() {
-            // EOF
-            break;
+    let format = default_format(config);
+    match config.state {
+        Some(ref path) => {
+            let input = match (config.inputs.as_slice(), config.files_from.as_ref()) {
+                ([input], None) if input != "-" && !http::is_url(input) => input,
+                _ => return Err("state requires a single input file".into()),
+            };
+            let mut state = State::load(path, input)?;
+            let (input, end) = state::open_from(input, state.offset)?;
+            state.offset = end;
+            if !config.append {
+                state.output_length = 0;
+            }
+            run_rows(config, vec![Ok(input)], output, format, Some(state))
+        }
+        None => run_rows(config, config.get_inputs()?, output, format, None),
+    }
+}
+
+/// The row format the config asks for
+fn default_format(config: &Config) -> Box<dyn RowFormat> {
+    let splitter = config.splitter();
+    if config.count_distinct.is_some() {
+        Box::new(NullFormat)
+    }
+    else if let Some(ref command) = config.pipe_per_key {
+        Box::new(PipeFormat::new(command, config.pipe_jobs))
+    }
+    else if let Some(ref template) = config.template {
+        Box::new(TemplateFormat::new(template.clone(), splitter.clone()))
+    }
+    else {
+        match config.output_format {
+            OutputFormat::Tsv => Box::new(TsvFormat::new(config.mark_dupes.clone())),
+            OutputFormat::Sql => Box::new(SqlFormat::new(&config.table, splitter.clone()).dialect(config.sql_dialect)),
+            OutputFormat::Decisions => Box::new(DecisionsFormat),
         }
+    }
+}
+
+/// As for `run`, but reads `inputs` rather than the inputs the config names,
+/// e.g. to deduplicate data that doesn't come from a file. Each input's name
+/// is used in messages and for {filename} in --format.
+pub fn run_from<I, W>(config: &Config, inputs: I, output: &mut W) -> Result<(), Box<dyn error::Error>>
+where I: IntoIterator<Item = io::Result<Input>>, W: io::Write {
+    let format = default_format(config);
+    run_rows(config, inputs, output, format, None)
+}
+
+/// As for `run`, but writes each row's decision through `format` rather than
+/// the format the config names, e.g. to send kept rows somewhere other than a
+/// byte stream. Anything written besides rows, such as --count-distinct
+/// counts or --group separators, still goes to `output`.
+pub fn run_with<W>(config: &Config, output: &mut W, format: Box<dyn RowFormat>) -> Result<(), Box<dyn error::Error>>
+where W: io::Write {
+    // The rest of an over-long row is copied straight to the output
+    if config.max_row_buffer.is_some() {
+        return Err("max_row_buffer can't be used with a custom row format".into());
+    }
+    run_rows(config, config.get_inputs()?, output, format, None)
+}
 
-        // Build sort key
-        let key : Vec<u8> = {
-            let mut fields = splitter.split(&line);
-            let mut key : Vec<u8> = vec![];
-            let mut last_idx = 0;
+/// `state` is the progress saved by an earlier run when `config.state` is set,
+/// with `inputs` continuing from it
+fn run_rows<I, W>(config: &Config, inputs: I, output: &mut W, mut format: Box<dyn RowFormat>, state: Option<State>)
+    -> Result<(), Box<dyn error::Error>>
+where I: IntoIterator<Item = io::Result<Input>>, W: io::Write {
+    if config.state.is_some() != state.is_some() {
+        return Err("state is only supported by run".into());
+    }
+    // Keys are carried over from the saved state, so every key must be
+    // remembered exactly, and every row decided, by the end of the input
+    if state.is_some() && (config.sorted || !config.sorted_by.is_empty() || config.memory_limit.is_some() || config.buffers_rows()
+            || config.all_duplicates || config.count_distinct.is_some() || config.head.is_some()
+            || config.skip_footer > 0 || config.footer_regex.is_some()) {
+        return Err("state can't be used with sorted or grouped input, memory_limit, keep policies that hold rows back, \
+            all_duplicates, count_distinct, head, skip_footer or footer_regex".into());
+    }
+    if config.byte_exact {
+        if let Some(option) = config.rewriting_option() {
+            return Err(format!("{} rewrites rows, so cannot be used with --byte-exact", option).into());
+        }
+    }
+    // No rows are written, so --head would cut the count short
+    if config.count_distinct.is_some() && config.head.is_some() {
+        return Err("count_distinct can't be used with head".into());
+    }
+    // Output offsets are counted for --index
+    let output = &mut CountingWriter::new(output);
+    let splitter = config.splitter();
+    let keys = config.key_builder()?;
+    let groups = config.group_builder();
+
+    let footer = match config.footer_regex {
+        Some(ref pattern) => Some(Regex::new(pattern)?),
+        None => None,
+    };
+
+    let masker = if config.mask_key.is_empty() {
+        None
+    }
+    else {
+        Some(Masker::new(&config.mask_key, &config.fields, splitter.clone()))
+    };
+
+    let mut diagnostics = Diagnostics::new(config.errors.clone(), config.errors_file.as_ref().map(|s| s.as_ref()))?;
+
+    let mut warnings = Warnings::new(config);
+    let mut tracer = match config.trace_keys {
+        Some(ref pattern) => Some(Tracer::new(Regex::new(pattern)?)),
+        None => None,
+    };
+    let mut profiler = Profiler::new(config.profile);
+    let mut stats = Stats::new(config.stats_per_file);
+    let mut progress = Progress::new(config.progress, config.expected_lines, config.expected_bytes);
+
+    let always_keep = match config.always_keep_from {
+        Some(ref path) => Some(key::load_keys(path, &config.value_key_builder()?)
+            .map_err(|e| format!("{}: {}", path, e))?),
+        None => None,
+    };
+    let new_seen = || {
+        Seen::new(config.sorted)
+            .memory_limit(config.memory_limit, config.adaptive_memory)
+            .hash_seed(config.hash_seed)
+    };
+    let mut seen = new_seen();
+    if let Some(ref state) = state {
+        for &(ref key, count) in &state.keys {
+            seen.restore(key, count);
+        }
+    }
+    let mut best = if !config.buffers_rows() {
+        None
+    }
+    else {
+        let mut best = Best::new(config.keep.clone(), config.sorted, config.seed, splitter.clone())
+            .tie_breaks(config.tie_breaks.clone())
+            .aggregating(config.aggregations.clone());
+        if let Some(ref path) = config.keep_rules {
+            let rules = KeepRules::load(path, &config.value_key_builder()?)
+                .map_err(|e| format!("{}: {}", path, e))?;
+            if config.key_xxhash && rules.has_regex() {
+                return Err(format!("{}: regex rules can't match --key-xxhash keys", path).into());
+            }
+            best = best.rules(rules);
+        }
+        Some(best)
+    };
+    // With --all-duplicates, the first row of each key seen only once so far
+    let mut singles = if config.all_duplicates { Some(HashMap::new()) } else { None };
+    let mut clusters = match config.clusters {
+        Some(ref path) => Some(ClusterFile::create(path)?),
+        None => None,
+    };
+
+    let mut index = match config.index {
+        Some(ref path) => Some(Index::create(path)?),
+        None => None,
+    };
+    let mut dupes = match config.dupes_to {
+        Some(ref path) => Some(BufWriter::new(fs::File::create(path)?)),
+        None => None,
+    };
+    let mut summary = match config.summary {
+        Some(ref path) => Some(Summary::create(path, config.sorted)?),
+        None => None,
+    };
+
+    // Rows written, counted for --head
+    let mut kept_rows = 0;
+    // Rows considered for deduplication, and how many were dropped
+    let mut keyed_rows = 0;
+    let mut dropped_rows = 0;
+    // For --count-distinct: keys seen, and the input they were counted for
+    let mut distinct = 0;
+    let mut counted = None;
+    // Keys over --max-key-bytes
+    let mut long_keys = 0;
+    // Inputs passed over by --skip-bad-inputs
+    let mut skipped_inputs = 0;
+    // For --group: whether a group has been written
+    let mut any_group = false;
+
+    'inputs: for (source, input) in inputs.into_iter().enumerate() {
+        let mut input = match input {
+            Ok(input) => input,
+            Err(e) => match BadInput::skipped(config, &e) {
+                Some(bad) => {
+                    writeln!(io::stderr(), "tsvfirst: skipping {}", bad)?;
+                    stats.skip_file(&bad.name);
+                    skipped_inputs += 1;
+                    continue;
+                }
+                None => return Err(e.into()),
+            },
+        };
+        if config.count_distinct == Some(CountDistinct::PerFile) {
+            if let Some(name) = counted.replace(input.name.clone()) {
+                writeln!(output, "{}\t{}", name, distinct)?;
+            }
+            distinct = 0;
+            seen = new_seen();
+        }
+        seen.start_source(source);
+        stats.start_file(&input.name);
+        let mut line : Vec<u8> = vec![];
+        let mut line_number = 0;
+        let mut next_offset = 0;
+        let mut held = VecDeque::new();
+        loop {
+            line.clear();
+            let offset = next_offset;
+            if config.head.is_some_and(|head| kept_rows >= head) {
+                break 'inputs;
+            }
+
+            let length = match config.max_row_buffer {
+                None => input.reader.read_until(b'\n', &mut line)?,
+                Some(limit) => {
+                    let mut length = lines::read_line_limited(&mut *input.reader, &mut line, limit)?;
+                    // The key itself has to be buffered, however long it is
+                    while !line.ends_with(b"\n") && !keys.complete_in(&line) {
+                        let target = line.len() + limit;
+                        let more = lines::read_line_limited(&mut *input.reader, &mut line, target)?;
+                        if more == 0 {
+                            break;
+                        }
+                        length += more;
+                    }
+                    length
+                }
+            };
+            if length == 0 {
+                break;
+            }
+            next_offset += length as u64;
+            progress.read(1, length as u64)?;
+            // Only a prefix of the row was read; the rest is streamed below
+            let mut partial = !line.ends_with(b"\n");
+            if partial && config.ensure_eol && input.reader.fill_buf()?.is_empty() {
+                // A final row without a newline
+                line.push(b'\n');
+                partial = false;
+            }
+            line_number += 1;
+            stats.row();
 
-            for idx in &config.fields {
-                if let Some(column) = fields.nth(idx - last_idx) {
-                    key.append(&mut column.into());
-                    last_idx = idx + 1;
+            // Rows are held back until --skip-footer more have been read, so
+            // that the last ones are never processed
+            let (line_number, offset) = if config.skip_footer > 0 {
+                held.push_back((line.clone(), line_number, offset));
+                if held.len() <= config.skip_footer {
+                    continue;
+                }
+                let (row, number, row_offset) = held.pop_front().unwrap();
+                line = row;
+                (number, row_offset)
+            }
+            else {
+                (line_number, offset)
+            };
+            if footer.as_ref().is_some_and(|footer| footer.is_match(chomp(&line))) {
+                continue 'inputs;
+            }
+
+            match config.utf8 {
+                Utf8Mode::Ignore => {}
+                Utf8Mode::Strict => if let Some(pos) = utf8::invalid_position(&line) {
+                    return Err(format!("{}:{}: invalid UTF-8 at byte offset {}",
+                        input.name, line_number, offset + pos as u64).into());
+                },
+                Utf8Mode::Lossy => if let Some(pos) = utf8::invalid_position(&line) {
+                    if diagnostics.enabled() {
+                        let at = Location { source: &input.name, line: line_number, offset };
+                        let message = format!("invalid UTF-8 at byte {} of row replaced", pos);
+                        diagnostics.report(Category::InvalidUtf8, &at, &message)?;
+                    }
+                    utf8::make_lossy(&mut line);
+                },
+            }
+            if let Some(count) = config.pad_fields {
+                let separator : &[u8] = if config.whitespace { b" " } else { b"\t" };
+                lines::fit_fields(&mut line, &splitter, count, config.truncate_fields, separator);
+            }
+            profiler.mark(Phase::Read);
+
+            if !filter::accepts(&config.filters, &splitter, &line, None) {
+                continue;
+            }
+            let mut key = keys.build(&line);
+            if key.unmatched {
+                match config.no_match {
+                    NoMatch::Skip => continue,
+                    NoMatch::Error => return Err(format!("{}:{}: row does not match --line-regex",
+                        input.name, line_number).into()),
+                    NoMatch::Pass => {}
+                }
+            }
+            if key.unpartitioned {
+                match config.outside_partitions {
+                    NoMatch::Skip => continue,
+                    NoMatch::Error => return Err(format!("{}:{}: row's field {} is in no --partition-by range",
+                        input.name, line_number, config.partitions.as_ref().map_or(0, |partitions| partitions.field + 1)).into()),
+                    NoMatch::Pass => {}
+                }
+            }
+            if let Some(limit) = config.max_key_bytes.filter(|&limit| !key.unmatched && key.bytes.len() > limit) {
+                long_keys += 1;
+                match config.long_key {
+                    LongKey::Truncate => key.bytes.truncate(limit),
+                    LongKey::Hash => key.bytes = key::hash(&key.bytes, config.hash_seed, config.hash_secret.as_deref()),
+                    LongKey::Skip => continue,
+                    LongKey::Error => return Err(format!("{}:{}: key is {} bytes, over --max-key-bytes {}",
+                        input.name, line_number, key.bytes.len(), limit).into()),
+                }
+            }
+            if let Some(idx) = key.missing_field {
+                if diagnostics.enabled() {
+                    let at = Location { source: &input.name, line: line_number, offset };
+                    let message = format!("row is missing key field {}", idx + 1);
+                    diagnostics.report(Category::ShortRow, &at, &message)?;
+                }
+            }
+            // Rows that are unmatched, unpartitioned or exempt from deduplication pass straight through
+            let passed = key.unmatched || key.unpartitioned || always_keep.as_ref().is_some_and(|keys| keys.contains(&key.bytes));
+            let keyed = if passed { None } else { Some(&key.bytes[..]) };
+            warnings.row(&line, keyed, key.missing_field.is_some(), &input.name, line_number)?;
+            let key = key.bytes;
+            if let Some(ref mut summary) = summary {
+                if !passed {
+                    summary.record(&key)?;
+                }
+            }
+            profiler.mark(Phase::Key);
+
+            if let Some(ref mut best) = best {
+                // Winners are written once all their key's rows have been seen
+                let mut winners = vec![];
+                if passed {
+                    winners.push(Row { line: line.clone(), key, source: input.name.clone(), line_number, occurrence: 1, count: 1 });
                 }
                 else {
-                    break;
+                    if let Some(ref groups) = groups {
+                        winners.extend(best.start_group(&groups.build(&line).bytes));
+                    }
+                    winners.extend(best.offer(Row { line: line.clone(), key, source: input.name.clone(), line_number, occurrence: 0, count: 0 }));
+                }
+                keyed_rows += 1;
+                dropped_rows = best.duplicates;
+                if keyed_rows >= DUPE_RATIO_MIN_ROWS {
+                    check_dupe_ratio(config, keyed_rows, dropped_rows)?;
+                }
+                profiler.mark(Phase::Dedup);
+                for row in winners {
+                    if config.head.is_some_and(|head| kept_rows >= head) {
+                        break;
+                    }
+                    if !filter::accepts(&config.having, &splitter, &row.line, Some(row.count)) {
+                        continue;
+                    }
+                    if let Some(ref mut index) = index {
+                        index.record(&row.key, output.written);
+                    }
+                    if let Some(ref tracer) = tracer {
+                        tracer.winner(&row.key, &row.source, row.line_number, row.occurrence, row.count)?;
+                    }
+                    write_winner(&mut *format, output, config, masker.as_ref(), &keys, &row)?;
+                    kept_rows += 1;
                 }
+                profiler.mark(Phase::Write);
+                continue;
+            }
+
+            if let Some(ref mut singles) = singles {
+                let mut rows = vec![];
+                let row = Row { line: line.clone(), key, source: input.name.clone(), line_number, occurrence: 1, count: 1 };
+                if passed {
+                    rows.push(row);
+                }
+                else {
+                    if let Some(ref groups) = groups {
+                        seen.start_group(&groups.build(&line).bytes);
+                    }
+                    let occurrence = seen.observe(&row.key);
+                    seen.check_memory()?;
+                    keyed_rows += 1;
+                    let row = Row { occurrence, count: occurrence, ..row };
+                    if occurrence == 1 {
+                        // With sorted input, earlier keys can't recur
+                        if config.sorted {
+                            singles.clear();
+                        }
+                        singles.insert(row.key.clone(), row);
+                    }
+                    else {
+                        // The second row shows the first was duplicated after all
+                        rows.extend(singles.remove(&row.key));
+                        rows.push(row);
+                    }
+                }
+                profiler.mark(Phase::Dedup);
+                for row in rows {
+                    if config.head.is_some_and(|head| kept_rows >= head) {
+                        break;
+                    }
+                    if let Some(ref mut tracer) = tracer {
+                        tracer.decision(&row.key, &row.source, row.line_number, row.occurrence, true)?;
+                    }
+                    write_winner(&mut *format, output, config, masker.as_ref(), &keys, &row)?;
+                    kept_rows += 1;
+                }
+                profiler.mark(Phase::Write);
+                continue;
             }
-            key
-        };
 
-        let should_print = if config.sorted {
-            // Compare against previous value
-            match last {
-                Some(ref last_key) if *last_key == key => {
-                    false
+            let entry = if passed {
+                None
+            }
+            else {
+                if let Some(ref groups) = groups {
+                    seen.start_group(&groups.build(&line).bytes);
                 }
-                _ => {
-                    last = Some(key);
-                    true
+                let entry = seen.observe_entry(&key);
+                seen.check_memory()?;
+                if entry.count == 1 {
+                    distinct += 1;
                 }
+                Some(entry)
+            };
+            let occurrence = entry.map_or(1, |entry| entry.count);
+            // With --across-files-only, repeats within the key's first input are kept
+            // --group writes every row
+            let kept = entry.is_none() || config.group_separator.is_some()
+                || config.keep.keeps(occurrence, config.max_per_key)
+                || (config.across_files_only && entry.is_some_and(|entry| entry.source == source));
+            keyed_rows += 1;
+            if let Some(entry) = entry.filter(|entry| entry.count > 1) {
+                stats.duplicate(entry.source);
             }
-        }
-        else {
-            // Print if wasn't present in seen set
-            seen.insert(key)
-        };
+            if !kept {
+                dropped_rows += 1;
+            }
+            if keyed_rows >= DUPE_RATIO_MIN_ROWS {
+                check_dupe_ratio(config, keyed_rows, dropped_rows)?;
+            }
+            if let Some(ref mut clusters) = clusters {
+                clusters.record(entry.map(|entry| (entry.id, entry.count)), &input.name, line_number)?;
+            }
+            profiler.mark(Phase::Dedup);
 
-        if should_print {
-            output.write_all(&line)?;
+            // Rows failing --having still count as seen, just aren't written
+            let kept = kept && filter::accepts(&config.having, &splitter, &line, None);
+            if let (Some(tracer), Some(_)) = (tracer.as_mut(), entry) {
+                tracer.decision(&key, &input.name, line_number, occurrence, kept)?;
+            }
+            if let Some(separator) = config.group_separator {
+                if occurrence == 1 && entry.is_some() {
+                    if separator.before_group(any_group) {
+                        output.write_all(b"\n")?;
+                    }
+                    any_group = true;
+                }
+            }
+            // With --invert only the dropped rows are written
+            let written = kept != config.invert;
+            if written {
+                if let Some(ref mut index) = index {
+                    index.record(&key, output.written);
+                }
+            }
+            write_row(&mut *format, output, config, masker.as_ref(), &keys, &Decision {
+                line: &line,
+                key: &key,
+                kept: written,
+                occurrence,
+                count: None,
+                source: &input.name,
+                line_number,
+            })?;
+            // Dropped rows are copied as read
+            let mut tee = dupes.as_mut().filter(|_| !kept);
+            if let Some(ref mut tee) = tee {
+                tee.write_all(&line)?;
+            }
+            if partial {
+                let copied = (written || config.mark_dupes.is_some())
+                    && config.output_format == OutputFormat::Tsv;
+                let rest : Option<&mut dyn io::Write> = if copied {
+                    Some(output)
+                }
+                else {
+                    tee.as_mut().map(|tee| &mut **tee as &mut dyn io::Write)
+                };
+                let (length, terminated) = lines::copy_rest_of_line(&mut *input.reader, rest)?;
+                next_offset += length as u64;
+                progress.read(0, length as u64)?;
+                if !terminated && config.ensure_eol {
+                    if copied {
+                        output.write_all(b"\n")?;
+                    }
+                    else if let Some(tee) = tee {
+                        tee.write_all(b"\n")?;
+                    }
+                }
+            }
+            profiler.mark(Phase::Write);
+            if written {
+                kept_rows += 1;
+            }
+            if kept {
+                stats.kept();
+            }
         }
-        line.clear();
     }
 
+    check_dupe_ratio(config, keyed_rows, dropped_rows)?;
+    warnings.finish()?;
+    if let Some(ref mut best) = best {
+        for row in best.finish() {
+            if config.head.is_some_and(|head| kept_rows >= head) {
+                break;
+            }
+            if !filter::accepts(&config.having, &splitter, &row.line, Some(row.count)) {
+                continue;
+            }
+            if let Some(ref mut index) = index {
+                index.record(&row.key, output.written);
+            }
+            if let Some(ref tracer) = tracer {
+                tracer.winner(&row.key, &row.source, row.line_number, row.occurrence, row.count)?;
+            }
+            write_winner(&mut *format, output, config, masker.as_ref(), &keys, &row)?;
+            kept_rows += 1;
+        }
+    }
+    if config.group_separator.is_some_and(|separator| separator.after_last()) && any_group {
+        output.write_all(b"\n")?;
+    }
+    match config.count_distinct {
+        Some(CountDistinct::Total) => writeln!(output, "{}", distinct)?,
+        Some(CountDistinct::PerFile) => if let Some(name) = counted {
+            writeln!(output, "{}\t{}", name, distinct)?;
+        },
+        None => {}
+    }
+    format.finish(output)?;
     output.flush()?;
+    profiler.mark(Phase::Write);
+    diagnostics.flush()?;
+    progress.finish()?;
+    if let Some(ref mut clusters) = clusters {
+        clusters.flush()?;
+    }
+    if let Some(ref mut index) = index {
+        index.finish()?;
+    }
+    if let Some(ref mut dupes) = dupes {
+        dupes.flush()?;
+    }
+    if let Some(ref mut summary) = summary {
+        summary.finish()?;
+    }
+    if long_keys > 0 {
+        let outcome = match config.long_key {
+            LongKey::Truncate => "truncated",
+            LongKey::Hash => "hashed",
+            LongKey::Skip => "skipped",
+            LongKey::Error => unreachable!(),
+        };
+        writeln!(io::stderr(), "tsvfirst: {} keys over --max-key-bytes {} were {}", long_keys,
+            config.max_key_bytes.unwrap_or(0), outcome)?;
+    }
+    if skipped_inputs > 0 {
+        writeln!(io::stderr(), "tsvfirst: {} inputs couldn't be opened and were skipped", skipped_inputs)?;
+    }
+    if let (Some(mut state), Some(ref path)) = (state, config.state.as_ref()) {
+        // Saved only once everything kept has been written
+        output.flush()?;
+        state.output_length += output.written;
+        state.keys = seen.counts().unwrap();
+        state.save(path).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    stats.report(&mut io::stderr())?;
+    profiler.report(&mut io::stderr())?;
 
     Ok(())
 }
 
+/// Fails the run if more than --max-dupe-ratio of the rows so far were dropped
+fn check_dupe_ratio(config: &Config, rows: u64, dropped: u64) -> Result<(), Box<dyn error::Error>> {
+    if let Some(limit) = config.max_dupe_ratio {
+        let ratio = if rows == 0 { 0.0 } else { dropped as f64 / rows as f64 };
+        if ratio > limit {
+            return Err(format!("{} of {} rows ({:.3}) were duplicates, over --max-dupe-ratio {}",
+                dropped, rows, ratio, limit).into());
+        }
+    }
+    Ok(())
+}
+
+/// Writes a row, masking key fields and adding the key hash column as configured
+fn write_row(format: &mut dyn RowFormat, output: &mut dyn io::Write, config: &Config, masker: Option<&Masker>,
+             keys: &KeyBuilder, decision: &Decision) -> io::Result<()> {
+    // Key fields are masked in output only; dedup uses the real values
+    let masked = masker.map(|masker| {
+        let line = masker.apply(decision.line);
+        let key = keys.build(&line).bytes;
+        (line, key)
+    });
+    let (mut line, key) = match masked {
+        Some((ref line, ref key)) => (&line[..], &key[..]),
+        None => (decision.line, decision.key),
+    };
+    // The hash column is of the real key, even if masked
+    let with_hash;
+    if config.emit_key_hash {
+        with_hash = [decision.key, b"\t", line].concat();
+        line = &with_hash;
+    }
+    // Templates place the count themselves
+    let with_count;
+    if config.count_prefix && config.template.is_none() {
+        let count = decision.count.unwrap_or(decision.occurrence);
+        with_count = [format!("{}\t", count).as_bytes(), line].concat();
+        line = &with_count;
+    }
+    format.write(output, &Decision { line, key, ..*decision })
+}
+
+/// Writes a row kept under a --keep policy other than first
+fn write_winner(format: &mut dyn RowFormat, output: &mut dyn io::Write, config: &Config, masker: Option<&Masker>,
+                keys: &KeyBuilder, row: &Row) -> io::Result<()> {
+    write_row(format, output, config, masker, keys, &Decision {
+        line: &row.line,
+        key: &row.key,
+        kept: true,
+        occurrence: row.occurrence,
+        count: Some(row.count),
+        source: &row.source,
+        line_number: row.line_number,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::{Cursor, Read};
+    use std::process;
+
+    use keep::Keep;
+    use key::KeyField;
+    use output::OutputFormat;
+
+    fn dedup(config: &Config, input: &[u8]) -> Vec<u8> {
+        let input = Input { name: "-".into(), reader: Box::new(Cursor::new(input.to_owned())) };
+        let mut output = vec![];
+        run_from(config, vec![Ok(input)], &mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn head_stops_buffered_winners() {
+        let config = Config::new()
+            .fields(&[KeyField::new(0), KeyField::new(1)])
+            .sorted_by(&[KeyField::new(0)])
+            .keep(Keep::Last)
+            .head(1);
+        assert_eq!(dedup(&config, b"a\t1\na\t2\nb\t3\nb\t4\nc\t5\n"), b"a\t1\n");
+    }
+
+    #[test]
+    fn key_fields_are_joined_by_tabs() {
+        // Not the same key when the field values are simply concatenated
+        let config = Config::new().fields(&[KeyField::new(0), KeyField::new(1)]);
+        assert_eq!(dedup(&config, b"ab\tc\na\tbc\nab\tc\n"), b"ab\tc\na\tbc\n");
+    }
+
+    #[test]
+    fn line_endings_are_not_part_of_keys() {
+        assert_eq!(dedup(&Config::new(), b"a\r\nb\na\nb\r\na"), b"a\r\nb\n");
+        let config = Config::new().fields(&[KeyField::new(1)]);
+        assert_eq!(dedup(&config, b"x\t1\r\ny\t1\n"), b"x\t1\r\n");
+    }
+
+    #[test]
+    fn read_errors_end_the_run() {
+        let failing = io::BufReader::new(Cursor::new(b"a\n".to_vec()).chain(Failing));
+        let input = Input { name: "-".into(), reader: Box::new(failing) };
+        let mut output = vec![];
+        let error = run_from(&Config::new(), vec![Ok(input)], &mut output).unwrap_err();
+        assert_eq!(error.to_string(), "disk on fire");
+    }
+
+    /// A reader whose every read fails
+    struct Failing;
+
+    impl io::Read for Failing {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk on fire"))
+        }
+    }
+
+    #[test]
+    fn byte_exact_keeps_crlf() {
+        let config = Config::new().byte_exact(true);
+        assert_eq!(dedup(&config, b"a\t1\r\na\t2\r\nb\t3\r\n"), b"a\t1\r\nb\t3\r\n");
+    }
+
+    #[test]
+    fn byte_exact_keeps_missing_final_newline() {
+        let config = Config::new().byte_exact(true);
+        assert_eq!(dedup(&config, b"a\nb\na\nc"), b"a\nb\nc");
+        let config = Config::new().byte_exact(true).keep(Keep::Nth(2));
+        assert_eq!(dedup(&config, b"a\nb\na"), b"a");
+    }
+
+    #[test]
+    fn byte_exact_keeps_trailing_empty_fields() {
+        let config = Config::new().byte_exact(true);
+        assert_eq!(dedup(&config, b"a\t\t\na\t\nb\t\t\t\r\n"), b"a\t\t\nb\t\t\t\r\n");
+    }
+
+    #[test]
+    fn byte_exact_rejects_rewriting_options() {
+        let configs = vec![
+            ("--output-format", Config::new().output_format(OutputFormat::Sql).table("t")),
+            ("--format", Config::new().template("{key}".parse().unwrap())),
+            ("--mask-key", Config::new().mask_key("1:x***".parse().unwrap())),
+            ("--mark-dupes", Config::new().mark_dupes(b"DUP\t")),
+            ("--emit-key-hash", Config::new().emit_key_hash(true)),
+            ("--count", Config::new().count_prefix(true)),
+            ("--agg", Config::new().aggregate("sum(2)".parse().unwrap())),
+            ("--pad-fields", Config::new().pad_fields(3, false)),
+            ("--utf8 lossy", Config::new().utf8(Utf8Mode::Lossy)),
+            ("--ensure-eol", Config::new().ensure_eol(true)),
+            ("--keep", Config::new().keep(Keep::Last)),
+            ("--having", Config::new().having("count > 1".parse().unwrap())),
+        ];
+        for (option, config) in configs {
+            let input = Input { name: "-".into(), reader: Box::new(Cursor::new(b"a\t1\n".to_vec())) };
+            let error = run_from(&config.byte_exact(true), vec![Ok(input)], &mut vec![]).unwrap_err();
+            assert_eq!(error.to_string(), format!("{} rewrites rows, so cannot be used with --byte-exact", option));
+        }
+    }
+
+    const ROWS: &[u8] = b"a\t5\tx\nb\t2\ty\na\t9\tw\nc\t\tz\na\t9\tv\nb\t-1\tu\nc\t3\tq\nb\t2\tt\n";
+
+    fn keeping(keep: Keep) -> Vec<u8> {
+        dedup(&Config::new().keep(keep), ROWS)
+    }
+
+    #[test]
+    fn keep_last() {
+        assert_eq!(keeping(Keep::Last), b"a\t9\tv\nb\t2\tt\nc\t3\tq\n");
+    }
+
+    #[test]
+    fn keep_nth() {
+        // Written as soon as they are read
+        assert_eq!(keeping(Keep::Nth(2)), b"a\t9\tw\nb\t-1\tu\nc\t3\tq\n");
+        // Keys with fewer rows are dropped
+        assert_eq!(keeping(Keep::Nth(3)), b"a\t9\tv\nb\t2\tt\n");
+    }
+
+    #[test]
+    fn keep_lexmin_and_lexmax() {
+        assert_eq!(keeping(Keep::Lexmin), b"a\t5\tx\nb\t-1\tu\nc\t\tz\n");
+        assert_eq!(keeping(Keep::Lexmax), b"a\t9\tw\nb\t2\ty\nc\t3\tq\n");
+    }
+
+    #[test]
+    fn keep_max_by_and_min_by() {
+        // Ties keep the earlier row, and rows without a number lose
+        assert_eq!(keeping(Keep::MaxBy(1)), b"a\t9\tw\nb\t2\ty\nc\t3\tq\n");
+        assert_eq!(keeping(Keep::MinBy(1)), b"a\t5\tx\nb\t-1\tu\nc\t3\tq\n");
+    }
+
+    #[test]
+    fn tie_breaks_decide_in_turn() {
+        let input = b"a\t9\tx\na\t9\tlong\na\t9\ty\nb\t1\t3\nb\t1\t2\nb\t1\t2\n";
+        let keeping = |tie_breaks: &[&str]| {
+            let config = tie_breaks.iter().fold(Config::new().keep(Keep::MaxBy(1)), |config, tie_break| {
+                config.tie_break(tie_break.parse().unwrap())
+            });
+            dedup(&config, input)
+        };
+        assert_eq!(keeping(&[]), b"a\t9\tx\nb\t1\t3\n");
+        assert_eq!(keeping(&["last"]), b"a\t9\ty\nb\t1\t2\n");
+        assert_eq!(keeping(&["longest", "last"]), b"a\t9\tlong\nb\t1\t2\n");
+        assert_eq!(keeping(&["min:3", "last"]), b"a\t9\ty\nb\t1\t2\n");
+        assert_eq!(keeping(&["max:3", "last"]), b"a\t9\ty\nb\t1\t3\n");
+        // 'first' decides every tie, so later rules are never reached
+        assert_eq!(keeping(&["first", "last"]), b"a\t9\tx\nb\t1\t3\n");
+    }
+
+    #[test]
+    fn rules_choose_policies_per_key() {
+        let path = env::temp_dir().join(format!("tsvfirst-rules-{}", process::id()));
+        let keeping = |rules: &str| {
+            fs::write(&path, rules).unwrap();
+            let output = run_from(&Config::new().keep_rules(path.to_str().unwrap()), vec![Ok(Input {
+                name: "-".into(),
+                reader: Box::new(Cursor::new(ROWS.to_vec())),
+            })], &mut vec![]);
+            output.map(|_| ()).map_err(|e| e.to_string())
+        };
+        let rules = "# comment\n\nlast\texact\ta\nmin-by:2\tregex\t^b$\nskip\tregex\t.\nlast\tregex\t.\n";
+        fs::write(&path, rules).unwrap();
+        assert_eq!(dedup(&Config::new().keep_rules(path.to_str().unwrap()), ROWS), b"a\t9\tv\nb\t-1\tu\n");
+        // Keys matching no rule follow the keep policy
+        fs::write(&path, "skip\texact\tb\n").unwrap();
+        assert_eq!(dedup(&Config::new().keep_rules(path.to_str().unwrap()).keep(Keep::Last), ROWS), b"a\t9\tv\nc\t3\tq\n");
+        let error = keeping("last\tregex\t(\n").unwrap_err();
+        assert!(error.starts_with(&format!("{}: line 1: regex parse error", path.display())), "{}", error);
+        let error = keeping("x\n\nnth\texact\ta\n").unwrap_err();
+        assert_eq!(error, format!("{}: line 1: unknown keep policy 'x'", path.display()));
+        let error = keeping("nth\texact\ta\n").unwrap_err();
+        assert_eq!(error, format!("{}: line 1: nth can't be used in a rules file", path.display()));
+        let error = keeping("last\tprefix\ta\n").unwrap_err();
+        assert_eq!(error, format!("{}: line 1: expected POLICY, 'exact' or 'regex' and a pattern, separated by tabs", path.display()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn keep_random_is_fixed_by_seed() {
+        let random = |seed| dedup(&Config::new().keep(Keep::Random).seed(seed), ROWS);
+        let outputs : Vec<Vec<u8>> = (0..20).map(random).collect();
+        for (seed, output) in outputs.iter().enumerate() {
+            assert_eq!(&random(seed as u64), output);
+            // One row per key, each from the input
+            let rows : Vec<&[u8]> = output.split_inclusive(|&b| b == b'\n').collect();
+            assert_eq!(rows.iter().map(|row| row[0]).collect::<Vec<_>>(), b"abc");
+            assert!(rows.iter().all(|row| ROWS.split_inclusive(|&b| b == b'\n').any(|input| input == *row)));
+        }
+        // Different seeds choose differently
+        assert!(outputs.iter().any(|output| *output != outputs[0]));
+    }
+
+    const UNIQ: &[u8] = b"a\t1\nb\t2\na\t3\nc\t4\na\t5\nd\t6\nd\t7\n";
+
+    #[test]
+    fn all_duplicates() {
+        // A key's first row is written once a second shows it repeats
+        let config = Config::new().all_duplicates(true);
+        assert_eq!(dedup(&config, UNIQ), b"a\t1\na\t3\na\t5\nd\t6\nd\t7\n");
+    }
+
+    #[test]
+    fn unique() {
+        let config = Config::new().having("count == 1".parse().unwrap());
+        assert_eq!(dedup(&config, UNIQ), b"b\t2\nc\t4\n");
+    }
+
+    #[test]
+    fn duplicates() {
+        let config = Config::new().having("count >= 2".parse().unwrap());
+        assert_eq!(dedup(&config, UNIQ), b"a\t1\nd\t6\n");
+    }
+
+    #[test]
+    fn count() {
+        let config = Config::new().count_prefix(true);
+        assert_eq!(dedup(&config, UNIQ), b"3\ta\t1\n1\tb\t2\n1\tc\t4\n2\td\t6\n");
+    }
+
+    #[test]
+    fn invert() {
+        let config = Config::new().invert(true);
+        assert_eq!(dedup(&config, UNIQ), b"a\t3\na\t5\nd\t7\n");
+    }
+
+    #[test]
+    fn partition_by_keeps_a_row_per_key_per_range() {
+        let input = b"a\t5\nb\t50\na\t99\na\t100\na\t-1\nb\tx\na\t150\n";
+        let config = |outside| Config::new().partition_by("2:0-100,100-".parse().unwrap(), outside);
+        assert_eq!(dedup(&config(NoMatch::Pass), input), b"a\t5\nb\t50\na\t100\na\t-1\nb\tx\n");
+        assert_eq!(dedup(&config(NoMatch::Skip), input), b"a\t5\nb\t50\na\t100\n");
+        let input = Input { name: "-".into(), reader: Box::new(Cursor::new(input.to_vec())) };
+        let error = run_from(&config(NoMatch::Error), vec![Ok(input)], &mut vec![]).unwrap_err();
+        assert_eq!(error.to_string(), "-:5: row's field 2 is in no --partition-by range");
+    }
+
+    #[test]
+    fn agg_appends_columns_to_kept_rows() {
+        let config = Config::new().aggregate("sum(2)".parse().unwrap()).aggregate("mean(2)".parse().unwrap());
+        assert_eq!(dedup(&config, b"a\t1\nb\tx\na\tn/a\na\t5\nb\n"), b"a\t1\t6\t3\nb\tx\t0\t\n");
+    }
+
+    #[test]
+    fn hash_secret_replaces_seeded_hashes() {
+        let hashed = |config: Config| dedup(&config.key_xxhash(true).emit_key_hash(true), b"a\nb\na\n");
+        assert_eq!(hashed(Config::new().hash_secret(b"secret")), b"4048c44911916043\ta\n8caf295837e09c87\tb\n");
+        // The seed plays no part once there is a secret
+        assert_eq!(hashed(Config::new().hash_seed(1).hash_secret(b"secret")), hashed(Config::new().hash_secret(b"secret")));
+        assert_ne!(hashed(Config::new().hash_secret(b"other")), hashed(Config::new().hash_secret(b"secret")));
+        assert_ne!(hashed(Config::new().hash_seed(0)), hashed(Config::new().hash_secret(b"secret")));
+    }
+
+    #[test]
+    fn state_carries_on_from_the_last_run() {
+        let dir = env::temp_dir().join(format!("tsvfirst-state-run-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_owned();
+        let config = Config::new().add_input(&path("in")).state(&path("state"));
+        let resumed = |rows: &[u8]| {
+            let mut input = fs::OpenOptions::new().append(true).create(true).open(path("in")).unwrap();
+            input.write_all(rows).unwrap();
+            let mut output = vec![];
+            run(&config, &mut output).unwrap();
+            output
+        };
+        assert_eq!(resumed(b"a\t1\nb\t1\na\t2\n"), b"a\t1\nb\t1\n");
+        // The unfinished last line is left for the next run
+        assert_eq!(resumed(b"c\t1\nb\t2\nd"), b"c\t1\n");
+        assert_eq!(resumed(b"\t1\na\t3\n"), b"d\t1\n");
+        assert_eq!(resumed(b""), b"");
+        fs::write(path("in"), "a\n").unwrap();
+        let error = run(&config, &mut vec![]).unwrap_err();
+        assert_eq!(error.to_string(), format!("{}: shorter than when --state was saved", path("in")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,37 @@
+use std::str;
+use std::str::FromStr;
+
+/// What to do with rows that are not valid UTF-8
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Utf8Mode {
+    /// Abort the run at the first invalid sequence
+    Strict,
+    /// Replace invalid sequences with U+FFFD before building the key
+    Lossy,
+    /// Don't validate; bytes pass through untouched
+    Ignore,
+}
+
+impl FromStr for Utf8Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Utf8Mode, String> {
+        match s {
+            "strict" => Ok(Utf8Mode::Strict),
+            "lossy" => Ok(Utf8Mode::Lossy),
+            "ignore" => Ok(Utf8Mode::Ignore),
+            _ => Err(format!("unknown UTF-8 mode '{}'", s)),
+        }
+    }
+}
+
+/// Returns the byte position within `line` of the first invalid sequence, if any
+pub fn invalid_position(line: &[u8]) -> Option<usize> {
+    str::from_utf8(line).err().map(|e| e.valid_up_to())
+}
+
+/// Replaces invalid sequences in `line` with U+FFFD
+pub fn make_lossy(line: &mut Vec<u8>) {
+    let fixed = String::from_utf8_lossy(line).into_owned();
+    *line = fixed.into_bytes();
+}
@@ -0,0 +1,92 @@
+use std::cell::RefCell;
+use std::error;
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::rc::Rc;
+
+use config::Config;
+use output::{chomp, Decision, RowFormat};
+use tsvfirst::run_with;
+
+/// Checks that `output_path` holds exactly the rows a run with `config` keeps,
+/// in order, by running it again. Returns a description of the first
+/// difference, if any, with the line numbers involved.
+///
+/// Only covers the rows themselves, so options that write other output, such
+/// as --group separators or --count-distinct, don't apply.
+pub fn verify(config: &Config, output_path: &str) -> Result<Option<String>, Box<dyn error::Error>> {
+    let output = fs::File::open(output_path).map_err(|e| format!("{}: {}", output_path, e))?;
+    let difference = Rc::new(RefCell::new(None));
+    let comparison = Comparison {
+        output: BufReader::new(output),
+        name: output_path.into(),
+        line_number: 0,
+        difference: difference.clone(),
+    };
+    let result = run_with(config, &mut io::sink(), Box::new(comparison));
+    // The run is stopped by an error at the first difference
+    let difference = difference.borrow_mut().take();
+    match difference {
+        Some(difference) => Ok(Some(difference)),
+        None => result.map(|_| None),
+    }
+}
+
+/// Compares each kept row with the next line of the output being verified
+struct Comparison {
+    output: BufReader<fs::File>,
+    name: String,
+    line_number: u64,
+    difference: Rc<RefCell<Option<String>>>,
+}
+
+impl Comparison {
+    fn next_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut line = vec![];
+        if self.output.read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+        self.line_number += 1;
+        Ok(Some(line))
+    }
+
+    fn differs(&self, message: String) -> io::Result<()> {
+        *self.difference.borrow_mut() = Some(message);
+        Err(io::Error::other("output differs"))
+    }
+}
+
+impl RowFormat for Comparison {
+    fn write(&mut self, _out: &mut dyn Write, decision: &Decision) -> io::Result<()> {
+        if !decision.kept {
+            return Ok(());
+        }
+        match self.next_line()? {
+            Some(ref line) if line.as_slice() == decision.line => Ok(()),
+            Some(line) => {
+                let message = format!("{}:{}: expected '{}' from {}:{}, found '{}'", self.name, self.line_number,
+                    String::from_utf8_lossy(chomp(decision.line)), decision.source, decision.line_number,
+                    String::from_utf8_lossy(chomp(&line)));
+                self.differs(message)
+            }
+            None => {
+                let message = format!("{}:{}: expected '{}' from {}:{}, found the end of the file", self.name,
+                    self.line_number + 1, String::from_utf8_lossy(chomp(decision.line)), decision.source,
+                    decision.line_number);
+                self.differs(message)
+            }
+        }
+    }
+
+    fn finish(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+        match self.next_line()? {
+            Some(line) => {
+                let message = format!("{}:{}: found '{}' after the last kept row", self.name, self.line_number,
+                    String::from_utf8_lossy(chomp(&line)));
+                self.differs(message)
+            }
+            None => Ok(()),
+        }
+    }
+}
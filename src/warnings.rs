@@ -0,0 +1,107 @@
+use std::collections::HashSet;
+use std::io;
+use std::io::Write;
+
+use config::Config;
+
+/// Rows looked at before deciding whether most of them are suspicious
+const SAMPLE_ROWS: u64 = 1000;
+
+/// Looks for signs that the options don't suit the input, such as a key field
+/// most rows lack, and warns about each on stderr at most once
+pub struct Warnings {
+    enabled: bool,
+    /// 1-indexed, if keys are made of fields
+    last_field: Option<usize>,
+    whitespace: bool,
+    sorted: bool,
+    rows: u64,
+    missing_field: u64,
+    with_tabs: u64,
+    with_tabs_and_spaces: u64,
+    sampled: bool,
+    // For -s: keys that have come and gone within the sample, and the current one
+    ended: HashSet<Vec<u8>>,
+    last_key: Option<Vec<u8>>,
+    warned_unsorted: bool,
+}
+
+impl Warnings {
+    pub fn new(config: &Config) -> Warnings {
+        Warnings {
+            enabled: config.warnings,
+            last_field: if config.line_regex.is_none() {
+                config.fields.iter().map(|field| field.index + 1).max()
+            }
+            else {
+                None
+            },
+            whitespace: config.whitespace,
+            sorted: config.sorted,
+            rows: 0,
+            missing_field: 0,
+            with_tabs: 0,
+            with_tabs_and_spaces: 0,
+            sampled: false,
+            ended: HashSet::new(),
+            last_key: None,
+            warned_unsorted: false,
+        }
+    }
+
+    /// Looks at a row and its key (None if it has none), read from `source`
+    pub fn row(&mut self, line: &[u8], key: Option<&[u8]>, missing_field: bool, source: &str, line_number: u64)
+               -> io::Result<()> {
+        if !self.enabled || self.sampled {
+            return Ok(());
+        }
+        self.rows += 1;
+        if missing_field {
+            self.missing_field += 1;
+        }
+        if line.contains(&b'\t') {
+            self.with_tabs += 1;
+            if line.contains(&b' ') {
+                self.with_tabs_and_spaces += 1;
+            }
+        }
+        if let Some(key) = key.filter(|_| self.sorted && !self.warned_unsorted) {
+            if self.last_key.as_ref().is_none_or(|last| last.as_slice() != key) {
+                if self.ended.contains(key) {
+                    self.warned_unsorted = true;
+                    warn(&format!("{}:{}: a key reappears after other keys, so the input isn't sorted as -s \
+                        assumes and its repeats aren't all dropped", source, line_number))?;
+                }
+                if let Some(last) = self.last_key.replace(key.to_owned()) {
+                    self.ended.insert(last);
+                }
+            }
+        }
+        if self.rows == SAMPLE_ROWS {
+            self.finish()?;
+        }
+        Ok(())
+    }
+
+    /// Warns about anything suspicious in the rows seen, if not already done
+    pub fn finish(&mut self) -> io::Result<()> {
+        if !self.enabled || self.sampled {
+            return Ok(());
+        }
+        self.sampled = true;
+        self.ended.clear();
+        if let Some(field) = self.last_field {
+            if self.missing_field * 2 > self.rows {
+                warn(&format!("most rows have no field {}, so their keys are incomplete; check -f", field))?;
+            }
+        }
+        if self.whitespace && self.with_tabs * 10 >= self.rows * 9 && self.with_tabs_and_spaces * 2 > self.with_tabs {
+            warn("rows look tab-separated with spaces inside fields, which -w also splits on")?;
+        }
+        Ok(())
+    }
+}
+
+fn warn(message: &str) -> io::Result<()> {
+    writeln!(io::stderr(), "tsvfirst: warning: {} (--no-warnings to hide)", message)
+}
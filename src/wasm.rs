@@ -0,0 +1,52 @@
+//! JavaScript bindings over `Deduplicator`, enabled by the `wasm` feature.
+//! Build with `cargo rustc --lib --release --target wasm32-unknown-unknown
+//! --features wasm --crate-type cdylib`, then generate the JS glue with
+//! `wasm-bindgen --target web target/wasm32-unknown-unknown/release/tsvfirst.wasm`.
+//!
+//! ```js
+//! const d = new Deduplicator("1,3i", false, false);
+//! for (const chunk of chunks) out.push(d.feed(chunk));
+//! out.push(d.flush());
+//! ```
+
+use wasm_bindgen::prelude::*;
+
+use config::Config;
+use engine;
+use key::parse_field_spec;
+
+fn js_error<E: ToString>(err: E) -> JsValue {
+    JsValue::from_str(&err.to_string())
+}
+
+/// Incremental deduplicator: `feed` takes a chunk of bytes and returns the
+/// kept records (with their newlines) that the chunk completes
+#[wasm_bindgen]
+pub struct Deduplicator {
+    inner: engine::Deduplicator,
+}
+
+#[wasm_bindgen]
+impl Deduplicator {
+    /// `fields` is a -f style spec such as "1,3i"
+    #[wasm_bindgen(constructor)]
+    pub fn new(fields: &str, sorted: bool, whitespace: bool) -> Result<Deduplicator, JsValue> {
+        let fields = parse_field_spec(fields).map_err(js_error)?;
+        let config = Config::new().fields(&fields).sorted(sorted).whitespace(whitespace);
+        let inner = engine::Deduplicator::new(&config).map_err(js_error)?;
+        Ok(Deduplicator { inner })
+    }
+
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<u8>, JsValue> {
+        let mut kept = vec![];
+        self.inner.feed(data, &mut kept).map_err(js_error)?;
+        Ok(kept)
+    }
+
+    /// Returns a final record that lacked a trailing newline, if kept
+    pub fn flush(&mut self) -> Result<Vec<u8>, JsValue> {
+        let mut kept = vec![];
+        self.inner.finish(&mut kept).map_err(js_error)?;
+        Ok(kept)
+    }
+}